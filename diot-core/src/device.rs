@@ -21,7 +21,7 @@ macro_rules! register_devices {
             pub mod $module;
         )*
 
-        #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
         pub enum HardwareDeviceType {
             $(
                 #[serde(rename = $stringified)]
@@ -43,6 +43,18 @@ macro_rules! register_devices {
             }
         }
 
+        impl HardwareDeviceType {
+            /// What devices of this type offer
+            pub fn capabilities(self) -> DeviceCapabilities {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        HardwareDeviceType::$name => <$module::$name as ConfigurableHardwareDevice>::capabilities()
+                    ),*
+                }
+            }
+        }
+
         pub fn initialize_device(dev_type: HardwareDeviceType, config: serde_json::Value) -> Result<Box<dyn HardwareDevice>> {
             match dev_type {
                 $(
@@ -218,6 +230,10 @@ pub enum ActuationResult {
         error_code: i64,
         error_description: String,
     },
+    /// The requesting node isn't allowed to carry out the actuation
+    Forbidden {
+        reason: String,
+    },
 }
 
 #[derive(Clone, Debug, PartialEq, EnumKind, Serialize, Deserialize)]
@@ -274,7 +290,7 @@ impl Measurement {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ActuatorValue {
     Signal,
@@ -444,10 +460,26 @@ pub trait HardwareDevice: Send {
     }
 }
 
+/// What a type of device offers, so that references to it can be checked without talking to it
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceCapabilities {
+    /// Sensors of the device, along with the kind of the measurements they publish
+    pub sensors: &'static [(&'static str, MeasurementKind)],
+    /// Names of the actuators of the device, or `None` if it takes any name
+    pub actuators: Option<&'static [&'static str]>,
+    /// Whether the actuators of the device accept the given value
+    pub accepts: fn(&ActuatorValue) -> bool,
+}
+
 pub trait ConfigurableHardwareDevice: HardwareDevice {
     /// Configuration data for the device
     type Config: DeserializeOwned;
 
+    /// What devices of this type offer
+    fn capabilities() -> DeviceCapabilities
+    where
+        Self: Sized;
+
     /// Initializes the device and returns an instance of this device.
     fn init(config: Self::Config) -> Result<Self>
     where
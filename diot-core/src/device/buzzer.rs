@@ -4,7 +4,9 @@ use anyhow::{Context, Result};
 use rppal::gpio::{Gpio, OutputPin};
 use serde::{Deserialize, Serialize};
 
-use super::{ActuationResult, ActuatorValue, ConfigurableHardwareDevice, HardwareDevice};
+use super::{
+    ActuationResult, ActuatorValue, ConfigurableHardwareDevice, DeviceCapabilities, HardwareDevice,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BuzzerConfig {
@@ -103,6 +105,15 @@ impl HardwareDevice for Buzzer {
 impl ConfigurableHardwareDevice for Buzzer {
     type Config = BuzzerConfig;
 
+    fn capabilities() -> DeviceCapabilities {
+        // Beeps on any actuator name, for a duration given as a number
+        DeviceCapabilities {
+            sensors: &[],
+            actuators: None,
+            accepts: |value| !matches!(value, ActuatorValue::String(_)),
+        }
+    }
+
     fn init(config: Self::Config) -> Result<Self>
     where
         Self: Sized,
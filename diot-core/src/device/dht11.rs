@@ -1,7 +1,9 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use super::{ConfigurableHardwareDevice, HardwareDevice, Measurement};
+use super::{
+    ConfigurableHardwareDevice, DeviceCapabilities, HardwareDevice, Measurement, MeasurementKind,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Dht11Config {
@@ -39,6 +41,17 @@ impl HardwareDevice for Dht11 {
 impl ConfigurableHardwareDevice for Dht11 {
     type Config = Dht11Config;
 
+    fn capabilities() -> DeviceCapabilities {
+        DeviceCapabilities {
+            sensors: &[
+                ("temperature", MeasurementKind::Double),
+                ("humidity", MeasurementKind::Double),
+            ],
+            actuators: Some(&[]),
+            accepts: |_| false,
+        }
+    }
+
     fn init(config: Self::Config) -> Result<Self>
     where
         Self: Sized,
@@ -1,7 +1,9 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use super::{ActuationResult, ActuatorValue, ConfigurableHardwareDevice, HardwareDevice};
+use super::{
+    ActuationResult, ActuatorValue, ConfigurableHardwareDevice, DeviceCapabilities, HardwareDevice,
+};
 
 const SIGNAL_DEFAULT: &str = "Received signal!";
 
@@ -42,6 +44,15 @@ impl HardwareDevice for Logger {
 impl ConfigurableHardwareDevice for Logger {
     type Config = LoggerConfig;
 
+    fn capabilities() -> DeviceCapabilities {
+        // Logs whatever it's given, under the name of the actuator
+        DeviceCapabilities {
+            sensors: &[],
+            actuators: None,
+            accepts: |_| true,
+        }
+    }
+
     fn init(config: Self::Config) -> Result<Self>
     where
         Self: Sized,
@@ -3,7 +3,9 @@ use std::time::Instant;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use super::{ConfigurableHardwareDevice, HardwareDevice, Measurement};
+use super::{
+    ConfigurableHardwareDevice, DeviceCapabilities, HardwareDevice, Measurement, MeasurementKind,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TimerConfig {
@@ -29,6 +31,14 @@ impl HardwareDevice for Timer {
 impl ConfigurableHardwareDevice for Timer {
     type Config = TimerConfig;
 
+    fn capabilities() -> DeviceCapabilities {
+        DeviceCapabilities {
+            sensors: &[("tick", MeasurementKind::Signal)],
+            actuators: Some(&[]),
+            accepts: |_| false,
+        }
+    }
+
     fn init(config: Self::Config) -> Result<Self>
     where
         Self: Sized,
@@ -0,0 +1,502 @@
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use diot_core::device::{ActuationResult, ActuatorValue, Measurement};
+use futures::FutureExt;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    control::{Action, ActuationId, RuleId},
+    hardware::{FullActuatorData, FullSensorData},
+    history::now_millis,
+    store::StorageBackend,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// Maximum number of entries to keep, dropping the oldest ones first
+    #[serde(default)]
+    pub max_entries: Option<usize>,
+    /// Maximum age of the entries to keep, in days
+    #[serde(default)]
+    pub max_age_days: Option<f64>,
+}
+
+/// Where an actuation was requested from and carried out at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActuationDirection {
+    /// Requested by this node, on one of its own devices
+    Local,
+    /// Requested by this node, on a device of another node
+    Outbound,
+    /// Requested by another node, on a device of this node
+    Inbound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditEvent {
+    RuleFired {
+        rule: RuleId,
+        /// Peer ID of the node the triggering measurement came from
+        node: String,
+        device: String,
+        sensor_name: String,
+        value: Measurement,
+    },
+    ActuationRequested {
+        direction: ActuationDirection,
+        /// Rule, script, mode or scene which requested it; unknown for inbound requests
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rule: Option<RuleId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        actuation: Option<ActuationId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attempt: Option<u32>,
+        /// Peer ID of the node which requested the actuation
+        requested_by: String,
+        /// Peer ID of the node the actuated device is on
+        node: String,
+        device: String,
+        actuator_name: String,
+        data: ActuatorValue,
+    },
+    ActuationResult {
+        direction: ActuationDirection,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rule: Option<RuleId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        actuation: Option<ActuationId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attempt: Option<u32>,
+        requested_by: String,
+        node: String,
+        device: String,
+        actuator_name: String,
+        /// What the actuator answered, if it did
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<ActuationResult>,
+        /// Why there's no answer from the actuator, if there isn't
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl AuditEvent {
+    pub fn rule_fired(rule: RuleId, node: PeerId, sensor: &FullSensorData) -> Self {
+        Self::RuleFired {
+            rule,
+            node: node.to_base58(),
+            device: sensor.device.clone(),
+            sensor_name: sensor.sensor_name.clone(),
+            value: sensor.value.clone(),
+        }
+    }
+
+    /// An actuation requested by this node, either locally or on a remote node
+    pub fn requested(
+        local_peer_id: PeerId,
+        rule: RuleId,
+        actuation: ActuationId,
+        attempt: u32,
+        action: &Action,
+    ) -> Self {
+        Self::ActuationRequested {
+            direction: direction_of(local_peer_id, action),
+            rule: Some(rule),
+            actuation: Some(actuation),
+            attempt: Some(attempt),
+            requested_by: local_peer_id.to_base58(),
+            node: action.node.unwrap_or(local_peer_id).to_base58(),
+            device: action.actuator.device.clone(),
+            actuator_name: action.actuator.actuator_name.clone(),
+            data: action.actuator.data.clone(),
+        }
+    }
+
+    /// Outcome of an actuation requested by this node
+    pub fn settled(
+        local_peer_id: PeerId,
+        rule: RuleId,
+        actuation: ActuationId,
+        attempt: u32,
+        action: &Action,
+        result: Result<ActuationResult, String>,
+    ) -> Self {
+        let (result, error) = split_result(result);
+        Self::ActuationResult {
+            direction: direction_of(local_peer_id, action),
+            rule: Some(rule),
+            actuation: Some(actuation),
+            attempt: Some(attempt),
+            requested_by: local_peer_id.to_base58(),
+            node: action.node.unwrap_or(local_peer_id).to_base58(),
+            device: action.actuator.device.clone(),
+            actuator_name: action.actuator.actuator_name.clone(),
+            result,
+            error,
+        }
+    }
+
+    /// An actuation another node requested on this one
+    pub fn inbound_requested(
+        local_peer_id: PeerId,
+        requested_by: PeerId,
+        data: &FullActuatorData,
+    ) -> Self {
+        Self::ActuationRequested {
+            direction: ActuationDirection::Inbound,
+            rule: None,
+            actuation: None,
+            attempt: None,
+            requested_by: requested_by.to_base58(),
+            node: local_peer_id.to_base58(),
+            device: data.device.clone(),
+            actuator_name: data.actuator_name.clone(),
+            data: data.data.clone(),
+        }
+    }
+
+    /// Outcome of an actuation another node requested on this one
+    pub fn inbound_settled(
+        local_peer_id: PeerId,
+        requested_by: PeerId,
+        data: &FullActuatorData,
+        result: Result<ActuationResult, String>,
+    ) -> Self {
+        let (result, error) = split_result(result);
+        Self::ActuationResult {
+            direction: ActuationDirection::Inbound,
+            rule: None,
+            actuation: None,
+            attempt: None,
+            requested_by: requested_by.to_base58(),
+            node: local_peer_id.to_base58(),
+            device: data.device.clone(),
+            actuator_name: data.actuator_name.clone(),
+            result,
+            error,
+        }
+    }
+
+    fn rule(&self) -> Option<&str> {
+        match self {
+            Self::RuleFired { rule, .. } => Some(rule),
+            Self::ActuationRequested { rule, .. } | Self::ActuationResult { rule, .. } => {
+                rule.as_deref()
+            }
+        }
+    }
+
+    fn device(&self) -> &str {
+        match self {
+            Self::RuleFired { device, .. }
+            | Self::ActuationRequested { device, .. }
+            | Self::ActuationResult { device, .. } => device,
+        }
+    }
+
+    /// Whether the given peer took part in the event, either as requester or target
+    fn involves(&self, peer: &str) -> bool {
+        match self {
+            Self::RuleFired { node, .. } => node == peer,
+            Self::ActuationRequested {
+                requested_by, node, ..
+            }
+            | Self::ActuationResult {
+                requested_by, node, ..
+            } => requested_by == peer || node == peer,
+        }
+    }
+}
+
+fn direction_of(local_peer_id: PeerId, action: &Action) -> ActuationDirection {
+    match action.node {
+        Some(node) if node != local_peer_id => ActuationDirection::Outbound,
+        _ => ActuationDirection::Local,
+    }
+}
+
+fn split_result(
+    result: Result<ActuationResult, String>,
+) -> (Option<ActuationResult>, Option<String>) {
+    match result {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp of the event, in milliseconds
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Filter over the audit log; every given criteria must match
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Unix timestamp in milliseconds to start at, inclusive
+    pub from: Option<u64>,
+    /// Unix timestamp in milliseconds to end at, exclusive
+    pub to: Option<u64>,
+    pub rule: Option<String>,
+    pub device: Option<String>,
+    /// Node involved, as a peer ID or display name
+    pub peer: Option<String>,
+    /// Maximum number of entries to return, the latest ones being kept
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    /// Whether an event matches the query, `peers` being what its `peer` stands for
+    fn matches(&self, event: &AuditEvent, peers: Option<&[PeerId]>) -> bool {
+        self.rule
+            .as_deref()
+            .is_none_or(|rule| event.rule() == Some(rule))
+            && self
+                .device
+                .as_deref()
+                .is_none_or(|device| event.device() == device)
+            && peers.is_none_or(|peers| peers.iter().any(|peer| event.involves(&peer.to_base58())))
+    }
+}
+
+/// How many entries the writer thread may put into a single batch
+const MAX_BATCH_SIZE: usize = 256;
+/// Time between drops of the entries older than allowed
+const RETENTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Append-only record of what the automations did, persisted across restarts
+///
+/// Writes happen on a thread of their own, so that recording an event never blocks, and old
+/// entries are dropped on another one. Entries still queued for the writer thread aren't returned
+/// by queries yet.
+pub struct AuditLog {
+    entries: Arc<AuditEntries>,
+    writer: UnboundedSender<AuditEntry>,
+}
+
+/// Entries of the audit log as stored, kept within the limits of its config
+struct AuditEntries {
+    backend: Arc<dyn StorageBackend>,
+    /// Number of entries in the log, kept around as counting them may be slow
+    len: AtomicUsize,
+    config: AuditConfig,
+}
+
+impl AuditLog {
+    pub fn open(backend: Arc<dyn StorageBackend>, config: AuditConfig) -> Result<Self> {
+        let len = AtomicUsize::new(
+            backend
+                .audit_len()
+                .context("Couldn't count audit entries")?,
+        );
+        let entries = Arc::new(AuditEntries {
+            backend,
+            len,
+            config,
+        });
+        entries.enforce_retention()?;
+
+        let (writer, queue) = unbounded_channel();
+        {
+            let entries = entries.clone();
+            thread::Builder::new()
+                .name(String::from("audit-writer"))
+                .spawn(move || entries.write(queue))
+                .context("Couldn't spawn audit writer thread")?;
+        }
+        if entries.config.max_age_days.is_some() {
+            let entries = Arc::downgrade(&entries);
+            thread::Builder::new()
+                .name(String::from("audit-retention"))
+                .spawn(move || loop {
+                    thread::sleep(RETENTION_INTERVAL);
+                    let Some(entries) = entries.upgrade() else {
+                        break;
+                    };
+                    if let Err(err) = entries.enforce_retention() {
+                        error!("Couldn't drop old entries from the audit log: {:#}", err);
+                    }
+                })
+                .context("Couldn't spawn audit retention thread")?;
+        }
+
+        Ok(Self { entries, writer })
+    }
+
+    /// Queues an event which just happened to be appended to the log
+    pub fn record(&self, event: AuditEvent) {
+        let entry = AuditEntry {
+            at: now_millis(),
+            event,
+        };
+        if self.writer.send(entry).is_err() {
+            error!("Audit writer thread is gone, event won't be recorded");
+        }
+    }
+
+    /// Returns the entries matching a query, oldest first
+    ///
+    /// `peers` are the peer IDs the `peer` of the query was resolved into.
+    pub fn query(&self, query: &AuditQuery, peers: Option<&[PeerId]>) -> Result<Vec<AuditEntry>> {
+        let limit = query.limit.unwrap_or(usize::MAX);
+
+        let mut entries = Vec::new();
+        if limit > 0 {
+            self.entries
+                .backend
+                .visit_audit(
+                    query.from.unwrap_or(0),
+                    query.to.unwrap_or(u64::MAX),
+                    &mut |entry| {
+                        if query.matches(&entry.event, peers) {
+                            entries.push(entry);
+                        }
+                        entries.len() < limit
+                    },
+                )
+                .context("Couldn't read audit entries")?;
+        }
+        entries.reverse();
+
+        Ok(entries)
+    }
+}
+
+impl AuditEntries {
+    /// Appends queued entries in batches, until the log is dropped
+    fn write(&self, mut queue: UnboundedReceiver<AuditEntry>) {
+        while let Some(entry) = queue.blocking_recv() {
+            let mut batch = vec![entry];
+            while batch.len() < MAX_BATCH_SIZE {
+                match queue.recv().now_or_never() {
+                    Some(Some(entry)) => batch.push(entry),
+                    _ => break,
+                }
+            }
+
+            if let Err(err) = self.append(&batch) {
+                error!("Couldn't write to the audit log: {:#}", err);
+            }
+        }
+
+        debug!("Audit writer stopped");
+    }
+
+    fn append(&self, batch: &[AuditEntry]) -> Result<()> {
+        self.backend
+            .append_audit(batch)
+            .context("Couldn't insert audit entries")?;
+        let len = self.len.fetch_add(batch.len(), Ordering::Relaxed) + batch.len();
+
+        if let Some(max_entries) = self.config.max_entries {
+            if len > max_entries {
+                let dropped = self
+                    .backend
+                    .remove_oldest_audit(len - max_entries)
+                    .context("Couldn't drop oldest audit entries")?;
+                self.len.fetch_sub(dropped, Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the entries older than allowed
+    fn enforce_retention(&self) -> Result<()> {
+        // Ages too long to be represented are as good as no limit at all
+        let Some(Ok(max_age)) = self
+            .config
+            .max_age_days
+            .map(|days| Duration::try_from_secs_f64(days.max(0.0) * 24.0 * 60.0 * 60.0))
+        else {
+            return Ok(());
+        };
+        let max_age = u64::try_from(max_age.as_millis()).unwrap_or(u64::MAX);
+
+        let dropped = self
+            .backend
+            .remove_audit_before(now_millis().saturating_sub(max_age))
+            .context("Couldn't drop old audit entries")?;
+        if dropped > 0 {
+            self.len.fetch_sub(dropped, Ordering::Relaxed);
+            debug!("Dropped {} audit entries past their maximum age", dropped);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{control::RetryPolicy, store::MemoryBackend};
+
+    fn action(node: Option<PeerId>) -> Action {
+        Action {
+            node,
+            actuator: FullActuatorData {
+                device: String::from("buzzer-1"),
+                actuator_name: String::from("buzz"),
+                data: ActuatorValue::Signal,
+            },
+            retry: RetryPolicy::default(),
+            fallback: None,
+        }
+    }
+
+    #[test]
+    fn actuations_are_outbound_only_on_other_nodes() {
+        let local = PeerId::random();
+        let direction =
+            |node| match AuditEvent::requested(local, RuleId::from("api"), 1, 1, &action(node)) {
+                AuditEvent::ActuationRequested { direction, .. } => direction,
+                _ => unreachable!(),
+            };
+
+        assert_eq!(direction(None), ActuationDirection::Local);
+        assert_eq!(direction(Some(local)), ActuationDirection::Local);
+        assert_eq!(
+            direction(Some(PeerId::random())),
+            ActuationDirection::Outbound
+        );
+    }
+
+    #[test]
+    fn overly_long_retention_keeps_everything() {
+        let backend = Arc::new(MemoryBackend::new());
+        let event = AuditEvent::rule_fired(
+            RuleId::from("alarm"),
+            PeerId::random(),
+            &FullSensorData {
+                device: String::from("button-1"),
+                sensor_name: String::from("pressed"),
+                value: Measurement::Signal,
+            },
+        );
+        backend
+            .append_audit(&[AuditEntry { at: 0, event }])
+            .unwrap();
+
+        let config = AuditConfig {
+            max_entries: None,
+            max_age_days: Some(1e300),
+        };
+        let log = AuditLog::open(backend, config).unwrap();
+        assert_eq!(log.query(&AuditQuery::default(), None).unwrap().len(), 1);
+    }
+}
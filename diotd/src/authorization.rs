@@ -0,0 +1,280 @@
+//! Which other nodes may actuate the devices of this one
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use diot_core::device::ActuatorValue;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::FullActuatorData;
+
+/// Role allowing every actuation
+const FULL_ROLE: &str = "full";
+/// Role allowing no actuation at all, for nodes which should only listen
+const VIEWER_ROLE: &str = "viewer";
+
+fn default_role() -> String {
+    FULL_ROLE.to_string()
+}
+
+fn role_exists(roles: &HashMap<String, Vec<Grant>>, role: &str) -> bool {
+    role == FULL_ROLE || role == VIEWER_ROLE || roles.contains_key(role)
+}
+
+/// Actuations allowed by a role
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    /// Device the grant applies to; all of them if missing
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Actuators of the device the grant applies to; all of them if empty
+    #[serde(default)]
+    pub actuators: Vec<String>,
+    /// Smallest value allowed; only numbers are allowed if there is a bound
+    #[serde(default)]
+    pub min: Option<f64>,
+    /// Largest value allowed; only numbers are allowed if there is a bound
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+impl Grant {
+    #[allow(clippy::cast_precision_loss)]
+    fn allows(&self, data: &FullActuatorData) -> bool {
+        if self
+            .device
+            .as_ref()
+            .is_some_and(|device| *device != data.device)
+        {
+            return false;
+        }
+        if !self.actuators.is_empty() && !self.actuators.contains(&data.actuator_name) {
+            return false;
+        }
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+
+        let value = match data.data {
+            ActuatorValue::Unsigned(value) => value as f64,
+            ActuatorValue::Signed(value) => value as f64,
+            ActuatorValue::Double(value) => value,
+            ActuatorValue::Signal | ActuatorValue::String(_) => return false,
+        };
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationConfig {
+    /// Role of the nodes not listed in `peers`
+    #[serde(default = "default_role")]
+    pub default_role: String,
+    /// Roles besides `full` and `viewer`, as the actuations they allow
+    #[serde(default)]
+    pub roles: HashMap<String, Vec<Grant>>,
+    /// Role of each node, by peer ID
+    #[serde(default)]
+    pub peers: HashMap<String, String>,
+}
+
+impl Default for AuthorizationConfig {
+    fn default() -> Self {
+        Self {
+            default_role: default_role(),
+            roles: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+/// Decides which actuations other nodes may request from this one
+#[derive(Debug)]
+pub struct Authorization {
+    default_role: String,
+    roles: HashMap<String, Vec<Grant>>,
+    peers: HashMap<PeerId, String>,
+}
+
+impl Authorization {
+    pub fn new(config: AuthorizationConfig) -> Result<Self> {
+        if let Some(role) = config
+            .roles
+            .keys()
+            .find(|role| *role == FULL_ROLE || *role == VIEWER_ROLE)
+        {
+            bail!("Role \"{}\" is built in and can't be redefined", role);
+        }
+        if !role_exists(&config.roles, &config.default_role) {
+            bail!("Unknown default role \"{}\"", config.default_role);
+        }
+        let mut peers = HashMap::with_capacity(config.peers.len());
+        for (peer, role) in &config.peers {
+            let peer_id = peer
+                .parse()
+                .map_err(|_| anyhow!("Invalid peer ID \"{}\"", peer))?;
+            if !role_exists(&config.roles, role) {
+                bail!("Unknown role \"{}\" of peer {}", role, peer);
+            }
+            peers.insert(peer_id, role.clone());
+        }
+
+        Ok(Self {
+            default_role: config.default_role,
+            roles: config.roles,
+            peers,
+        })
+    }
+
+    pub fn default_role(&self) -> &str {
+        &self.default_role
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        role_exists(&self.roles, role)
+    }
+
+    /// Gives a node a role, replacing the one it had
+    pub fn assign(&mut self, peer: PeerId, role: String) {
+        self.peers.insert(peer, role);
+    }
+
+    /// Checks whether a node may carry out an actuation on this one, telling why not otherwise
+    pub fn check(&self, peer: PeerId, data: &FullActuatorData) -> Result<(), String> {
+        let role = self.peers.get(&peer).unwrap_or(&self.default_role);
+        let allowed = match role.as_str() {
+            FULL_ROLE => true,
+            VIEWER_ROLE => false,
+            role => self
+                .roles
+                .get(role)
+                .is_some_and(|grants| grants.iter().any(|grant| grant.allows(data))),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!(
+                "Role \"{}\" may not set actuator \"{}\" of device \"{}\" to {}",
+                role, data.actuator_name, data.device, data.data
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actuation(device: &str, actuator_name: &str, data: ActuatorValue) -> FullActuatorData {
+        FullActuatorData {
+            device: device.to_string(),
+            actuator_name: actuator_name.to_string(),
+            data,
+        }
+    }
+
+    fn authorization(default_role: &str, peers: &[(PeerId, &str)]) -> Authorization {
+        let config: AuthorizationConfig = serde_json::from_value(serde_json::json!({
+            "default_role": default_role,
+            "roles": {
+                "thermostat": [
+                    { "device": "heater-1", "actuators": ["power"], "min": 0, "max": 100 },
+                    { "device": "buzzer-1" }
+                ]
+            },
+        }))
+        .unwrap();
+        let mut authorization = Authorization::new(config).unwrap();
+        for (peer, role) in peers {
+            authorization.assign(*peer, role.to_string());
+        }
+        authorization
+    }
+
+    #[test]
+    fn built_in_roles_allow_everything_or_nothing() {
+        let (full, viewer) = (PeerId::random(), PeerId::random());
+        let authorization = authorization("viewer", &[(full, "full")]);
+        let data = actuation("relay-1", "fan", ActuatorValue::Unsigned(1));
+
+        assert!(authorization.check(full, &data).is_ok());
+        assert!(authorization.check(viewer, &data).is_err());
+    }
+
+    #[test]
+    fn grants_limit_devices_actuators_and_values() {
+        let peer = PeerId::random();
+        let authorization = authorization("viewer", &[(peer, "thermostat")]);
+        let check = |data| authorization.check(peer, &data).is_ok();
+
+        assert!(check(actuation(
+            "heater-1",
+            "power",
+            ActuatorValue::Unsigned(40)
+        )));
+        assert!(check(actuation(
+            "heater-1",
+            "power",
+            ActuatorValue::Double(0.0)
+        )));
+        assert!(!check(actuation(
+            "heater-1",
+            "power",
+            ActuatorValue::Signed(-1)
+        )));
+        assert!(!check(actuation(
+            "heater-1",
+            "power",
+            ActuatorValue::Unsigned(101)
+        )));
+        assert!(!check(actuation(
+            "heater-1",
+            "power",
+            ActuatorValue::Signal
+        )));
+        assert!(!check(actuation(
+            "heater-1",
+            "mode",
+            ActuatorValue::Unsigned(1)
+        )));
+        assert!(check(actuation("buzzer-1", "buzz", ActuatorValue::Signal)));
+        assert!(!check(actuation(
+            "relay-1",
+            "fan",
+            ActuatorValue::Unsigned(1)
+        )));
+    }
+
+    #[test]
+    fn denials_tell_the_role_and_actuation() {
+        let authorization = authorization("viewer", &[]);
+        let data = actuation("relay-1", "fan", ActuatorValue::Unsigned(1));
+
+        assert_eq!(
+            authorization.check(PeerId::random(), &data).unwrap_err(),
+            "Role \"viewer\" may not set actuator \"fan\" of device \"relay-1\" to 1"
+        );
+    }
+
+    #[test]
+    fn unknown_and_redefined_roles_are_rejected() {
+        let config = |json| serde_json::from_value::<AuthorizationConfig>(json).unwrap();
+
+        assert!(
+            Authorization::new(config(serde_json::json!({ "default_role": "admin" }))).is_err()
+        );
+        assert!(
+            Authorization::new(config(serde_json::json!({ "roles": { "full": [] } }))).is_err()
+        );
+        assert!(Authorization::new(config(serde_json::json!({
+            "peers": { (PeerId::random().to_base58()): "admin" }
+        })))
+        .is_err());
+        assert!(Authorization::new(config(serde_json::json!({
+            "peers": { "not a peer ID": "viewer" }
+        })))
+        .is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    path::Path,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use diot_core::device::{HardwareDeviceType, Measurement};
+use futures::FutureExt;
+use libp2p::{identity::Keypair, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    control::{Action, ControlLayer, ExecutionId, PlanEvent, Rule, RuleId},
+    hardware::FullSensorData,
+    store::{MemoryBackend, Storage},
+    swarm::PeerData,
+    system::SystemConfig,
+};
+
+/// A measurement as recorded on a backtest input file, one per line
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordedSample {
+    /// Unix timestamp of the measurement, in milliseconds
+    pub at: u64,
+    /// Node the measurement comes from: a peer ID, an alias, a display name, or none/"local"
+    #[serde(default)]
+    pub node: Option<String>,
+    /// Type of the device, used to register the devices of simulated nodes
+    #[serde(default)]
+    pub device_type: Option<HardwareDeviceType>,
+    #[serde(flatten)]
+    pub data: FullSensorData,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BacktestEvent {
+    Fired {
+        at: u64,
+        rule: RuleId,
+        /// Display name of the node the measurement came from
+        node: String,
+        device: String,
+        sensor_name: String,
+        value: Measurement,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execution: Option<ExecutionId>,
+    },
+    Actuated {
+        at: u64,
+        rule: RuleId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execution: Option<ExecutionId>,
+        action: Action,
+    },
+    PlanFinished {
+        at: u64,
+        rule: RuleId,
+        execution: ExecutionId,
+    },
+    PlanCancelled {
+        at: u64,
+        rule: RuleId,
+        execution: ExecutionId,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleStats {
+    pub fired: usize,
+    pub actuations: usize,
+    pub cancelled: usize,
+}
+
+/// What a ruleset would have done over a recording
+#[derive(Debug, Default, Serialize)]
+pub struct BacktestReport {
+    pub samples: usize,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub rules: BTreeMap<RuleId, RuleStats>,
+    pub events: Vec<BacktestEvent>,
+}
+
+impl BacktestReport {
+    fn record(&mut self, event: BacktestEvent) {
+        match &event {
+            BacktestEvent::Fired { rule, .. } => self.stats(rule).fired += 1,
+            BacktestEvent::Actuated { rule, .. } => self.stats(rule).actuations += 1,
+            BacktestEvent::PlanCancelled { rule, .. } => self.stats(rule).cancelled += 1,
+            BacktestEvent::PlanFinished { .. } => {}
+        }
+        self.events.push(event);
+    }
+
+    fn stats(&mut self, rule: &str) -> &mut RuleStats {
+        self.rules.entry(rule.to_string()).or_default()
+    }
+}
+
+/// Reads a recording of measurements in JSON Lines format, sorted by time
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedSample>> {
+    let raw = std::fs::read_to_string(path).context("Couldn't read recording file")?;
+
+    let mut samples = raw
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid sample on line {} of recording", n + 1))
+        })
+        .collect::<Result<Vec<RecordedSample>>>()?;
+    samples.sort_by_key(|sample| sample.at);
+
+    Ok(samples)
+}
+
+/// Reads a ruleset to test instead of the configured one
+pub fn read_rules(path: &Path) -> Result<Vec<Rule>> {
+    let raw = std::fs::read(path).context("Couldn't read rules file")?;
+    serde_json::from_slice(&raw).context("Couldn't parse rules file")
+}
+
+/// Runs rules over recorded measurements without actuating anything
+///
+/// Time is simulated: action plans advance on a virtual clock driven by the timestamps of the
+/// samples, so waits and refire policies behave as they would have live.
+pub struct Backtest {
+    control: ControlLayer,
+    storage: Arc<Storage>,
+    /// Simulated nodes, by the reference used for them on the recording
+    simulated_nodes: HashMap<String, PeerId>,
+    /// Timestamp the virtual clock started at
+    start: u64,
+    /// Current timestamp of the virtual clock
+    now: u64,
+    report: BacktestReport,
+}
+
+impl Backtest {
+    pub fn new(config: &SystemConfig, rules: Vec<Rule>) -> Result<Self> {
+        let local_peer_id = config
+            .secrets
+            .as_ref()
+            .map_or_else(PeerId::random, |secrets| {
+                PeerId::from(Keypair::Ed25519(secrets.keypair.clone()).public())
+            });
+        let storage = Arc::new(
+            Storage::new(
+                local_peer_id,
+                config.peer.clone(),
+                Arc::new(MemoryBackend::new()),
+            )
+            .context("Couldn't open storage")?,
+        );
+        let mut control = ControlLayer::from_ruleset(
+            rules,
+            storage.clone(),
+            config.aliases.clone().unwrap_or_default(),
+        )
+        .with_modes(config.modes.clone().unwrap_or_default())
+        .with_scenes(config.scenes.clone().unwrap_or_default())
+        .with_virtual_clock();
+        control.validate();
+
+        Ok(Self {
+            control,
+            storage,
+            simulated_nodes: HashMap::new(),
+            start: 0,
+            now: 0,
+            report: BacktestReport::default(),
+        })
+    }
+
+    pub fn run(mut self, samples: Vec<RecordedSample>) -> BacktestReport {
+        self.start = samples.first().map_or(0, |sample| sample.at);
+        self.now = self.start;
+        self.report.from = samples.first().map(|sample| sample.at);
+        self.report.to = samples.last().map(|sample| sample.at);
+
+        for sample in samples {
+            let at = sample.at;
+            self.run_plans_until(at);
+            self.feed(&sample);
+            // Pick up whatever the sample set off right away
+            self.run_plans_until(at);
+        }
+
+        // Let any plan still running play out to the end
+        self.run_plans_until(u64::MAX);
+
+        self.report
+    }
+
+    fn node_for(&mut self, node: Option<&str>) -> PeerId {
+        let nodes = self.control.nodes();
+        let Some(node) = node else {
+            return nodes.local_peer_id();
+        };
+        if let Some(peer_id) = self.simulated_nodes.get(node) {
+            return *peer_id;
+        }
+
+        // Nodes given by peer ID keep it; unknown names get a made up one
+        let name = nodes.unalias(node).to_string();
+        let peer_id = match nodes.resolve(node) {
+            Ok(peer_id) if self.storage.peer_name(peer_id).is_some() => return peer_id,
+            Ok(peer_id) => peer_id,
+            Err(_) => PeerId::random(),
+        };
+        info!("Simulating node {} as {}", name, peer_id);
+        let changes = self.storage.merge_peer_data(
+            peer_id,
+            PeerData {
+                name,
+                devices: HashMap::new(),
+            },
+        );
+        self.simulated_nodes.insert(node.to_string(), peer_id);
+        self.control.peer_changed(peer_id, &changes);
+
+        peer_id
+    }
+
+    fn feed(&mut self, sample: &RecordedSample) {
+        self.report.samples += 1;
+        let node = self.node_for(sample.node.as_deref());
+
+        if let Some(device_type) = sample.device_type {
+            self.storage
+                .insert_device(node, &sample.data.device, device_type);
+        }
+        self.storage.insert_sensor_data(node, sample.data.clone());
+
+        let node_name = self
+            .storage
+            .peer_name(node)
+            .unwrap_or_else(|| node.to_base58());
+        let at = UNIX_EPOCH + Duration::from_millis(sample.at);
+        for fired in self.control.trigger_detailed(node, &sample.data, at) {
+            self.report.record(BacktestEvent::Fired {
+                at: sample.at,
+                rule: fired.rule.clone(),
+                node: node_name.clone(),
+                device: sample.data.device.clone(),
+                sensor_name: sample.data.sensor_name.clone(),
+                value: sample.data.value.clone(),
+                execution: fired.execution,
+            });
+            for action in fired.actions {
+                self.report.record(BacktestEvent::Actuated {
+                    at: sample.at,
+                    rule: fired.rule.clone(),
+                    execution: None,
+                    action,
+                });
+            }
+        }
+    }
+
+    /// Moves the virtual clock up to the given timestamp, recording what plans do meanwhile
+    fn run_plans_until(&mut self, until: u64) {
+        let until_offset = Duration::from_millis(until.saturating_sub(self.start));
+
+        loop {
+            while let Some(Some(event)) = self.control.plans.events.recv().now_or_never() {
+                self.handle_plan_event(self.now, event);
+            }
+
+            match self.control.plans.advance(until_offset) {
+                Some(at) => {
+                    let at = u64::try_from(at.as_millis()).unwrap_or(u64::MAX);
+                    self.now = self.start.saturating_add(at);
+                }
+                None => break,
+            }
+        }
+        self.now = self.now.max(until);
+    }
+
+    fn handle_plan_event(&mut self, at: u64, event: PlanEvent) {
+        match event {
+            PlanEvent::Started { .. } => {}
+            PlanEvent::Actuate {
+                execution,
+                rule,
+                action,
+            } => {
+                self.report.record(BacktestEvent::Actuated {
+                    at,
+                    rule,
+                    execution: Some(execution),
+                    action,
+                });
+            }
+            PlanEvent::Finished { execution, rule } => {
+                self.control.plans.finished(execution);
+                self.report.record(BacktestEvent::PlanFinished {
+                    at,
+                    rule,
+                    execution,
+                });
+            }
+            PlanEvent::Cancelled { execution, rule } => {
+                self.report.record(BacktestEvent::PlanCancelled {
+                    at,
+                    rule,
+                    execution,
+                });
+            }
+        }
+    }
+}
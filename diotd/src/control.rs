@@ -1,14 +1,45 @@
-use std::collections::HashMap;
+mod actuation;
+mod modes;
+mod nodes;
+mod plan;
+mod script;
+mod template;
+mod validate;
+mod window;
 
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
 use diot_core::device::Measurement;
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     hardware::{FullActuatorData, FullSensorData},
+    liveness::LIVENESS_DEVICE,
+    store::{PeerChange, SeriesId, Storage},
     system::peerid_opt_parse,
 };
 
+pub use actuation::{
+    ActuationEvent, ActuationId, ActuationOutcome, ActuationSignal, ActuationTracker, RetryPolicy,
+};
+use modes::Modes;
+pub use modes::{ModeState, ModesConfig};
+use nodes::NodeRef;
+pub use nodes::NodeResolver;
+pub use script::{ScriptConfig, ScriptHost, ScriptOutput};
+use template::{ActuatorPayload, TemplateContext};
+pub use validate::RuleIssue;
+use validate::Validator;
+use window::{Aggregate, SensorWindows};
+
+pub use plan::{ActionPlan, ExecutionId, PlanEvent, PlanExecutionInfo, PlanExecutor};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "operation", rename_all = "snake_case")]
 pub enum ConditionOp {
@@ -20,15 +51,47 @@ pub enum ConditionOp {
     LessOrEqualThan { value: Measurement },
 }
 
+impl ConditionOp {
+    pub fn matches(&self, input: &Measurement) -> bool {
+        match self {
+            ConditionOp::Any => true,
+            ConditionOp::Equal { value } => input.eq(value),
+            ConditionOp::GreaterThan { value } => input.gt(value).unwrap_or(false),
+            ConditionOp::LessThan { value } => input.lt(value).unwrap_or(false),
+            ConditionOp::GreaterOrEqualThan { value } => input.geq(value).unwrap_or(false),
+            ConditionOp::LessOrEqualThan { value } => input.leq(value).unwrap_or(false),
+        }
+    }
+}
+
+/// A sensor as referenced on a rule
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UniversalSensorIdentifier {
-    #[serde(default, with = "peerid_opt_parse")]
-    node: Option<PeerId>,
+    #[serde(default)]
+    node: Option<NodeRef>,
     device: String,
     sensor_name: String,
 }
 
 impl UniversalSensorIdentifier {
+    fn resolve(&self, nodes: &NodeResolver) -> Result<SensorKey> {
+        Ok(SensorKey {
+            node: nodes.resolve_opt(self.node.as_ref())?,
+            device: self.device.clone(),
+            sensor_name: self.sensor_name.clone(),
+        })
+    }
+}
+
+/// A sensor identified by the peer ID of its node, `None` being this node
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SensorKey {
+    node: Option<PeerId>,
+    device: String,
+    sensor_name: String,
+}
+
+impl SensorKey {
     pub fn from_local(data: FullSensorData) -> Self {
         Self {
             node: None,
@@ -45,8 +108,13 @@ impl UniversalSensorIdentifier {
         }
     }
 
-    pub fn corresponds_with(&self, data: &FullSensorData) -> bool {
-        data.device == self.device && data.sensor_name == self.sensor_name
+    /// The sensor, if its measurements come from another node over the network
+    fn remote_series(&self) -> Option<SeriesId> {
+        // Liveness measurements are made up by each node itself
+        if self.device == LIVENESS_DEVICE {
+            return None;
+        }
+        Some(SeriesId::new(self.node?, &self.device, &self.sensor_name))
     }
 }
 
@@ -56,91 +124,772 @@ pub struct Action {
     pub node: Option<PeerId>,
     #[serde(flatten)]
     pub actuator: FullActuatorData,
+    #[serde(default, skip_serializing_if = "RetryPolicy::is_default")]
+    pub retry: RetryPolicy,
+    /// Action to take instead if this one fails for good
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<Box<Action>>,
+}
+
+/// An [`Action`] as written on a rule, whose payload may depend on the triggering measurement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionTemplate {
+    #[serde(default)]
+    pub node: Option<NodeRef>,
+    pub device: String,
+    pub actuator_name: String,
+    pub data: ActuatorPayload,
+    #[serde(default, skip_serializing_if = "RetryPolicy::is_default")]
+    pub retry: RetryPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<Box<ActionTemplate>>,
+}
+
+impl ActionTemplate {
+    pub fn render(&self, ctx: &TemplateContext) -> Result<Action> {
+        Ok(Action {
+            node: ctx.nodes.resolve_opt(self.node.as_ref())?,
+            actuator: FullActuatorData {
+                device: self.device.clone(),
+                actuator_name: self.actuator_name.clone(),
+                data: self.data.render(ctx)?,
+            },
+            retry: self.retry.clone(),
+            fallback: match &self.fallback {
+                Some(fallback) => Some(Box::new(fallback.render(ctx)?)),
+                None => None,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Condition {
+    sensor: UniversalSensorIdentifier,
+    on: ConditionOp,
+}
+
+/// What a rule does once it fires: a single action, a whole plan, a mode switch or a scene
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum RuleAction {
+    Plan(ActionPlan),
+    SetMode { set_mode: String },
+    ApplyScene { scene: String },
+    Single(ActionTemplate),
+}
+
+impl<'de> Deserialize<'de> for RuleAction {
+    /// Tells the kind of action apart by the key only it has, so that errors are about that kind
+    /// rather than about none of them matching
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        struct SetMode {
+            set_mode: String,
+        }
+
+        #[derive(Deserialize)]
+        struct ApplyScene {
+            scene: String,
+        }
+
+        fn parse<T: serde::de::DeserializeOwned, E: Error>(
+            value: serde_json::Value,
+            kind: &str,
+        ) -> Result<T, E> {
+            serde_json::from_value(value)
+                .map_err(|err| E::custom(format!("invalid {kind} action: {err}")))
+        }
+
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let Some(fields) = value.as_object() else {
+            return Err(D::Error::custom(
+                "invalid action: expected an object with \"steps\", \"set_mode\", \"scene\" or \"device\"",
+            ));
+        };
+
+        if fields.contains_key("steps") {
+            parse(value, "plan").map(Self::Plan)
+        } else if fields.contains_key("set_mode") {
+            parse(value, "mode switch").map(|action: SetMode| Self::SetMode {
+                set_mode: action.set_mode,
+            })
+        } else if fields.contains_key("scene") {
+            parse(value, "scene").map(|action: ApplyScene| Self::ApplyScene {
+                scene: action.scene,
+            })
+        } else {
+            parse(value, "actuator").map(Self::Single)
+        }
+    }
+}
+
+/// A named set of actions applied at once
+pub type Scene = Vec<ActionTemplate>;
+
+pub type RuleId = String;
+
+/// A rule that fired on a measurement
+#[derive(Debug, Clone, Default)]
+pub struct FiredRule {
+    pub rule: RuleId,
+    /// Actions to take right away, for rules with a single action or applying a scene
+    pub actions: Vec<Action>,
+    /// Execution started, for rules with an action plan
+    pub execution: Option<ExecutionId>,
+    /// New mode, for rules which switched it
+    pub mode: Option<ModeState>,
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rule {
+    /// Identifier of the rule; generated on load if missing
+    #[serde(default)]
+    pub id: RuleId,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Modes the rule is active in; active in all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modes: Vec<String>,
     sensor: UniversalSensorIdentifier,
+    /// Aggregation of recent measurements to evaluate the condition on, instead of the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aggregate: Option<Aggregate>,
     on: ConditionOp,
-    then: Action,
+    then: RuleAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum RuleChange {
+    Added { rule: Box<Rule> },
+    Replaced { rule: Box<Rule> },
+    Enabled { id: RuleId },
+    Disabled { id: RuleId },
+    Deleted { id: RuleId },
 }
 
 pub struct ControlLayer {
-    rule_triggers: HashMap<UniversalSensorIdentifier, Vec<usize>>,
+    rule_triggers: HashMap<SensorKey, Vec<usize>>,
     rules: Vec<Rule>,
+    nodes: NodeResolver,
+    /// Last reason why each rule's sensor couldn't be resolved, to avoid repeating warnings
+    unresolved: HashMap<RuleId, String>,
+    /// Problems found on the rules the last time they were validated
+    issues: Vec<RuleIssue>,
+    windows: SensorWindows,
+    modes: Modes,
+    scenes: HashMap<String, Scene>,
+    pub(crate) plans: PlanExecutor,
+    pub(crate) actuations: ActuationTracker,
+    pub(crate) scripts: ScriptHost,
 }
 
 impl ControlLayer {
-    pub fn from_ruleset(rules: Vec<Rule>) -> Self {
+    pub fn from_ruleset(
+        mut rules: Vec<Rule>,
+        storage: Arc<Storage>,
+        aliases: HashMap<String, String>,
+    ) -> Self {
         info!("Loading {} rules", rules.len());
 
+        for i in 0..rules.len() {
+            let duplicated = rules[..i].iter().any(|other| other.id == rules[i].id);
+            if rules[i].id.is_empty() || duplicated {
+                let mut n = i;
+                while rules.iter().any(|other| other.id == format!("rule-{n}")) {
+                    n += 1;
+                }
+                if duplicated {
+                    warn!(
+                        "Duplicated rule ID \"{}\", renaming to \"rule-{}\"",
+                        rules[i].id, n
+                    );
+                }
+                rules[i].id = format!("rule-{n}");
+            }
+        }
+
+        let mut control = Self {
+            rule_triggers: HashMap::new(),
+            rules,
+            nodes: NodeResolver::new(storage, aliases),
+            unresolved: HashMap::new(),
+            issues: Vec::new(),
+            windows: SensorWindows::default(),
+            modes: Modes::new(ModesConfig::default()),
+            scenes: HashMap::new(),
+            plans: PlanExecutor::new(),
+            actuations: ActuationTracker::new(),
+            scripts: ScriptHost::new(),
+        };
+        control.rebuild_triggers();
+        control
+    }
+
+    /// Rebuilds the trigger index from scratch
+    ///
+    /// Must be called after every change to `rules`, and whenever node names might have changed.
+    fn rebuild_triggers(&mut self) {
         let mut rule_triggers = HashMap::new();
-        for (i, rule) in rules.iter().enumerate() {
+        let mut unresolved = HashMap::new();
+        let mut aggregates = Vec::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.enabled {
+                continue;
+            }
+            let key = match rule.sensor.resolve(&self.nodes) {
+                Ok(key) => key,
+                Err(err) => {
+                    unresolved.insert(rule.id.clone(), format!("{err:#}"));
+                    continue;
+                }
+            };
+            if let Some(aggregate) = &rule.aggregate {
+                aggregates.push((key.clone(), aggregate));
+            }
             rule_triggers
-                .entry(rule.sensor.clone())
+                .entry(key)
                 .and_modify(|v: &mut Vec<_>| v.push(i))
                 .or_insert_with(|| vec![i]);
         }
+        self.windows.set_aggregates(aggregates.into_iter());
 
-        Self {
-            rule_triggers,
-            rules,
+        for (id, reason) in &unresolved {
+            if self.unresolved.get(id) != Some(reason) {
+                warn!(
+                    "Rule {} won't trigger until its sensor's node can be resolved: {}",
+                    id, reason
+                );
+            }
+        }
+        for id in self.unresolved.keys() {
+            if !unresolved.contains_key(id) && self.rules.iter().any(|rule| &rule.id == id) {
+                info!("Sensor node of rule {} is now resolved", id);
+            }
         }
+
+        self.rule_triggers = rule_triggers;
+        self.unresolved = unresolved;
     }
 
-    fn evaluate_rule(rule: &Rule, input: &FullSensorData, check_source: bool) -> bool {
-        if check_source && !rule.sensor.corresponds_with(input) {
-            return false;
+    /// Checks the rules against the devices known for each node, warning about new problems
+    ///
+    /// Must be called after every change to `rules`, and whenever node identities might have
+    /// changed.
+    pub fn validate(&mut self) -> &[RuleIssue] {
+        let issues = Validator::validate(&self.rules, &self.nodes, &self.scenes, &self.modes);
+        for issue in &issues {
+            if !self.issues.contains(issue) {
+                warn!(
+                    "Rule {} may not work as intended: {}",
+                    issue.rule, issue.problem
+                );
+            }
         }
 
-        match &rule.on {
-            ConditionOp::Any => true,
-            ConditionOp::Equal { value } => input.value.eq(value),
-            ConditionOp::GreaterThan { value } => input.value.gt(value).unwrap_or(false),
-            ConditionOp::LessThan { value } => input.value.lt(value).unwrap_or(false),
-            ConditionOp::GreaterOrEqualThan { value } => input.value.geq(value).unwrap_or(false),
-            ConditionOp::LessOrEqualThan { value } => input.value.leq(value).unwrap_or(false),
-        }
+        self.issues = issues;
+        &self.issues
+    }
+
+    pub fn issues(&self) -> &[RuleIssue] {
+        &self.issues
     }
 
-    pub fn trigger_local(&mut self, sensor: &FullSensorData) -> Option<Vec<Action>> {
-        let sensor_id = UniversalSensorIdentifier::from_local(sensor.clone());
+    /// Runs action plans on a virtual clock instead of in real time, for simulations
+    pub fn with_virtual_clock(mut self) -> Self {
+        self.plans = PlanExecutor::with_virtual_clock();
+        self
+    }
+
+    pub fn with_modes(mut self, modes: ModesConfig) -> Self {
+        self.modes = Modes::new(modes);
+        self
+    }
 
-        if let Some(rules) = self.rule_triggers.get(&sensor_id) {
-            let mut actions = Vec::new();
+    pub fn with_scenes(mut self, scenes: HashMap<String, Scene>) -> Self {
+        self.scenes = scenes;
+        self
+    }
 
-            for rule_idx in rules {
-                let rule = self.rules.get(*rule_idx).expect("a rule to be there");
+    /// Starts the given scripts, which are passed the measurements going through the control layer
+    pub fn with_scripts(mut self, scripts: Vec<ScriptConfig>) -> Self {
+        self.scripts.start(scripts, &self.nodes);
+        self
+    }
 
-                if Self::evaluate_rule(rule, sensor, false) {
-                    actions.push(rule.then.clone());
+    /// Lets the control layer know that the identity of a peer changed
+    pub fn peer_changed(&mut self, peer: PeerId, changes: &[PeerChange]) {
+        let node = (peer != self.nodes.local_peer_id()).then_some(peer);
+        for change in changes {
+            match change {
+                PeerChange::DeviceRemoved { device }
+                | PeerChange::DeviceRenamed { from: device, .. } => {
+                    self.windows.forget_device(node, device);
                 }
+                _ => {}
             }
+        }
+
+        self.rebuild_triggers();
+        self.validate();
+    }
+
+    fn rule_position(&self, id: &str) -> Result<usize> {
+        match self.rules.iter().position(|rule| rule.id == id) {
+            Some(idx) => Ok(idx),
+            None => bail!("No rule with ID \"{}\"", id),
+        }
+    }
 
-            Some(actions)
+    fn cancel_plans_of(&mut self, id: &str) {
+        for execution in self.plans.running_for_rule(id) {
+            self.plans.cancel(execution);
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Adds a new rule, or replaces the rule with the same ID
+    pub fn upsert_rule(&mut self, rule: Rule) -> Result<RuleChange> {
+        if rule.id.is_empty() {
+            bail!("Rule ID can't be empty");
+        }
+
+        let change = if let Ok(idx) = self.rule_position(&rule.id) {
+            self.cancel_plans_of(&rule.id);
+            self.rules[idx] = rule.clone();
+            RuleChange::Replaced {
+                rule: Box::new(rule),
+            }
         } else {
-            None
+            self.rules.push(rule.clone());
+            RuleChange::Added {
+                rule: Box::new(rule),
+            }
+        };
+        self.rebuild_triggers();
+        self.validate();
+
+        Ok(change)
+    }
+
+    pub fn set_rule_enabled(&mut self, id: &str, enabled: bool) -> Result<RuleChange> {
+        let idx = self.rule_position(id)?;
+        self.rules[idx].enabled = enabled;
+        if !enabled {
+            self.cancel_plans_of(id);
         }
+        self.rebuild_triggers();
+        self.validate();
+
+        let id = id.to_string();
+        Ok(if enabled {
+            RuleChange::Enabled { id }
+        } else {
+            RuleChange::Disabled { id }
+        })
     }
 
-    pub fn trigger_remote(&mut self, peer: PeerId, sensor: &FullSensorData) -> Option<Vec<Action>> {
-        let sensor_id = UniversalSensorIdentifier::from_remote(peer, sensor.clone());
+    pub fn delete_rule(&mut self, id: &str) -> Result<RuleChange> {
+        let idx = self.rule_position(id)?;
+        self.cancel_plans_of(id);
+        self.rules.remove(idx);
+        self.rebuild_triggers();
+        self.validate();
+
+        Ok(RuleChange::Deleted { id: id.to_string() })
+    }
 
-        if let Some(rules) = self.rule_triggers.get(&sensor_id) {
-            let mut actions = Vec::new();
+    fn evaluate_rule(
+        &self,
+        rule: &Rule,
+        sensor_id: &SensorKey,
+        input: &FullSensorData,
+        at: SystemTime,
+    ) -> bool {
+        rule.aggregate.as_ref().map_or_else(
+            || rule.on.matches(&input.value),
+            |aggregate| {
+                self.windows
+                    .aggregate(sensor_id, aggregate, at)
+                    .is_some_and(|value| rule.on.matches(&Measurement::Double(value)))
+            },
+        )
+    }
 
-            for rule_idx in rules {
-                let rule = self.rules.get(*rule_idx).expect("a rule to be there");
+    /// Fires the given rule, returning `None` if nothing came out of it
+    fn fire_rule(
+        &mut self,
+        rule_idx: usize,
+        node: PeerId,
+        sensor: &FullSensorData,
+    ) -> Option<FiredRule> {
+        let rule = self.rules.get(rule_idx).expect("a rule to be there");
+        let ctx = TemplateContext {
+            node,
+            trigger: Some(sensor),
+            nodes: &self.nodes,
+        };
 
-                if Self::evaluate_rule(rule, sensor, false) {
-                    info!("Sensor event matches local rule {}, triggering", rule_idx);
-                    actions.push(rule.then.clone());
+        match &rule.then {
+            RuleAction::Single(action) => match action.render(&ctx) {
+                Ok(action) => Some(FiredRule {
+                    rule: rule.id.clone(),
+                    actions: vec![action],
+                    ..FiredRule::default()
+                }),
+                Err(err) => {
+                    warn!(
+                        "Couldn't build action payload for rule {}: {:#}",
+                        rule.id, err
+                    );
+                    None
+                }
+            },
+            RuleAction::Plan(plan) => {
+                let rendered = match plan.render(&ctx) {
+                    Ok(rendered) => rendered,
+                    Err(err) => {
+                        warn!("Couldn't build action plan for rule {}: {:#}", rule.id, err);
+                        return None;
+                    }
+                };
+
+                let running = self.plans.running_for_rule(&rule.id);
+                if !running.is_empty() {
+                    match plan.on_refire {
+                        plan::RefirePolicy::Ignore => {
+                            debug!(
+                                "Plan for rule {} is already running, ignoring trigger",
+                                rule.id
+                            );
+                            return None;
+                        }
+                        plan::RefirePolicy::Restart => {
+                            for execution in running {
+                                info!(
+                                    "Restarting plan for rule {} (cancelling execution {})",
+                                    rule.id, execution
+                                );
+                                self.plans.cancel(execution);
+                            }
+                        }
+                    }
+                }
+
+                let execution = self.plans.start(rule.id.clone(), rendered);
+                info!(
+                    "Started execution {} of plan for rule {}",
+                    execution, rule.id
+                );
+                Some(FiredRule {
+                    rule: rule.id.clone(),
+                    execution: Some(execution),
+                    ..FiredRule::default()
+                })
+            }
+            RuleAction::ApplyScene { scene } => match self.render_scene(scene, &ctx) {
+                Ok(actions) => Some(FiredRule {
+                    rule: rule.id.clone(),
+                    actions,
+                    ..FiredRule::default()
+                }),
+                Err(err) => {
+                    warn!("Couldn't apply scene for rule {}: {:#}", rule.id, err);
+                    None
+                }
+            },
+            RuleAction::SetMode { set_mode } => {
+                let (id, set_mode) = (rule.id.clone(), set_mode.clone());
+                match self.set_mode(&set_mode) {
+                    Ok((mode, actions)) => Some(FiredRule {
+                        rule: id,
+                        actions,
+                        mode,
+                        ..FiredRule::default()
+                    }),
+                    Err(err) => {
+                        warn!("Couldn't switch mode for rule {}: {:#}", id, err);
+                        None
+                    }
                 }
             }
+        }
+    }
+
+    fn render_scene(&self, name: &str, ctx: &TemplateContext) -> Result<Vec<Action>> {
+        let Some(scene) = self.scenes.get(name) else {
+            bail!("Unknown scene \"{}\"", name);
+        };
+
+        scene.iter().map(|action| action.render(ctx)).collect()
+    }
+
+    /// Renders the actions of a scene applied by hand
+    pub fn apply_scene(&self, name: &str) -> Result<Vec<Action>> {
+        let ctx = TemplateContext {
+            node: self.nodes.local_peer_id(),
+            trigger: None,
+            nodes: &self.nodes,
+        };
+
+        self.render_scene(name, &ctx)
+    }
+
+    pub fn scenes(&self) -> &HashMap<String, Scene> {
+        &self.scenes
+    }
+
+    pub fn current_mode(&self) -> &ModeState {
+        self.modes.current()
+    }
+
+    /// Switches to a mode, returning the new state if it changed along with the actions of the
+    /// mode's scene
+    pub fn set_mode(&mut self, mode: &str) -> Result<(Option<ModeState>, Vec<Action>)> {
+        let Some(state) = self.modes.switch(mode, self.nodes.local_peer_id())? else {
+            return Ok((None, Vec::new()));
+        };
+        info!("Switched to mode {}", mode);
+        self.cancel_inactive_plans();
+
+        let actions = self.modes.scene_of(mode).map_or_else(Vec::new, |scene| {
+            self.apply_scene(scene).unwrap_or_else(|err| {
+                warn!("Couldn't apply scene of mode {}: {:#}", mode, err);
+                Vec::new()
+            })
+        });
 
-            Some(actions)
+        Ok((Some(state), actions))
+    }
+
+    /// Takes on the mode announced by another node, if newer; returns whether the mode changed
+    pub fn merge_mode(&mut self, remote: ModeState) -> bool {
+        let previous = self.modes.current().mode.clone();
+        if !self.modes.merge(remote) || self.modes.current().mode == previous {
+            return false;
+        }
+
+        info!(
+            "Switched to mode {} as set by {}",
+            self.modes.current().mode,
+            self.modes.current().set_by
+        );
+        self.cancel_inactive_plans();
+        true
+    }
+
+    /// Cancels running plans of rules which aren't active in the current mode
+    fn cancel_inactive_plans(&mut self) {
+        for info in self.plans.running() {
+            let inactive = self
+                .rules
+                .iter()
+                .find(|rule| rule.id == info.rule)
+                .is_some_and(|rule| !self.modes.is_active(&rule.modes));
+            if inactive {
+                info!(
+                    "Rule {} is inactive in the current mode, cancelling execution {} of its plan",
+                    info.rule, info.execution
+                );
+                self.plans.cancel(info.execution);
+            }
+        }
+    }
+
+    /// Cancels any running plan whose abort conditions are met by the given measurement
+    fn check_plan_aborts(&mut self, sensor_id: &SensorKey, sensor: &FullSensorData) {
+        for info in self.plans.running() {
+            let aborted = self
+                .rules
+                .iter()
+                .find(|rule| rule.id == info.rule)
+                .and_then(|rule| match &rule.then {
+                    RuleAction::Plan(plan) => Some(plan),
+                    _ => None,
+                })
+                .is_some_and(|plan| {
+                    plan.abort_on.iter().any(|cond| {
+                        cond.sensor
+                            .resolve(&self.nodes)
+                            .is_ok_and(|key| &key == sensor_id)
+                            && cond.on.matches(&sensor.value)
+                    })
+                });
+
+            if aborted {
+                info!(
+                    "Abort condition met for execution {} of plan for rule {}, cancelling",
+                    info.execution, info.rule
+                );
+                self.plans.cancel(info.execution);
+            }
+        }
+    }
+
+    fn trigger(
+        &mut self,
+        node: PeerId,
+        sensor_id: &SensorKey,
+        sensor: &FullSensorData,
+        at: SystemTime,
+    ) -> Option<Vec<FiredRule>> {
+        self.windows.record(sensor_id, at, &sensor.value);
+        self.check_plan_aborts(sensor_id, sensor);
+        self.scripts.dispatch(node, sensor_id, sensor, &self.nodes);
+
+        let rules = self.rule_triggers.get(sensor_id)?.clone();
+        let mut fired = Vec::new();
+
+        for rule_idx in rules {
+            let rule = self.rules.get(rule_idx).expect("a rule to be there");
+            if !self.modes.is_active(&rule.modes) {
+                continue;
+            }
+
+            if self.evaluate_rule(rule, sensor_id, sensor, at) {
+                info!("Sensor event matches local rule {}, triggering", rule.id);
+                fired.extend(self.fire_rule(rule_idx, node, sensor));
+            }
+        }
+
+        Some(fired)
+    }
+
+    /// Runs the rules triggered by a measurement of the given node, reporting which ones fired
+    pub fn trigger_detailed(
+        &mut self,
+        node: PeerId,
+        sensor: &FullSensorData,
+        at: SystemTime,
+    ) -> Vec<FiredRule> {
+        let sensor_id = if node == self.nodes.local_peer_id() {
+            SensorKey::from_local(sensor.clone())
         } else {
-            None
+            SensorKey::from_remote(node, sensor.clone())
+        };
+
+        self.trigger(node, &sensor_id, sensor, at)
+            .unwrap_or_default()
+    }
+
+    pub fn trigger_local(&mut self, sensor: &FullSensorData) -> Option<Vec<FiredRule>> {
+        let sensor_id = SensorKey::from_local(sensor.clone());
+        let local_peer_id = self.nodes.local_peer_id();
+
+        self.trigger(local_peer_id, &sensor_id, sensor, SystemTime::now())
+    }
+
+    pub fn trigger_remote(
+        &mut self,
+        peer: PeerId,
+        sensor: &FullSensorData,
+    ) -> Option<Vec<FiredRule>> {
+        let sensor_id = SensorKey::from_remote(peer, sensor.clone());
+
+        self.trigger(peer, &sensor_id, sensor, SystemTime::now())
+    }
+
+    pub fn nodes(&self) -> &NodeResolver {
+        &self.nodes
+    }
+
+    /// Sensors of other nodes that enabled rules and scripts listen to, along with the given
+    /// ones, or `None` if a script listens to every sensor
+    pub fn watched_sensors(
+        &self,
+        extra: &[UniversalSensorIdentifier],
+    ) -> Option<HashSet<SeriesId>> {
+        if self.scripts.listens_to_all() {
+            return None;
         }
+
+        let rule_sensors = self
+            .rules
+            .iter()
+            .filter(|rule| rule.enabled)
+            .flat_map(|rule| {
+                let abort_on = match &rule.then {
+                    RuleAction::Plan(plan) => &plan.abort_on[..],
+                    _ => &[],
+                };
+                std::iter::once(&rule.sensor).chain(abort_on.iter().map(|cond| &cond.sensor))
+            });
+        Some(
+            rule_sensors
+                .chain(self.scripts.sensors())
+                .chain(extra)
+                .filter_map(|sensor| sensor.resolve(&self.nodes).ok()?.remote_series())
+                .collect(),
+        )
+    }
+
+    pub fn running_plans(&self) -> Vec<PlanExecutionInfo> {
+        self.plans.running()
+    }
+
+    pub fn cancel_plan(&mut self, execution: ExecutionId) -> bool {
+        self.plans.cancel(execution)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(json: &str) -> Result<RuleAction, String> {
+        serde_json::from_str(json).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn rule_actions_are_told_apart_by_their_keys() {
+        assert!(matches!(
+            action(r#"{ "set_mode": "away" }"#),
+            Ok(RuleAction::SetMode { set_mode }) if set_mode == "away"
+        ));
+        assert!(matches!(
+            action(r#"{ "scene": "all-off" }"#),
+            Ok(RuleAction::ApplyScene { scene }) if scene == "all-off"
+        ));
+        assert!(matches!(
+            action(r#"{ "steps": [] }"#),
+            Ok(RuleAction::Plan(_))
+        ));
+        assert!(matches!(
+            action(r#"{ "device": "relay-1", "actuator_name": "fan", "data": { "unsigned": 1 } }"#),
+            Ok(RuleAction::Single(_))
+        ));
+    }
+
+    #[test]
+    fn rule_action_errors_name_the_kind_of_action() {
+        let err = action(r#"{ "device": "relay-1", "data": { "unsigned": 1 } }"#).unwrap_err();
+        assert!(
+            err.starts_with("invalid actuator action: missing field `actuator_name`"),
+            "{}",
+            err
+        );
+
+        let err = action(r#"{ "set_mode": 3 }"#).unwrap_err();
+        assert!(err.starts_with("invalid mode switch action:"), "{}", err);
+
+        let err = action(r#"{ "steps": [], "on_refire": "sometimes" }"#).unwrap_err();
+        assert!(err.starts_with("invalid plan action:"), "{}", err);
+
+        let err = action(r#""relay-1""#).unwrap_err();
+        assert!(
+            err.starts_with("invalid action: expected an object"),
+            "{}",
+            err
+        );
     }
 }
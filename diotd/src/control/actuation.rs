@@ -0,0 +1,483 @@
+use std::{collections::HashMap, time::Duration};
+
+use diot_core::device::ActuationResult;
+use libp2p_request_response::RequestId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use super::{Action, ExecutionId, RuleId};
+
+pub type ActuationId = u64;
+
+/// Result of an actuation, or why it couldn't be carried out
+pub type ActuationOutcome = Result<ActuationResult, String>;
+
+fn default_retry_delay() -> f64 {
+    1.0
+}
+
+/// How to go about an actuation that doesn't succeed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// How many more times to try after the first attempt fails
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds to wait between attempts
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: f64,
+    /// Seconds to wait for a result before considering an attempt failed
+    #[serde(default)]
+    pub timeout: Option<f64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            retry_delay: default_retry_delay(),
+            timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Something that happened to an actuation requested by the control layer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ActuationEvent {
+    Requested {
+        actuation: ActuationId,
+        rule: RuleId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        execution: Option<ExecutionId>,
+        /// Actuation this one is the fallback of
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback_of: Option<ActuationId>,
+        attempt: u32,
+        action: Action,
+    },
+    Succeeded {
+        actuation: ActuationId,
+        rule: RuleId,
+        attempt: u32,
+        result: ActuationResult,
+    },
+    Failed {
+        actuation: ActuationId,
+        rule: RuleId,
+        attempt: u32,
+        reason: String,
+        will_retry: bool,
+        /// Actuation started in its place, if the action has a fallback
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback: Option<ActuationId>,
+    },
+}
+
+/// Outcome of an attempt, fed back into the system loop
+#[derive(Debug)]
+pub enum ActuationSignal {
+    Completed {
+        actuation: ActuationId,
+        attempt: u32,
+        result: ActuationOutcome,
+    },
+    TimedOut {
+        actuation: ActuationId,
+        attempt: u32,
+    },
+    RetryDue {
+        actuation: ActuationId,
+    },
+}
+
+struct PendingActuation {
+    rule: RuleId,
+    execution: Option<ExecutionId>,
+    fallback_of: Option<ActuationId>,
+    action: Action,
+    attempt: u32,
+    /// Whether the current attempt is still waiting for an outcome
+    in_flight: bool,
+    /// Caller waiting for the actuation to settle for good, fallbacks included
+    waiter: Option<oneshot::Sender<ActuationOutcome>>,
+}
+
+/// Keeps track of in-flight actuations, deciding what to do when they fail
+pub struct ActuationTracker {
+    next_actuation: ActuationId,
+    pending: HashMap<ActuationId, PendingActuation>,
+    remote_requests: HashMap<RequestId, (ActuationId, u32)>,
+    signals_tx: UnboundedSender<ActuationSignal>,
+    pub(crate) signals: UnboundedReceiver<ActuationSignal>,
+}
+
+impl ActuationTracker {
+    pub fn new() -> Self {
+        let (signals_tx, signals) = unbounded_channel();
+
+        Self {
+            next_actuation: 0,
+            pending: HashMap::new(),
+            remote_requests: HashMap::new(),
+            signals_tx,
+            signals,
+        }
+    }
+
+    /// Registers an actuation requested by a rule; it must then be started through [`Self::attempt`]
+    pub fn begin(
+        &mut self,
+        rule: RuleId,
+        execution: Option<ExecutionId>,
+        action: Action,
+    ) -> ActuationId {
+        self.insert(rule, execution, None, action)
+    }
+
+    fn insert(
+        &mut self,
+        rule: RuleId,
+        execution: Option<ExecutionId>,
+        fallback_of: Option<ActuationId>,
+        action: Action,
+    ) -> ActuationId {
+        let actuation = self.next_actuation;
+        self.next_actuation += 1;
+
+        self.pending.insert(
+            actuation,
+            PendingActuation {
+                rule,
+                execution,
+                fallback_of,
+                action,
+                attempt: 0,
+                in_flight: false,
+                waiter: None,
+            },
+        );
+
+        actuation
+    }
+
+    /// Starts a new attempt of the given actuation, returning the action to carry out
+    pub fn attempt(&mut self, actuation: ActuationId) -> Option<(Action, u32, ActuationEvent)> {
+        let pending = self.pending.get_mut(&actuation)?;
+        pending.attempt += 1;
+        pending.in_flight = true;
+        let attempt = pending.attempt;
+
+        if let Some(timeout) = pending.action.retry.timeout {
+            let signals_tx = self.signals_tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs_f64(timeout.max(0.0))).await;
+                let _ = signals_tx.send(ActuationSignal::TimedOut { actuation, attempt });
+            });
+        }
+
+        let event = ActuationEvent::Requested {
+            actuation,
+            rule: pending.rule.clone(),
+            execution: pending.execution,
+            fallback_of: pending.fallback_of,
+            attempt,
+            action: pending.action.clone(),
+        };
+
+        Some((pending.action.clone(), attempt, event))
+    }
+
+    /// Lets `waiter` know of the final outcome of an actuation, once it and its fallbacks settle
+    pub fn notify(&mut self, actuation: ActuationId, waiter: oneshot::Sender<ActuationOutcome>) {
+        if let Some(pending) = self.pending.get_mut(&actuation) {
+            pending.waiter = Some(waiter);
+        }
+    }
+
+    /// Rule and action of an actuation which hasn't settled for good yet
+    pub fn get(&self, actuation: ActuationId) -> Option<(&RuleId, &Action)> {
+        self.pending
+            .get(&actuation)
+            .map(|pending| (&pending.rule, &pending.action))
+    }
+
+    /// Sender through which results of local attempts are to be reported
+    pub fn signal_sender(&self) -> UnboundedSender<ActuationSignal> {
+        self.signals_tx.clone()
+    }
+
+    /// Associates a request sent to a remote node with the attempt it carries out
+    pub fn track_request(&mut self, request: RequestId, actuation: ActuationId, attempt: u32) {
+        self.remote_requests.insert(request, (actuation, attempt));
+    }
+
+    /// Turns the outcome of a remote request into a signal, if it belongs to an actuation
+    pub fn remote_outcome(
+        &mut self,
+        request: RequestId,
+        result: ActuationOutcome,
+    ) -> Option<ActuationSignal> {
+        let (actuation, attempt) = self.remote_requests.remove(&request)?;
+        Some(ActuationSignal::Completed {
+            actuation,
+            attempt,
+            result,
+        })
+    }
+
+    /// Settles an attempt, scheduling retries and starting fallbacks as needed
+    ///
+    /// Returns what happened, along with an actuation to attempt right away, if any. Outcomes of
+    /// attempts which were already settled (e.g. late results after a timeout) are ignored.
+    pub fn settle(
+        &mut self,
+        actuation: ActuationId,
+        attempt: u32,
+        result: ActuationOutcome,
+    ) -> Option<(ActuationEvent, Option<ActuationId>)> {
+        let pending = self.pending.get_mut(&actuation)?;
+        if pending.attempt != attempt || !pending.in_flight {
+            return None;
+        }
+        pending.in_flight = false;
+
+        let mut retryable = true;
+        let reason = match result {
+            Ok(result @ (ActuationResult::Success | ActuationResult::Ignored)) => {
+                let pending = self.pending.remove(&actuation)?;
+                if let Some(waiter) = pending.waiter {
+                    let _ = waiter.send(Ok(result.clone()));
+                }
+                return Some((
+                    ActuationEvent::Succeeded {
+                        actuation,
+                        rule: pending.rule,
+                        attempt,
+                        result,
+                    },
+                    None,
+                ));
+            }
+            Ok(ActuationResult::NoResponse) => "Actuator didn't respond".to_string(),
+            Ok(ActuationResult::BadRequest { reason }) => format!("Bad request: {reason}"),
+            Ok(ActuationResult::ActuatorError {
+                error_code,
+                error_description,
+            }) => format!("Actuator error {error_code}: {error_description}"),
+            Ok(ActuationResult::Forbidden { reason }) => {
+                // Asking again won't change the mind of the node
+                retryable = false;
+                format!("Forbidden: {reason}")
+            }
+            Err(reason) => reason,
+        };
+
+        if retryable && attempt <= pending.action.retry.retries {
+            let delay = pending.action.retry.retry_delay;
+            let signals_tx = self.signals_tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs_f64(delay.max(0.0))).await;
+                let _ = signals_tx.send(ActuationSignal::RetryDue { actuation });
+            });
+
+            return Some((
+                ActuationEvent::Failed {
+                    actuation,
+                    rule: pending.rule.clone(),
+                    attempt,
+                    reason,
+                    will_retry: true,
+                    fallback: None,
+                },
+                None,
+            ));
+        }
+
+        let PendingActuation {
+            rule,
+            execution,
+            action,
+            waiter,
+            ..
+        } = self.pending.remove(&actuation)?;
+        let fallback = action
+            .fallback
+            .map(|fallback| self.insert(rule.clone(), execution, Some(actuation), *fallback));
+        if let Some(waiter) = waiter {
+            match fallback {
+                Some(fallback) => self.notify(fallback, waiter),
+                None => {
+                    let _ = waiter.send(Err(reason.clone()));
+                }
+            }
+        }
+
+        Some((
+            ActuationEvent::Failed {
+                actuation,
+                rule,
+                attempt,
+                reason,
+                will_retry: false,
+                fallback,
+            },
+            fallback,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diot_core::device::ActuatorValue;
+
+    use super::*;
+    use crate::hardware::FullActuatorData;
+
+    fn action(retries: u32, timeout: Option<f64>, fallback: Option<Action>) -> Action {
+        Action {
+            node: None,
+            actuator: FullActuatorData {
+                device: String::from("relay-1"),
+                actuator_name: String::from("fan"),
+                data: ActuatorValue::Unsigned(1),
+            },
+            retry: RetryPolicy {
+                retries,
+                retry_delay: 0.0,
+                timeout,
+            },
+            fallback: fallback.map(Box::new),
+        }
+    }
+
+    const FAILURE: ActuationOutcome = Ok(ActuationResult::NoResponse);
+
+    #[tokio::test]
+    async fn failed_attempts_are_retried_until_out_of_retries() {
+        let mut tracker = ActuationTracker::new();
+        let actuation = tracker.begin(String::from("rule"), None, action(1, None, None));
+        let (waiter, outcome) = oneshot::channel();
+        tracker.notify(actuation, waiter);
+
+        let (_, attempt, _) = tracker.attempt(actuation).unwrap();
+        assert!(matches!(
+            tracker.settle(actuation, attempt, FAILURE),
+            Some((
+                ActuationEvent::Failed {
+                    will_retry: true,
+                    ..
+                },
+                None
+            ))
+        ));
+        assert!(matches!(
+            tracker.signals.recv().await,
+            Some(ActuationSignal::RetryDue { actuation: due }) if due == actuation
+        ));
+
+        let (_, attempt, _) = tracker.attempt(actuation).unwrap();
+        assert_eq!(attempt, 2);
+        assert!(matches!(
+            tracker.settle(actuation, attempt, FAILURE),
+            Some((
+                ActuationEvent::Failed {
+                    will_retry: false,
+                    fallback: None,
+                    ..
+                },
+                None
+            ))
+        ));
+        assert_eq!(
+            outcome.await.unwrap().unwrap_err(),
+            "Actuator didn't respond"
+        );
+        assert!(tracker.get(actuation).is_none());
+    }
+
+    #[tokio::test]
+    async fn late_results_after_a_timeout_are_ignored() {
+        let mut tracker = ActuationTracker::new();
+        let actuation = tracker.begin(String::from("rule"), None, action(0, Some(0.0), None));
+
+        let (_, attempt, _) = tracker.attempt(actuation).unwrap();
+        assert!(matches!(
+            tracker.signals.recv().await,
+            Some(ActuationSignal::TimedOut { actuation: timed_out, attempt: timed_out_attempt })
+                if (timed_out, timed_out_attempt) == (actuation, attempt)
+        ));
+
+        assert!(tracker
+            .settle(actuation, attempt, Err(String::from("Timed out")))
+            .is_some());
+        assert!(tracker
+            .settle(actuation, attempt, Ok(ActuationResult::Success))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn fallbacks_take_over_and_report_to_the_waiter() {
+        let mut tracker = ActuationTracker::new();
+        let actuation = tracker.begin(
+            String::from("rule"),
+            None,
+            action(0, None, Some(action(0, None, None))),
+        );
+        let (waiter, outcome) = oneshot::channel();
+        tracker.notify(actuation, waiter);
+
+        let (_, attempt, _) = tracker.attempt(actuation).unwrap();
+        let next = match tracker.settle(actuation, attempt, FAILURE) {
+            Some((ActuationEvent::Failed { fallback, .. }, Some(next)))
+                if fallback == Some(next) =>
+            {
+                next
+            }
+            other => panic!("expected a fallback to start, got {:?}", other),
+        };
+
+        let (_, attempt, event) = tracker.attempt(next).unwrap();
+        assert!(matches!(
+            event,
+            ActuationEvent::Requested { fallback_of: Some(of), .. } if of == actuation
+        ));
+        assert!(matches!(
+            tracker.settle(next, attempt, Ok(ActuationResult::Success)),
+            Some((ActuationEvent::Succeeded { .. }, None))
+        ));
+        assert!(matches!(
+            outcome.await.unwrap(),
+            Ok(ActuationResult::Success)
+        ));
+    }
+
+    #[tokio::test]
+    async fn forbidden_actuations_are_not_retried() {
+        let mut tracker = ActuationTracker::new();
+        let actuation = tracker.begin(String::from("rule"), None, action(3, None, None));
+
+        let (_, attempt, _) = tracker.attempt(actuation).unwrap();
+        let forbidden = Ok(ActuationResult::Forbidden {
+            reason: String::from("Role \"viewer\" may not"),
+        });
+        assert!(matches!(
+            tracker.settle(actuation, attempt, forbidden),
+            Some((
+                ActuationEvent::Failed {
+                    will_retry: false,
+                    ..
+                },
+                None
+            ))
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_MODE: &str = "default";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModeConfig {
+    /// Scene to apply when switching into this mode
+    #[serde(default)]
+    pub scene: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModesConfig {
+    /// Mode to start in, until another node tells otherwise
+    pub initial: String,
+    /// Known modes; if empty, any mode name is accepted
+    #[serde(default)]
+    pub available: HashMap<String, ModeConfig>,
+}
+
+impl Default for ModesConfig {
+    fn default() -> Self {
+        Self {
+            initial: DEFAULT_MODE.to_string(),
+            available: HashMap::new(),
+        }
+    }
+}
+
+/// The mode the swarm is in, as agreed upon by every node
+///
+/// When two nodes disagree, the latest switch wins, ties being broken by the ID of the node
+/// which made it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeState {
+    pub mode: String,
+    /// Unix timestamp of the switch to this mode, in milliseconds; 0 for the initial mode
+    pub since: u64,
+    /// Peer ID of the node which switched to this mode; empty for the initial mode
+    pub set_by: String,
+}
+
+impl ModeState {
+    fn is_newer_than(&self, other: &Self) -> bool {
+        (self.since, &self.set_by) > (other.since, &other.set_by)
+    }
+}
+
+pub struct Modes {
+    config: ModesConfig,
+    current: ModeState,
+}
+
+impl Modes {
+    pub fn new(config: ModesConfig) -> Self {
+        let current = ModeState {
+            mode: config.initial.clone(),
+            since: 0,
+            set_by: String::new(),
+        };
+
+        Self { config, current }
+    }
+
+    pub fn current(&self) -> &ModeState {
+        &self.current
+    }
+
+    /// Whether a rule restricted to the given modes is active right now
+    pub fn is_active(&self, modes: &[String]) -> bool {
+        modes.is_empty() || modes.iter().any(|mode| mode == &self.current.mode)
+    }
+
+    /// Whether the given mode can be switched to
+    pub fn is_known(&self, mode: &str) -> bool {
+        self.config.available.is_empty() || self.config.available.contains_key(mode)
+    }
+
+    /// Scene to apply when entering the given mode
+    pub fn scene_of(&self, mode: &str) -> Option<&str> {
+        self.config
+            .available
+            .get(mode)
+            .and_then(|mode| mode.scene.as_deref())
+    }
+
+    /// Switches to a mode on behalf of this node, returning the new state if anything changed
+    pub fn switch(&mut self, mode: &str, by: PeerId) -> Result<Option<ModeState>> {
+        if !self.is_known(mode) {
+            bail!("Unknown mode \"{}\"", mode);
+        }
+        if self.current.mode == mode {
+            return Ok(None);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| {
+                u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+            });
+        self.current = ModeState {
+            mode: mode.to_string(),
+            // Never go back in time, even if our clock is behind
+            since: now.max(self.current.since + 1),
+            set_by: by.to_base58(),
+        };
+
+        Ok(Some(self.current.clone()))
+    }
+
+    /// Adopts the mode announced by another node if it's newer, returning whether it was
+    pub fn merge(&mut self, remote: ModeState) -> bool {
+        if !remote.is_newer_than(&self.current) {
+            return false;
+        }
+
+        self.current = remote;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modes(available: &[&str]) -> Modes {
+        Modes::new(ModesConfig {
+            initial: String::from("home"),
+            available: available
+                .iter()
+                .map(|mode| (mode.to_string(), ModeConfig::default()))
+                .collect(),
+        })
+    }
+
+    fn state(mode: &str, since: u64, set_by: &str) -> ModeState {
+        ModeState {
+            mode: mode.to_string(),
+            since,
+            set_by: set_by.to_string(),
+        }
+    }
+
+    #[test]
+    fn latest_switch_wins_with_ties_broken_by_node() {
+        let mut modes = modes(&[]);
+
+        assert!(modes.merge(state("away", 10, "b")));
+        assert!(!modes.merge(state("night", 9, "z")));
+        assert!(!modes.merge(state("night", 10, "a")));
+        assert!(!modes.merge(state("away", 10, "b")));
+        assert!(modes.merge(state("night", 10, "c")));
+        assert_eq!(modes.current(), &state("night", 10, "c"));
+    }
+
+    #[test]
+    fn initial_mode_loses_to_any_switch() {
+        let mut modes = modes(&[]);
+        assert_eq!(modes.current(), &state("home", 0, ""));
+        assert!(modes.merge(state("away", 0, "a")));
+    }
+
+    #[test]
+    fn local_switches_are_newer_than_what_they_replace() {
+        let mut modes = modes(&["home", "away"]);
+        let far_future = u64::MAX / 2;
+        assert!(modes.merge(state("home", far_future, "z")));
+
+        let switched = modes.switch("away", PeerId::random()).unwrap().unwrap();
+        assert_eq!(switched.since, far_future + 1);
+        assert!(!modes.merge(state("home", far_future, "z")));
+        assert_eq!(modes.switch("away", PeerId::random()).unwrap(), None);
+    }
+
+    #[test]
+    fn only_known_modes_can_be_switched_to() {
+        let mut modes = modes(&["home", "away"]);
+        assert!(modes.switch("party", PeerId::random()).is_err());
+        assert!(modes.is_active(&[]));
+        assert!(modes.is_active(&[String::from("away"), String::from("home")]));
+        assert!(!modes.is_active(&[String::from("away")]));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use anyhow::{bail, Result};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::store::Storage;
+
+/// Reference to a node as written on a rule: `local`, a peer ID, a configured alias or a display name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NodeRef(String);
+
+impl NodeRef {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for NodeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Resolves [`NodeRef`]s into peer IDs using the identities known to the storage
+#[derive(Clone)]
+pub struct NodeResolver {
+    storage: Arc<Storage>,
+    aliases: HashMap<String, String>,
+}
+
+impl NodeResolver {
+    pub fn new(storage: Arc<Storage>, aliases: HashMap<String, String>) -> Self {
+        Self { storage, aliases }
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.storage.local_peer_id()
+    }
+
+    /// Returns what the given alias stands for, or the reference itself if it isn't an alias
+    pub fn unalias<'a>(&'a self, node: &'a str) -> &'a str {
+        self.aliases.get(node).map_or(node, String::as_str)
+    }
+
+    /// Resolves a node reference into the peer ID it points to
+    pub fn resolve(&self, node: &str) -> Result<PeerId> {
+        let target = self.unalias(node);
+
+        if target == "local" {
+            return Ok(self.local_peer_id());
+        }
+
+        if let Ok(peer_id) = target.parse::<PeerId>() {
+            return Ok(peer_id);
+        }
+
+        let mut candidates = self.storage.peers_named(target);
+        match candidates.len() {
+            0 => bail!("No node named \"{}\" is known (yet)", target),
+            1 => Ok(candidates.remove(0)),
+            _ => bail!(
+                "Node name \"{}\" is ambiguous, it matches: {}",
+                target,
+                candidates
+                    .iter()
+                    .map(PeerId::to_base58)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// Resolves an optional node reference, where both `None` and this node itself mean `None`
+    pub fn resolve_opt(&self, node: Option<&NodeRef>) -> Result<Option<PeerId>> {
+        match node {
+            None => Ok(None),
+            Some(node) => {
+                let peer_id = self.resolve(node.as_str())?;
+                Ok(if peer_id == self.local_peer_id() {
+                    None
+                } else {
+                    Some(peer_id)
+                })
+            }
+        }
+    }
+}
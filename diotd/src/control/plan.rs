@@ -0,0 +1,471 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use super::{Action, ActionTemplate, Condition, RuleId, TemplateContext};
+
+pub type ExecutionId = u64;
+
+/// A single step of an [`ActionPlan`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanStep<A> {
+    /// Request an actuation
+    Actuate(A),
+    /// Wait before going on with the next step
+    Wait { seconds: f64 },
+    /// Run the given steps one after another
+    Sequence(Vec<PlanStep<A>>),
+    /// Run the given steps at the same time, waiting for all of them to finish
+    Parallel(Vec<PlanStep<A>>),
+}
+
+impl PlanStep<ActionTemplate> {
+    pub fn render(&self, ctx: &TemplateContext) -> Result<PlanStep<Action>> {
+        Ok(match self {
+            Self::Actuate(action) => PlanStep::Actuate(action.render(ctx)?),
+            Self::Wait { seconds } => PlanStep::Wait { seconds: *seconds },
+            Self::Sequence(steps) => PlanStep::Sequence(
+                steps
+                    .iter()
+                    .map(|step| step.render(ctx))
+                    .collect::<Result<_>>()?,
+            ),
+            Self::Parallel(steps) => PlanStep::Parallel(
+                steps
+                    .iter()
+                    .map(|step| step.render(ctx))
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+}
+
+/// What to do when a rule fires again while its plan is still running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefirePolicy {
+    /// Cancel the running execution and start over
+    #[default]
+    Restart,
+    /// Keep the running execution and do nothing
+    Ignore,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionPlan {
+    pub steps: Vec<PlanStep<ActionTemplate>>,
+    /// Conditions that cancel a running execution of this plan when met
+    #[serde(default)]
+    pub abort_on: Vec<Condition>,
+    #[serde(default)]
+    pub on_refire: RefirePolicy,
+}
+
+impl ActionPlan {
+    pub fn render(&self, ctx: &TemplateContext) -> Result<PlanStep<Action>> {
+        Ok(PlanStep::Sequence(
+            self.steps
+                .iter()
+                .map(|step| step.render(ctx))
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PlanEvent {
+    Started {
+        execution: ExecutionId,
+        rule: RuleId,
+    },
+    Actuate {
+        execution: ExecutionId,
+        rule: RuleId,
+        action: Action,
+    },
+    Finished {
+        execution: ExecutionId,
+        rule: RuleId,
+    },
+    Cancelled {
+        execution: ExecutionId,
+        rule: RuleId,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanExecutionInfo {
+    pub execution: ExecutionId,
+    pub rule: RuleId,
+}
+
+struct RunningPlan {
+    rule: RuleId,
+    /// Task running the plan; `None` when running on a virtual clock
+    handle: Option<JoinHandle<()>>,
+}
+
+/// An event of a plan due at some point in virtual time
+struct ScheduledEvent {
+    at: Duration,
+    execution: ExecutionId,
+    event: PlanEvent,
+}
+
+/// Clock that only moves forward when told to, for simulating plans without waiting
+#[derive(Default)]
+struct VirtualClock {
+    now: Duration,
+    /// Pending events, in the order they are due
+    pending: Vec<ScheduledEvent>,
+}
+
+/// Keeps track of the action plans being executed
+pub struct PlanExecutor {
+    next_execution: ExecutionId,
+    running: HashMap<ExecutionId, RunningPlan>,
+    virtual_clock: Option<VirtualClock>,
+    events_tx: UnboundedSender<PlanEvent>,
+    pub(crate) events: UnboundedReceiver<PlanEvent>,
+}
+
+impl PlanExecutor {
+    pub fn new() -> Self {
+        let (events_tx, events) = unbounded_channel();
+
+        Self {
+            next_execution: 0,
+            running: HashMap::new(),
+            virtual_clock: None,
+            events_tx,
+            events,
+        }
+    }
+
+    /// Creates an executor that runs plans on a virtual clock, moved forward through [`Self::advance`]
+    pub fn with_virtual_clock() -> Self {
+        Self {
+            virtual_clock: Some(VirtualClock::default()),
+            ..Self::new()
+        }
+    }
+
+    pub fn start(&mut self, rule: RuleId, plan: PlanStep<Action>) -> ExecutionId {
+        let execution = self.next_execution;
+        self.next_execution += 1;
+
+        let handle = if let Some(clock) = &mut self.virtual_clock {
+            let mut timeline = Vec::new();
+            let end = schedule_step(plan, clock.now, &mut timeline);
+            let events = timeline
+                .into_iter()
+                .map(|(at, action)| {
+                    let event = PlanEvent::Actuate {
+                        execution,
+                        rule: rule.clone(),
+                        action,
+                    };
+                    (at, event)
+                })
+                .chain(std::iter::once((
+                    end,
+                    PlanEvent::Finished {
+                        execution,
+                        rule: rule.clone(),
+                    },
+                )));
+            for (at, event) in events {
+                let idx = clock.pending.partition_point(|pending| pending.at <= at);
+                clock.pending.insert(
+                    idx,
+                    ScheduledEvent {
+                        at,
+                        execution,
+                        event,
+                    },
+                );
+            }
+            None
+        } else {
+            let events_tx = self.events_tx.clone();
+            let rule = rule.clone();
+            Some(tokio::spawn(async move {
+                run_step(execution, &rule, plan, &events_tx).await;
+                let _ = events_tx.send(PlanEvent::Finished { execution, rule });
+            }))
+        };
+
+        self.running.insert(
+            execution,
+            RunningPlan {
+                rule: rule.clone(),
+                handle,
+            },
+        );
+        let _ = self.events_tx.send(PlanEvent::Started { execution, rule });
+
+        execution
+    }
+
+    /// Moves the virtual clock forward up to `until`, emitting the next event due by then
+    ///
+    /// Returns the time the event was due at, or `None` if there was none, in which case the
+    /// clock is left at `until`. Does nothing when not running on a virtual clock.
+    pub fn advance(&mut self, until: Duration) -> Option<Duration> {
+        let clock = self.virtual_clock.as_mut()?;
+        if clock.pending.first().is_none_or(|next| next.at > until) {
+            clock.now = clock.now.max(until);
+            return None;
+        }
+
+        let next = clock.pending.remove(0);
+        clock.now = clock.now.max(next.at);
+        let _ = self.events_tx.send(next.event);
+        Some(next.at)
+    }
+
+    pub fn cancel(&mut self, execution: ExecutionId) -> bool {
+        if let Some(running) = self.running.remove(&execution) {
+            if let Some(handle) = running.handle {
+                handle.abort();
+            }
+            if let Some(clock) = &mut self.virtual_clock {
+                clock
+                    .pending
+                    .retain(|pending| pending.execution != execution);
+            }
+            let _ = self.events_tx.send(PlanEvent::Cancelled {
+                execution,
+                rule: running.rule,
+            });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets about an execution once it has finished on its own
+    ///
+    /// Returns `false` if the execution had already been cancelled.
+    pub fn finished(&mut self, execution: ExecutionId) -> bool {
+        self.running.remove(&execution).is_some()
+    }
+
+    pub fn running_for_rule(&self, rule: &str) -> Vec<ExecutionId> {
+        self.running
+            .iter()
+            .filter(|(_, running)| running.rule == rule)
+            .map(|(execution, _)| *execution)
+            .collect()
+    }
+
+    pub fn running(&self) -> Vec<PlanExecutionInfo> {
+        self.running
+            .iter()
+            .map(|(execution, running)| PlanExecutionInfo {
+                execution: *execution,
+                rule: running.rule.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Lays out the actuations of a step on a timeline starting at `start`, returning when it ends
+fn schedule_step(
+    step: PlanStep<Action>,
+    start: Duration,
+    timeline: &mut Vec<(Duration, Action)>,
+) -> Duration {
+    match step {
+        PlanStep::Actuate(action) => {
+            timeline.push((start, action));
+            start
+        }
+        PlanStep::Wait { seconds } => start + Duration::from_secs_f64(seconds.max(0.0)),
+        PlanStep::Sequence(steps) => steps
+            .into_iter()
+            .fold(start, |at, step| schedule_step(step, at, timeline)),
+        PlanStep::Parallel(steps) => steps
+            .into_iter()
+            .map(|step| schedule_step(step, start, timeline))
+            .max()
+            .unwrap_or(start),
+    }
+}
+
+fn run_step<'a>(
+    execution: ExecutionId,
+    rule: &'a RuleId,
+    step: PlanStep<Action>,
+    events_tx: &'a UnboundedSender<PlanEvent>,
+) -> BoxFuture<'a, ()> {
+    async move {
+        match step {
+            PlanStep::Actuate(action) => {
+                let _ = events_tx.send(PlanEvent::Actuate {
+                    execution,
+                    rule: rule.clone(),
+                    action,
+                });
+            }
+            PlanStep::Wait { seconds } => {
+                tokio::time::sleep(Duration::from_secs_f64(seconds.max(0.0))).await;
+            }
+            PlanStep::Sequence(steps) => {
+                for step in steps {
+                    run_step(execution, rule, step, events_tx).await;
+                }
+            }
+            PlanStep::Parallel(steps) => {
+                join_all(
+                    steps
+                        .into_iter()
+                        .map(|step| run_step(execution, rule, step, events_tx)),
+                )
+                .await;
+            }
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use diot_core::device::ActuatorValue;
+
+    use super::*;
+    use crate::{control::RetryPolicy, hardware::FullActuatorData};
+
+    fn actuate(actuator_name: &str) -> PlanStep<Action> {
+        PlanStep::Actuate(Action {
+            node: None,
+            actuator: FullActuatorData {
+                device: String::from("relay-1"),
+                actuator_name: actuator_name.to_string(),
+                data: ActuatorValue::Signal,
+            },
+            retry: RetryPolicy::default(),
+            fallback: None,
+        })
+    }
+
+    fn wait(seconds: f64) -> PlanStep<Action> {
+        PlanStep::Wait { seconds }
+    }
+
+    /// Names of the actuators of the events received so far, `.` standing for the end of a plan
+    fn drain(executor: &mut PlanExecutor) -> Vec<String> {
+        let mut seen = Vec::new();
+        while let Some(Some(event)) = executor.events.recv().now_or_never() {
+            match event {
+                PlanEvent::Actuate { action, .. } => seen.push(action.actuator.actuator_name),
+                PlanEvent::Finished { .. } => seen.push(String::from(".")),
+                PlanEvent::Cancelled { .. } => seen.push(String::from("x")),
+                PlanEvent::Started { .. } => {}
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn virtual_clock_emits_events_when_due() {
+        let mut executor = PlanExecutor::with_virtual_clock();
+        let plan = PlanStep::Sequence(vec![
+            actuate("a"),
+            wait(10.0),
+            PlanStep::Parallel(vec![
+                PlanStep::Sequence(vec![wait(5.0), actuate("c")]),
+                actuate("b"),
+            ]),
+        ]);
+        executor.start(String::from("first"), plan);
+        executor.start(
+            String::from("second"),
+            PlanStep::Sequence(vec![wait(12.0), actuate("z")]),
+        );
+
+        assert_eq!(executor.advance(Duration::ZERO), Some(Duration::ZERO));
+        assert_eq!(drain(&mut executor), ["a"]);
+        assert_eq!(executor.advance(Duration::from_secs(9)), None);
+        assert!(drain(&mut executor).is_empty());
+
+        let mut due = Vec::new();
+        while let Some(at) = executor.advance(Duration::from_secs(20)) {
+            due.push(at.as_secs());
+        }
+        assert_eq!(due, [10, 12, 12, 15, 15]);
+        assert_eq!(drain(&mut executor), ["b", "z", ".", "c", "."]);
+    }
+
+    #[test]
+    fn cancelling_drops_pending_virtual_events() {
+        let mut executor = PlanExecutor::with_virtual_clock();
+        let execution = executor.start(
+            String::from("rule"),
+            PlanStep::Sequence(vec![wait(1.0), actuate("a")]),
+        );
+
+        assert!(executor.cancel(execution));
+        assert_eq!(executor.advance(Duration::from_secs(60)), None);
+        assert_eq!(drain(&mut executor), ["x"]);
+    }
+
+    #[tokio::test]
+    async fn plans_run_their_steps_in_order() {
+        let mut executor = PlanExecutor::new();
+        let plan = PlanStep::Sequence(vec![
+            actuate("a"),
+            wait(0.01),
+            PlanStep::Parallel(vec![
+                PlanStep::Sequence(vec![wait(0.02), actuate("c")]),
+                actuate("b"),
+            ]),
+            actuate("d"),
+        ]);
+        let execution = executor.start(String::from("rule"), plan);
+        assert_eq!(executor.running_for_rule("rule"), vec![execution]);
+
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some(".") {
+            match executor.events.recv().await.unwrap() {
+                PlanEvent::Actuate { action, .. } => seen.push(action.actuator.actuator_name),
+                PlanEvent::Finished {
+                    execution: finished,
+                    ..
+                } => {
+                    assert_eq!(finished, execution);
+                    seen.push(String::from("."));
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(seen, ["a", "b", "c", "d", "."]);
+        assert!(executor.finished(execution));
+        assert!(executor.running().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_plans_stop_and_are_not_finished() {
+        let mut executor = PlanExecutor::new();
+        let execution = executor.start(
+            String::from("rule"),
+            PlanStep::Sequence(vec![wait(60.0), actuate("a")]),
+        );
+
+        assert!(executor.cancel(execution));
+        assert!(!executor.cancel(execution));
+        assert!(!executor.finished(execution));
+        assert_eq!(drain(&mut executor), ["x"]);
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::{
+    nodes::NodeRef,
+    template::{into_actuator_value, ActuatorValueKind},
+    Action, NodeResolver, RetryPolicy, SensorKey, UniversalSensorIdentifier,
+};
+use crate::hardware::{FullActuatorData, FullSensorData};
+
+/// How many sensor events may wait for a script before new ones are dropped
+const SCRIPT_QUEUE_SIZE: usize = 64;
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+fn default_timeout() -> f64 {
+    0.5
+}
+
+/// Where to take the source of a script from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ScriptSource {
+    File { path: PathBuf },
+    Inline { source: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptConfig {
+    pub id: String,
+    #[serde(flatten)]
+    pub source: ScriptSource,
+    /// Sensors whose measurements are passed to `on_sensor`; all of them if empty
+    #[serde(default)]
+    pub sensors: Vec<UniversalSensorIdentifier>,
+    /// Seconds between calls to `on_tick`, if the script should be called periodically
+    #[serde(default)]
+    pub tick_every: Option<f64>,
+    /// Maximum number of operations a single call may take
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+    /// Maximum seconds a single call may take
+    #[serde(default = "default_timeout")]
+    pub timeout: f64,
+}
+
+/// Actuations requested by a script during a single call
+#[derive(Debug)]
+pub struct ScriptOutput {
+    pub script: String,
+    pub actions: Vec<Action>,
+}
+
+/// An action as passed to `actuate` from a script
+#[derive(Debug, Deserialize)]
+struct ScriptAction {
+    #[serde(default)]
+    node: Option<NodeRef>,
+    device: String,
+    actuator_name: String,
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(default)]
+    into: Option<ActuatorValueKind>,
+    #[serde(default)]
+    retry: RetryPolicy,
+}
+
+impl ScriptAction {
+    fn into_action(self, nodes: &NodeResolver) -> Result<Action> {
+        let value = match self.data {
+            serde_json::Value::Null => Measurement::Signal,
+            serde_json::Value::Number(number) => number.as_i64().map_or_else(
+                || Measurement::Double(number.as_f64().unwrap_or(f64::NAN)),
+                Measurement::Integer,
+            ),
+            serde_json::Value::String(string) => Measurement::String(string),
+            other => bail!("Can't actuate with {}", other),
+        };
+
+        Ok(Action {
+            node: nodes.resolve_opt(self.node.as_ref())?,
+            actuator: FullActuatorData {
+                device: self.device,
+                actuator_name: self.actuator_name,
+                data: into_actuator_value(value, self.into)?,
+            },
+            retry: self.retry,
+            fallback: None,
+        })
+    }
+}
+
+fn measurement_to_dynamic(value: Measurement) -> Dynamic {
+    match value {
+        Measurement::Signal => Dynamic::UNIT,
+        Measurement::Integer(val) => Dynamic::from(val),
+        Measurement::Double(val) => Dynamic::from(val),
+        Measurement::String(val) => Dynamic::from(val),
+    }
+}
+
+/// Input for a script thread
+enum ScriptInput {
+    Sensor { node: PeerId, data: FullSensorData },
+}
+
+/// A script running on its own thread
+struct RunningScript {
+    config: ScriptConfig,
+    inbox: SyncSender<ScriptInput>,
+}
+
+/// Runs the user's scripts, each on its own thread so that they can't stall the system loop
+pub struct ScriptHost {
+    scripts: Vec<RunningScript>,
+    outputs_tx: UnboundedSender<ScriptOutput>,
+    pub(crate) outputs: UnboundedReceiver<ScriptOutput>,
+}
+
+impl ScriptHost {
+    pub fn new() -> Self {
+        let (outputs_tx, outputs) = unbounded_channel();
+
+        Self {
+            scripts: Vec::new(),
+            outputs_tx,
+            outputs,
+        }
+    }
+
+    /// Whether a running script listens to every sensor
+    pub fn listens_to_all(&self) -> bool {
+        self.scripts
+            .iter()
+            .any(|script| script.config.sensors.is_empty())
+    }
+
+    /// Sensors the running scripts listen to
+    pub fn sensors(&self) -> impl Iterator<Item = &UniversalSensorIdentifier> {
+        self.scripts
+            .iter()
+            .flat_map(|script| &script.config.sensors)
+    }
+
+    /// Compiles and starts the given scripts; scripts which fail to load are skipped
+    pub fn start(&mut self, configs: Vec<ScriptConfig>, nodes: &NodeResolver) {
+        for config in configs {
+            let id = config.id.clone();
+            match self.start_script(config, nodes) {
+                Ok(()) => info!("Started script {}", id),
+                Err(err) => error!("Couldn't start script {}: {:#}", id, err),
+            }
+        }
+    }
+
+    fn start_script(&mut self, config: ScriptConfig, nodes: &NodeResolver) -> Result<()> {
+        let source = match &config.source {
+            ScriptSource::File { path } => std::fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read script file {}", path.display()))?,
+            ScriptSource::Inline { source } => source.clone(),
+        };
+
+        let (inbox, inputs) = sync_channel(SCRIPT_QUEUE_SIZE);
+        let mut runner = ScriptRunner::new(&config, nodes.clone(), self.outputs_tx.clone());
+        runner.load(&source)?;
+
+        thread::Builder::new()
+            .name(format!("script-{}", config.id))
+            .spawn(move || runner.run(&inputs))
+            .context("Couldn't spawn script thread")?;
+
+        self.scripts.push(RunningScript { config, inbox });
+        Ok(())
+    }
+
+    /// Passes a measurement on to the scripts subscribed to its sensor, without waiting for them
+    pub fn dispatch(
+        &mut self,
+        node: PeerId,
+        sensor_id: &SensorKey,
+        sensor: &FullSensorData,
+        nodes: &NodeResolver,
+    ) {
+        self.scripts.retain(|script| {
+            let subscribed = script.config.sensors.is_empty()
+                || script.config.sensors.iter().any(|subscription| {
+                    subscription
+                        .resolve(nodes)
+                        .is_ok_and(|key| &key == sensor_id)
+                });
+            if !subscribed {
+                return true;
+            }
+
+            let input = ScriptInput::Sensor {
+                node,
+                data: sensor.clone(),
+            };
+            match script.inbox.try_send(input) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Script {} is falling behind, dropping sensor event",
+                        script.config.id
+                    );
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("Script {} stopped, unloading it", script.config.id);
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// Engine and state of a single script, living on the script's thread
+struct ScriptRunner {
+    id: String,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// Object bound to `this` on every call, where the script keeps its state
+    state: Dynamic,
+    tick_every: Option<Duration>,
+    timeout: Duration,
+    deadline: Arc<Mutex<Instant>>,
+    requested: Arc<Mutex<Vec<Action>>>,
+    nodes: NodeResolver,
+    outputs_tx: UnboundedSender<ScriptOutput>,
+}
+
+impl ScriptRunner {
+    fn new(
+        config: &ScriptConfig,
+        nodes: NodeResolver,
+        outputs_tx: UnboundedSender<ScriptOutput>,
+    ) -> Self {
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let requested = Arc::new(Mutex::new(Vec::new()));
+
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(config.max_operations)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(4096)
+            .set_max_array_size(1024)
+            .set_max_map_size(1024)
+            .disable_symbol("eval");
+
+        {
+            let deadline = deadline.clone();
+            engine.on_progress(move |_| {
+                let deadline = *deadline.lock().expect("deadline lock not poisoned");
+                if Instant::now() > deadline {
+                    Some(Dynamic::from("Script ran out of time"))
+                } else {
+                    None
+                }
+            });
+        }
+
+        let id = config.id.clone();
+        engine.on_print(move |text| info!("[script {}] {}", id, text));
+        let id = config.id.clone();
+        engine.on_debug(move |text, _, pos| debug!("[script {}] {} {}", id, pos, text));
+
+        {
+            let nodes = nodes.clone();
+            engine.register_fn(
+                "sensor",
+                move |device: &str, sensor_name: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+                    read_sensor(&nodes, "local", device, sensor_name)
+                },
+            );
+        }
+        {
+            let nodes = nodes.clone();
+            engine.register_fn(
+                "sensor",
+                move |node: &str,
+                      device: &str,
+                      sensor_name: &str|
+                      -> Result<Dynamic, Box<EvalAltResult>> {
+                    read_sensor(&nodes, node, device, sensor_name)
+                },
+            );
+        }
+        {
+            let nodes = nodes.clone();
+            let requested = requested.clone();
+            engine.register_fn(
+                "actuate",
+                move |action: Map| -> Result<(), Box<EvalAltResult>> {
+                    let action: ScriptAction =
+                        rhai::serde::from_dynamic(&Dynamic::from_map(action))?;
+                    let action = action
+                        .into_action(&nodes)
+                        .map_err(|err| format!("{err:#}"))?;
+                    requested
+                        .lock()
+                        .expect("requested actions lock not poisoned")
+                        .push(action);
+                    Ok(())
+                },
+            );
+        }
+
+        Self {
+            id: config.id.clone(),
+            engine,
+            ast: AST::empty(),
+            scope: Scope::new(),
+            state: Dynamic::from_map(Map::new()),
+            tick_every: config
+                .tick_every
+                .map(|seconds| Duration::from_secs_f64(seconds.max(0.001))),
+            timeout: Duration::from_secs_f64(config.timeout.max(0.0)),
+            deadline,
+            requested,
+            nodes,
+            outputs_tx,
+        }
+    }
+
+    /// Compiles the script, runs its top level and calls its `init` function, if any
+    fn load(&mut self, source: &str) -> Result<()> {
+        self.ast = self
+            .engine
+            .compile(source)
+            .map_err(|err| anyhow!("Syntax error: {}", err))?;
+
+        self.set_deadline();
+        self.engine
+            .run_ast_with_scope(&mut self.scope, &self.ast)
+            .map_err(|err| anyhow!("{}", err))?;
+
+        if self.has_function("init", 0) {
+            self.call("init", ())
+                .map_err(|err| anyhow!("Error in init: {}", err))?;
+        }
+
+        Ok(())
+    }
+
+    fn has_function(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == params)
+    }
+
+    fn set_deadline(&self) {
+        *self.deadline.lock().expect("deadline lock not poisoned") = Instant::now() + self.timeout;
+    }
+
+    fn call(&mut self, name: &str, args: impl rhai::FuncArgs) -> Result<(), Box<EvalAltResult>> {
+        self.set_deadline();
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        self.engine
+            .call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, name, args)
+            .map(|_| ())
+    }
+
+    /// Calls a function of the script, sending on the actuations it requested if it succeeded
+    fn handle(&mut self, name: &str, args: impl rhai::FuncArgs) {
+        let result = self.call(name, args);
+        let actions = std::mem::take(
+            &mut *self
+                .requested
+                .lock()
+                .expect("requested actions lock not poisoned"),
+        );
+
+        match result {
+            Ok(()) if !actions.is_empty() => {
+                let _ = self.outputs_tx.send(ScriptOutput {
+                    script: self.id.clone(),
+                    actions,
+                });
+            }
+            Ok(()) => {}
+            Err(err) => {
+                warn!(
+                    "Script {} failed in {}, discarding {} requested actuations: {}",
+                    self.id,
+                    name,
+                    actions.len(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn sensor_event(&self, node: PeerId, data: FullSensorData) -> Map {
+        let node_name = self.nodes.storage().peer_name(node).unwrap_or_default();
+
+        let mut event = Map::new();
+        event.insert("node".into(), Dynamic::from(node.to_base58()));
+        event.insert("node_name".into(), Dynamic::from(node_name));
+        event.insert("device".into(), Dynamic::from(data.device));
+        event.insert("sensor_name".into(), Dynamic::from(data.sensor_name));
+        event.insert("value".into(), measurement_to_dynamic(data.value));
+        event
+    }
+
+    fn run(mut self, inputs: &Receiver<ScriptInput>) {
+        let on_sensor = self.has_function("on_sensor", 1);
+        let on_tick = self.tick_every.filter(|_| self.has_function("on_tick", 0));
+        let mut next_tick = on_tick.map(|every| Instant::now() + every);
+
+        loop {
+            let input = match next_tick {
+                Some(at) => match inputs.recv_timeout(at.saturating_duration_since(Instant::now()))
+                {
+                    Ok(input) => Some(input),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match inputs.recv() {
+                    Ok(input) => Some(input),
+                    Err(_) => break,
+                },
+            };
+
+            match input {
+                Some(ScriptInput::Sensor { node, data }) => {
+                    if on_sensor {
+                        let event = self.sensor_event(node, data);
+                        self.handle("on_sensor", (event,));
+                    }
+                }
+                None => {
+                    self.handle("on_tick", ());
+                    next_tick = on_tick.map(|every| Instant::now() + every);
+                }
+            }
+        }
+
+        debug!("Script {} stopped", self.id);
+    }
+}
+
+fn read_sensor(
+    nodes: &NodeResolver,
+    node: &str,
+    device: &str,
+    sensor_name: &str,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let node = nodes.resolve(node).map_err(|err| format!("{err:#}"))?;
+    let value = nodes.storage().sensor_data(node, device, sensor_name);
+
+    Ok(value.map_or(Dynamic::UNIT, measurement_to_dynamic))
+}
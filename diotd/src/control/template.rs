@@ -0,0 +1,720 @@
+use std::{
+    convert::TryFrom,
+    fmt::Display,
+    iter::{Enumerate, Peekable},
+    str::Chars,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use diot_core::device::{ActuatorValue, Measurement};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use super::NodeResolver;
+use crate::hardware::FullSensorData;
+
+/// Data available to a template while it's being rendered
+pub struct TemplateContext<'a> {
+    /// Node the triggering measurement came from
+    pub node: PeerId,
+    /// The triggering measurement itself, if there is one (e.g. not for scenes applied by hand)
+    pub trigger: Option<&'a FullSensorData>,
+    /// Where to resolve node references and look up the latest values of other sensors
+    pub nodes: &'a NodeResolver,
+}
+
+impl<'a> TemplateContext<'a> {
+    fn trigger(&self) -> Result<&'a FullSensorData> {
+        self.trigger
+            .ok_or_else(|| anyhow!("There's no triggering measurement to take values from"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActuatorValueKind {
+    Signal,
+    Unsigned,
+    Signed,
+    Double,
+    String,
+}
+
+/// Payload of an action: either a fixed value, or a template rendered from the triggering measurement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ActuatorPayload {
+    Fixed(ActuatorValue),
+    Template {
+        template: Template,
+        #[serde(default)]
+        into: Option<ActuatorValueKind>,
+    },
+}
+
+impl ActuatorPayload {
+    pub fn render(&self, ctx: &TemplateContext) -> Result<ActuatorValue> {
+        match self {
+            Self::Fixed(value) => Ok(value.clone()),
+            Self::Template { template, into } => {
+                let value = template
+                    .evaluate(ctx)
+                    .with_context(|| format!("Failed to evaluate template \"{template}\""))?;
+                into_actuator_value(value, *into)
+            }
+        }
+    }
+}
+
+pub(super) fn into_actuator_value(
+    value: Measurement,
+    into: Option<ActuatorValueKind>,
+) -> Result<ActuatorValue> {
+    use ActuatorValueKind as Kind;
+
+    let into = match (into, &value) {
+        (Some(kind), _) => kind,
+        (None, Measurement::Signal) => Kind::Signal,
+        (None, Measurement::Integer(_)) => Kind::Signed,
+        (None, Measurement::Double(_)) => Kind::Double,
+        (None, Measurement::String(_)) => Kind::String,
+    };
+
+    Ok(match (into, value) {
+        (Kind::Signal, _) => ActuatorValue::Signal,
+        (Kind::String, value) => ActuatorValue::String(display_measurement(&value)),
+        (Kind::Signed, Measurement::Integer(val)) => ActuatorValue::Signed(val),
+        (Kind::Signed, Measurement::Double(val)) => ActuatorValue::Signed(double_to_integer(val)?),
+        (Kind::Unsigned, Measurement::Integer(val)) => ActuatorValue::Unsigned(
+            u64::try_from(val)
+                .map_err(|_| anyhow!("Can't convert {} into an unsigned value", val))?,
+        ),
+        (Kind::Unsigned, Measurement::Double(val)) => {
+            let val = double_to_integer(val)?;
+            ActuatorValue::Unsigned(
+                u64::try_from(val)
+                    .map_err(|_| anyhow!("Can't convert {} into an unsigned value", val))?,
+            )
+        }
+        #[allow(clippy::cast_precision_loss)]
+        (Kind::Double, Measurement::Integer(val)) => ActuatorValue::Double(val as f64),
+        (Kind::Double, Measurement::Double(val)) => ActuatorValue::Double(val),
+        (Kind::Double | Kind::Signed | Kind::Unsigned, Measurement::String(val)) => {
+            let val: f64 = val
+                .trim()
+                .parse()
+                .with_context(|| format!("Can't convert \"{val}\" into a number"))?;
+            into_actuator_value(Measurement::Double(val), Some(into))?
+        }
+        (kind, Measurement::Signal) => bail!("Can't convert a signal into a {:?} value", kind),
+    })
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn double_to_integer(val: f64) -> Result<i64> {
+    let rounded = val.round();
+    if !rounded.is_finite() || rounded < i64::MIN as f64 || rounded > i64::MAX as f64 {
+        bail!("Can't convert {} into an integer value", val);
+    }
+    Ok(rounded as i64)
+}
+
+fn display_measurement(value: &Measurement) -> String {
+    match value {
+        Measurement::Signal => String::from("signal"),
+        Measurement::Integer(val) => val.to_string(),
+        Measurement::Double(val) => val.to_string(),
+        Measurement::String(val) => val.clone(),
+    }
+}
+
+/// Arithmetic expression over the triggering measurement and other sensors' latest values
+///
+/// Supports `+`, `-`, `*`, `/`, `%`, parentheses, numeric and `"string"` literals, the
+/// variables `value`, `node`, `node_name`, `device` and `sensor`, and the functions
+/// `sensor(device, name)`, `sensor(node, device, name)`, `clamp(x, lo, hi)`, `min`, `max`,
+/// `abs`, `round`, `floor` and `ceil`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Template {
+    source: String,
+    expr: Expr,
+}
+
+impl Template {
+    pub fn evaluate(&self, ctx: &TemplateContext) -> Result<Measurement> {
+        self.expr.evaluate(ctx)
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        let mut parser = Parser::new(&source)?;
+        let expr = parser.expr()?;
+        if let Some((column, token)) = parser.next() {
+            bail!("Unexpected {} at column {}", token, column);
+        }
+
+        Ok(Self { source, expr })
+    }
+}
+
+impl From<Template> for String {
+    fn from(template: Template) -> Self {
+        template.source
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    Value,
+    Node,
+    NodeName,
+    Device,
+    Sensor,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Measurement),
+    Variable(Variable),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    fn evaluate(&self, ctx: &TemplateContext) -> Result<Measurement> {
+        match self {
+            Self::Literal(value) => Ok(value.clone()),
+            Self::Variable(var) => Ok(match var {
+                Variable::Value => ctx.trigger()?.value.clone(),
+                Variable::Node => Measurement::String(ctx.node.to_base58()),
+                Variable::NodeName => Measurement::String(
+                    ctx.nodes
+                        .storage()
+                        .peer_name(ctx.node)
+                        .unwrap_or_else(|| ctx.node.to_base58()),
+                ),
+                Variable::Device => Measurement::String(ctx.trigger()?.device.clone()),
+                Variable::Sensor => Measurement::String(ctx.trigger()?.sensor_name.clone()),
+            }),
+            Self::Negate(expr) => match expr.evaluate(ctx)? {
+                Measurement::Integer(val) => Ok(Measurement::Integer(
+                    val.checked_neg()
+                        .ok_or_else(|| anyhow!("Integer overflow"))?,
+                )),
+                Measurement::Double(val) => Ok(Measurement::Double(-val)),
+                other => bail!("Can't negate {:?}", other),
+            },
+            Self::Binary(op, lhs, rhs) => binary_op(*op, lhs.evaluate(ctx)?, rhs.evaluate(ctx)?),
+            Self::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate(ctx))
+                    .collect::<Result<Vec<_>>>()?;
+                call(function, args, ctx)
+            }
+        }
+    }
+}
+
+fn as_double(value: &Measurement) -> Result<f64> {
+    match value {
+        #[allow(clippy::cast_precision_loss)]
+        Measurement::Integer(val) => Ok(*val as f64),
+        Measurement::Double(val) => Ok(*val),
+        other => bail!("Expected a number, found {:?}", other),
+    }
+}
+
+fn as_string(value: Measurement) -> Result<String> {
+    match value {
+        Measurement::String(val) => Ok(val),
+        other => bail!("Expected a string, found {:?}", other),
+    }
+}
+
+fn binary_op(op: BinaryOp, lhs: Measurement, rhs: Measurement) -> Result<Measurement> {
+    use Measurement::{Double, Integer, String};
+
+    Ok(match (op, lhs, rhs) {
+        (BinaryOp::Add, String(lhs), rhs) => String(lhs + display_measurement(&rhs).as_str()),
+        (BinaryOp::Add, lhs, String(rhs)) => String(display_measurement(&lhs) + rhs.as_str()),
+        (BinaryOp::Add, Integer(a), Integer(b)) => Integer(
+            a.checked_add(b)
+                .ok_or_else(|| anyhow!("Integer overflow"))?,
+        ),
+        (BinaryOp::Sub, Integer(a), Integer(b)) => Integer(
+            a.checked_sub(b)
+                .ok_or_else(|| anyhow!("Integer overflow"))?,
+        ),
+        (BinaryOp::Mul, Integer(a), Integer(b)) => Integer(
+            a.checked_mul(b)
+                .ok_or_else(|| anyhow!("Integer overflow"))?,
+        ),
+        (BinaryOp::Rem, Integer(_), Integer(0)) => bail!("Division by zero"),
+        (BinaryOp::Rem, Integer(a), Integer(b)) => Integer(
+            a.checked_rem(b)
+                .ok_or_else(|| anyhow!("Integer overflow"))?,
+        ),
+        (op, lhs, rhs) => {
+            let (a, b) = (as_double(&lhs)?, as_double(&rhs)?);
+            Double(match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Sub => a - b,
+                BinaryOp::Mul => a * b,
+                BinaryOp::Div | BinaryOp::Rem if b == 0.0 => bail!("Division by zero"),
+                BinaryOp::Div => a / b,
+                BinaryOp::Rem => a % b,
+            })
+        }
+    })
+}
+
+fn call(function: &str, args: Vec<Measurement>, ctx: &TemplateContext) -> Result<Measurement> {
+    let mut args = args.into_iter();
+    let mut arity = |n: usize| -> Result<Vec<Measurement>> {
+        let args: Vec<_> = args.by_ref().collect();
+        if args.len() != n {
+            bail!("{}() takes {} arguments, {} given", function, n, args.len());
+        }
+        Ok(args)
+    };
+
+    match function {
+        "sensor" => {
+            let args: Vec<_> = args.by_ref().collect();
+            let (node, device, sensor) = match args.len() {
+                2 => {
+                    let mut args = args.into_iter();
+                    let device = as_string(args.next().expect("two arguments"))?;
+                    let sensor = as_string(args.next().expect("two arguments"))?;
+                    (ctx.node, device, sensor)
+                }
+                3 => {
+                    let mut args = args.into_iter();
+                    let node = as_string(args.next().expect("three arguments"))?;
+                    let node = ctx.nodes.resolve(&node)?;
+                    let device = as_string(args.next().expect("three arguments"))?;
+                    let sensor = as_string(args.next().expect("three arguments"))?;
+                    (node, device, sensor)
+                }
+                n => bail!("sensor() takes 2 or 3 arguments, {} given", n),
+            };
+
+            ctx.nodes
+                .storage()
+                .sensor_data(node, &device, &sensor)
+                .ok_or_else(|| anyhow!("No value known yet for sensor {}/{}", device, sensor))
+        }
+        "clamp" => {
+            let args = arity(3)?;
+            let (lo, hi) = (as_double(&args[1])?, as_double(&args[2])?);
+            if lo > hi {
+                bail!(
+                    "clamp() lower bound {} is greater than upper bound {}",
+                    lo,
+                    hi
+                );
+            }
+            match &args[0] {
+                Measurement::Integer(val) => match (&args[1], &args[2]) {
+                    (Measurement::Integer(lo), Measurement::Integer(hi)) => {
+                        Ok(Measurement::Integer(*val.max(lo).min(hi)))
+                    }
+                    _ => Ok(Measurement::Double(as_double(&args[0])?.max(lo).min(hi))),
+                },
+                other => Ok(Measurement::Double(as_double(other)?.max(lo).min(hi))),
+            }
+        }
+        "min" | "max" => {
+            let mut args = arity(2)?;
+            let (a, b) = (as_double(&args[0])?, as_double(&args[1])?);
+            if (function == "min") == (a <= b) {
+                Ok(args.swap_remove(0))
+            } else {
+                Ok(args.swap_remove(1))
+            }
+        }
+        "abs" => match arity(1)?.remove(0) {
+            Measurement::Integer(val) => Ok(Measurement::Integer(
+                val.checked_abs()
+                    .ok_or_else(|| anyhow!("Integer overflow"))?,
+            )),
+            other => Ok(Measurement::Double(as_double(&other)?.abs())),
+        },
+        "round" | "floor" | "ceil" => {
+            let val = as_double(&arity(1)?[0])?;
+            let val = match function {
+                "round" => val.round(),
+                "floor" => val.floor(),
+                _ => val.ceil(),
+            };
+            Ok(Measurement::Integer(double_to_integer(val)?))
+        }
+        other => bail!("Unknown function {}()", other),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Measurement),
+    Str(String),
+    Ident(String),
+    Symbol(char),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(num) => write!(f, "number {}", display_measurement(num)),
+            Token::Str(s) => write!(f, "string \"{s}\""),
+            Token::Ident(ident) => write!(f, "identifier {ident}"),
+            Token::Symbol(sym) => write!(f, "'{sym}'"),
+        }
+    }
+}
+
+/// Splits a template into tokens, along with the column each of them starts at
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<Enumerate<Chars>> = source.chars().enumerate().peekable();
+
+    while let Some(&(index, c)) = chars.peek() {
+        let column = index + 1;
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '0'..='9' | '.' => {
+                let mut num = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' {
+                        num.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let invalid = || format!("Invalid number {num} at column {column}");
+                let num = if num.contains('.') {
+                    Measurement::Double(num.parse().with_context(invalid)?)
+                } else {
+                    Measurement::Integer(num.parse().with_context(invalid)?)
+                };
+                tokens.push((column, Token::Number(num)));
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next().map(|(_, c)| c) {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => bail!("Unterminated string literal at column {}", column),
+                        },
+                        Some(c) => s.push(c),
+                        None => bail!("Unterminated string literal at column {}", column),
+                    }
+                }
+                tokens.push((column, Token::Str(s)));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push((column, Token::Ident(ident)));
+            }
+            '+' | '-' | '*' | '/' | '%' | '(' | ')' | ',' => {
+                tokens.push((column, Token::Symbol(c)));
+                chars.next();
+            }
+            c => bail!("Unexpected character '{}' at column {}", c, column),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<(usize, Token)>>,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(source)?.into_iter().peekable(),
+        })
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        self.tokens.next()
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.tokens.peek().map(|(_, token)| token) == Some(&Token::Symbol(symbol)) {
+            self.tokens.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        match self.tokens.next() {
+            Some((_, Token::Symbol(c))) if c == symbol => Ok(()),
+            Some((column, token)) => {
+                bail!(
+                    "Expected '{}', found {} at column {}",
+                    symbol,
+                    token,
+                    column
+                )
+            }
+            None => bail!("Expected '{}', found end of template", symbol),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                BinaryOp::Add
+            } else if self.eat('-') {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinaryOp::Mul
+            } else if self.eat('/') {
+                BinaryOp::Div
+            } else if self.eat('%') {
+                BinaryOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat('-') {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.tokens.next() {
+            Some((_, Token::Number(num))) => Ok(Expr::Literal(num)),
+            Some((_, Token::Str(s))) => Ok(Expr::Literal(Measurement::String(s))),
+            Some((_, Token::Symbol('('))) => {
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some((_, Token::Ident(ident))) if self.eat('(') => {
+                let mut args = Vec::new();
+                if !self.eat(')') {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.eat(')') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Ok(Expr::Call(ident, args))
+            }
+            Some((column, Token::Ident(ident))) => Ok(match ident.as_str() {
+                "value" => Expr::Variable(Variable::Value),
+                "node" => Expr::Variable(Variable::Node),
+                "node_name" => Expr::Variable(Variable::NodeName),
+                "device" => Expr::Variable(Variable::Device),
+                "sensor" => Expr::Variable(Variable::Sensor),
+                "signal" => Expr::Literal(Measurement::Signal),
+                other => bail!("Unknown variable {} at column {}", other, column),
+            }),
+            Some((column, token)) => bail!("Unexpected {} at column {}", token, column),
+            None => bail!("Unexpected end of template"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::*;
+    use crate::{
+        store::{MemoryBackend, Storage},
+        system::LocalPeerData,
+    };
+
+    fn nodes() -> NodeResolver {
+        let local_peer_data = LocalPeerData {
+            name: String::from("test"),
+            devices: HashMap::new(),
+        };
+        let storage = Storage::new(
+            PeerId::random(),
+            local_peer_data,
+            Arc::new(MemoryBackend::new()),
+        )
+        .unwrap();
+        NodeResolver::new(Arc::new(storage), HashMap::new())
+    }
+
+    fn evaluate(source: &str, value: Measurement) -> Result<Measurement> {
+        let nodes = nodes();
+        let trigger = FullSensorData {
+            device: String::from("dht11-1"),
+            sensor_name: String::from("temperature"),
+            value,
+        };
+        let ctx = TemplateContext {
+            node: nodes.local_peer_id(),
+            trigger: Some(&trigger),
+            nodes: &nodes,
+        };
+        Template::try_from(source.to_string())?.evaluate(&ctx)
+    }
+
+    fn integer(source: &str) -> Result<Measurement> {
+        evaluate(source, Measurement::Integer(0))
+    }
+
+    fn error(result: Result<impl std::fmt::Debug>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(integer("1 + 2 * 3").unwrap(), Measurement::Integer(7));
+        assert_eq!(integer("(1 + 2) * 3").unwrap(), Measurement::Integer(9));
+        assert_eq!(integer("10 - 4 - 3").unwrap(), Measurement::Integer(3));
+        assert_eq!(integer("7 % 4 * 2").unwrap(), Measurement::Integer(6));
+        assert_eq!(integer("7 / 2").unwrap(), Measurement::Double(3.5));
+    }
+
+    #[test]
+    fn unary_minus_binds_tighter_than_binary_operators() {
+        assert_eq!(integer("-2 * 3").unwrap(), Measurement::Integer(-6));
+        assert_eq!(integer("4 - -2").unwrap(), Measurement::Integer(6));
+        assert_eq!(integer("--3").unwrap(), Measurement::Integer(3));
+        assert_eq!(
+            evaluate("-value", Measurement::Double(1.5)).unwrap(),
+            Measurement::Double(-1.5)
+        );
+    }
+
+    #[test]
+    fn strings_concatenate_with_anything() {
+        assert_eq!(
+            evaluate(
+                "\"it's \" + value + \" in \" + device",
+                Measurement::Integer(21)
+            )
+            .unwrap(),
+            Measurement::String(String::from("it's 21 in dht11-1"))
+        );
+        assert_eq!(
+            integer("1 + \"2\"").unwrap(),
+            Measurement::String(String::from("12"))
+        );
+    }
+
+    #[test]
+    fn functions_check_their_arity() {
+        assert_eq!(
+            integer("clamp(15, 0, 10)").unwrap(),
+            Measurement::Integer(10)
+        );
+        assert_eq!(integer("max(2, 3.5)").unwrap(), Measurement::Double(3.5));
+        assert_eq!(
+            error(integer("clamp(1, 2)")),
+            "clamp() takes 3 arguments, 2 given"
+        );
+        assert_eq!(error(integer("abs()")), "abs() takes 1 arguments, 0 given");
+        assert_eq!(
+            error(integer("sensor(\"dht11-1\")")),
+            "sensor() takes 2 or 3 arguments, 1 given"
+        );
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        assert_eq!(
+            error(integer("9223372036854775807 + 1")),
+            "Integer overflow"
+        );
+        assert_eq!(
+            error(evaluate("-value", Measurement::Integer(i64::MIN))),
+            "Integer overflow"
+        );
+        assert_eq!(
+            error(evaluate("abs(value)", Measurement::Integer(i64::MIN))),
+            "Integer overflow"
+        );
+        assert_eq!(
+            error(evaluate("value % -1", Measurement::Integer(i64::MIN))),
+            "Integer overflow"
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(error(integer("1 / 0")), "Division by zero");
+        assert_eq!(error(integer("1 % 0")), "Division by zero");
+        assert_eq!(error(integer("1.5 % 0.0")), "Division by zero");
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_offending_column() {
+        let parse = |source: &str| error(Template::try_from(source.to_string()));
+
+        assert_eq!(parse("1 + $"), "Unexpected character '$' at column 5");
+        assert_eq!(parse("1 + 2)"), "Unexpected ')' at column 6");
+        assert_eq!(parse("(1 + 2"), "Expected ')', found end of template");
+        assert_eq!(
+            parse("max(1 2)"),
+            "Expected ',', found number 2 at column 7"
+        );
+        assert_eq!(parse("2 * foo"), "Unknown variable foo at column 5");
+        assert_eq!(parse("\"abc"), "Unterminated string literal at column 1");
+    }
+}
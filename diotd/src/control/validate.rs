@@ -0,0 +1,572 @@
+use std::{collections::HashMap, ops::Bound};
+
+use diot_core::device::{HardwareDeviceType, Measurement, MeasurementKind};
+use libp2p::PeerId;
+use serde::Serialize;
+
+use crate::liveness::{LIVENESS_DEVICE, ONLINE_SENSOR};
+
+use super::{
+    modes::Modes, plan::PlanStep, template::ActuatorPayload, window::Aggregate, ActionTemplate,
+    ConditionOp, NodeResolver, Rule, RuleAction, RuleId, Scene, SensorKey,
+    UniversalSensorIdentifier,
+};
+
+/// Something wrong with a rule, found before it ever fires
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RuleIssue {
+    /// Rule with the issue, or `scene:<name>` for scenes
+    pub rule: RuleId,
+    pub problem: String,
+}
+
+/// Checks rules against the devices known for each node
+///
+/// Nodes whose identity isn't known yet are skipped; rules are meant to be checked again as
+/// identities arrive.
+pub struct Validator<'a> {
+    nodes: &'a NodeResolver,
+    scenes: &'a HashMap<String, Scene>,
+    modes: &'a Modes,
+    issues: Vec<RuleIssue>,
+}
+
+impl<'a> Validator<'a> {
+    pub fn validate(
+        rules: &[Rule],
+        nodes: &'a NodeResolver,
+        scenes: &'a HashMap<String, Scene>,
+        modes: &'a Modes,
+    ) -> Vec<RuleIssue> {
+        let mut validator = Self {
+            nodes,
+            scenes,
+            modes,
+            issues: Vec::new(),
+        };
+
+        for rule in rules.iter().filter(|rule| rule.enabled) {
+            validator.check_rule(rule);
+        }
+        let mut scenes: Vec<_> = scenes.iter().collect();
+        scenes.sort_by_key(|(name, _)| name.as_str());
+        for (name, scene) in scenes {
+            let id = format!("scene:{name}");
+            for action in scene {
+                validator.check_action(&id, action);
+            }
+        }
+        validator.check_conflicts(rules);
+
+        validator.issues
+    }
+
+    fn flag(&mut self, rule: &str, problem: String) {
+        self.issues.push(RuleIssue {
+            rule: rule.to_string(),
+            problem,
+        });
+    }
+
+    /// Device types on the given node, or `None` if they aren't known (yet)
+    fn devices_of(&self, node: Option<PeerId>) -> Option<HashMap<String, HardwareDeviceType>> {
+        self.nodes
+            .storage()
+            .peer_devices(node.unwrap_or_else(|| self.nodes.local_peer_id()))
+    }
+
+    fn node_label(&self, node: Option<PeerId>) -> String {
+        node.map_or_else(
+            || String::from("this node"),
+            |peer_id| {
+                let name = self.nodes.storage().peer_name(peer_id);
+                format!("node {}", name.unwrap_or_else(|| peer_id.to_base58()))
+            },
+        )
+    }
+
+    fn check_rule(&mut self, rule: &Rule) {
+        for mode in &rule.modes {
+            if !self.modes.is_known(mode) {
+                self.flag(&rule.id, format!("Restricted to unknown mode \"{mode}\""));
+            }
+        }
+
+        self.check_condition(&rule.id, &rule.sensor, rule.aggregate.as_ref(), &rule.on);
+
+        match &rule.then {
+            RuleAction::Single(action) => self.check_action(&rule.id, action),
+            RuleAction::Plan(plan) => {
+                for cond in &plan.abort_on {
+                    self.check_condition(&rule.id, &cond.sensor, None, &cond.on);
+                }
+                for step in &plan.steps {
+                    self.check_step(&rule.id, step);
+                }
+            }
+            RuleAction::SetMode { set_mode } => {
+                if !self.modes.is_known(set_mode) {
+                    self.flag(&rule.id, format!("Switches to unknown mode \"{set_mode}\""));
+                }
+            }
+            RuleAction::ApplyScene { scene } => {
+                if !self.scenes.contains_key(scene) {
+                    self.flag(&rule.id, format!("Applies unknown scene \"{scene}\""));
+                }
+            }
+        }
+    }
+
+    fn check_step(&mut self, rule: &str, step: &PlanStep<ActionTemplate>) {
+        match step {
+            PlanStep::Actuate(action) => self.check_action(rule, action),
+            PlanStep::Wait { .. } => {}
+            PlanStep::Sequence(steps) | PlanStep::Parallel(steps) => {
+                for step in steps {
+                    self.check_step(rule, step);
+                }
+            }
+        }
+    }
+
+    fn check_condition(
+        &mut self,
+        rule: &str,
+        sensor: &UniversalSensorIdentifier,
+        aggregate: Option<&Aggregate>,
+        on: &ConditionOp,
+    ) {
+        let value = condition_value(on);
+        if let Some(value) = value {
+            if !matches!(on, ConditionOp::Equal { .. }) && !is_numeric(value.kind()) {
+                self.flag(
+                    rule,
+                    format!(
+                        "Condition can never match, as {:?} values can't be ordered",
+                        value.kind()
+                    ),
+                );
+                return;
+            }
+        }
+
+        // Unresolved nodes are reported when building the triggers
+        let Ok(key) = sensor.resolve(self.nodes) else {
+            return;
+        };
+        // Every node has the liveness pseudo-device, whether it announced it or not
+        if key.device == LIVENESS_DEVICE {
+            if key.sensor_name != ONLINE_SENSOR {
+                let problem = format!(
+                    "Device \"{}\" has no sensor \"{}\"",
+                    key.device, key.sensor_name
+                );
+                self.flag(rule, problem);
+            }
+            return;
+        }
+        let Some(devices) = self.devices_of(key.node) else {
+            return;
+        };
+        let Some(device_type) = devices.get(&key.device) else {
+            let problem = format!(
+                "Listens to unknown device \"{}\" on {}",
+                key.device,
+                self.node_label(key.node)
+            );
+            self.flag(rule, problem);
+            return;
+        };
+        let capabilities = device_type.capabilities();
+        let Some((_, kind)) = capabilities
+            .sensors
+            .iter()
+            .find(|(name, _)| *name == key.sensor_name)
+        else {
+            let problem = format!(
+                "Device \"{}\" ({}) on {} has no sensor \"{}\"",
+                key.device,
+                device_type,
+                self.node_label(key.node),
+                key.sensor_name
+            );
+            self.flag(rule, problem);
+            return;
+        };
+
+        let kind = if aggregate.is_some() {
+            if !is_numeric(*kind) {
+                self.flag(
+                    rule,
+                    format!(
+                        "Aggregates sensor \"{}\" of device \"{}\", which measures {:?} values",
+                        key.sensor_name, key.device, kind
+                    ),
+                );
+                return;
+            }
+            MeasurementKind::Double
+        } else {
+            *kind
+        };
+
+        if let Some(value) = value {
+            if value.kind() != kind {
+                self.flag(
+                    rule,
+                    format!(
+                        "Condition compares {:?} measurements of sensor \"{}\" of device \"{}\" with a {:?} value",
+                        kind,
+                        key.sensor_name,
+                        key.device,
+                        value.kind()
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_action(&mut self, rule: &str, action: &ActionTemplate) {
+        if let Some(fallback) = &action.fallback {
+            self.check_action(rule, fallback);
+        }
+
+        // Nodes which can't be resolved yet will be checked once they can
+        let Ok(node) = self.nodes.resolve_opt(action.node.as_ref()) else {
+            return;
+        };
+        let Some(devices) = self.devices_of(node) else {
+            return;
+        };
+        let Some(device_type) = devices.get(&action.device) else {
+            let problem = format!(
+                "Actuates unknown device \"{}\" on {}",
+                action.device,
+                self.node_label(node)
+            );
+            self.flag(rule, problem);
+            return;
+        };
+
+        let capabilities = device_type.capabilities();
+        if let Some(actuators) = capabilities.actuators {
+            if !actuators.iter().any(|name| *name == action.actuator_name) {
+                let problem = format!(
+                    "Device \"{}\" ({}) on {} has no actuator \"{}\"",
+                    action.device,
+                    device_type,
+                    self.node_label(node),
+                    action.actuator_name
+                );
+                self.flag(rule, problem);
+                return;
+            }
+        }
+        if let ActuatorPayload::Fixed(value) = &action.data {
+            if !(capabilities.accepts)(value) {
+                let problem = format!(
+                    "Actuator \"{}\" of device \"{}\" ({}) doesn't accept {:?}",
+                    action.actuator_name, action.device, device_type, value
+                );
+                self.flag(rule, problem);
+            }
+        }
+    }
+
+    /// Flags rules which set the same actuator to different values on the same measurement
+    fn check_conflicts(&mut self, rules: &[Rule]) {
+        let mut targets = Vec::new();
+        for rule in rules.iter().filter(|rule| rule.enabled) {
+            let RuleAction::Single(action) = &rule.then else {
+                continue;
+            };
+            let ActuatorPayload::Fixed(value) = &action.data else {
+                continue;
+            };
+            let (Ok(sensor), Ok(node)) = (
+                rule.sensor.resolve(self.nodes),
+                self.nodes.resolve_opt(action.node.as_ref()),
+            ) else {
+                continue;
+            };
+            targets.push((rule, sensor, node, action, value));
+        }
+
+        for (i, (rule, sensor, node, action, value)) in targets.iter().enumerate() {
+            for (other, other_sensor, other_node, other_action, other_value) in &targets[..i] {
+                let conflicting = sensor == other_sensor
+                    && node == other_node
+                    && action.device == other_action.device
+                    && action.actuator_name == other_action.actuator_name
+                    && value != other_value
+                    && rule.aggregate == other.aggregate
+                    && modes_overlap(&rule.modes, &other.modes)
+                    && may_both_match(&rule.on, &other.on);
+                if conflicting {
+                    let problem = format!(
+                        "Conflicts with rule {}: both can fire on the same measurement of {}, setting actuator \"{}\" of device \"{}\" on {} to {:?} and {:?}",
+                        other.id,
+                        describe_sensor(sensor),
+                        action.actuator_name,
+                        action.device,
+                        self.node_label(*node),
+                        value,
+                        other_value
+                    );
+                    self.flag(&rule.id, problem);
+                }
+            }
+        }
+    }
+}
+
+fn describe_sensor(sensor: &SensorKey) -> String {
+    format!(
+        "sensor \"{}\" of device \"{}\"",
+        sensor.sensor_name, sensor.device
+    )
+}
+
+fn is_numeric(kind: MeasurementKind) -> bool {
+    matches!(kind, MeasurementKind::Integer | MeasurementKind::Double)
+}
+
+fn condition_value(on: &ConditionOp) -> Option<&Measurement> {
+    match on {
+        ConditionOp::Any => None,
+        ConditionOp::Equal { value }
+        | ConditionOp::GreaterThan { value }
+        | ConditionOp::LessThan { value }
+        | ConditionOp::GreaterOrEqualThan { value }
+        | ConditionOp::LessOrEqualThan { value } => Some(value),
+    }
+}
+
+fn modes_overlap(a: &[String], b: &[String]) -> bool {
+    a.is_empty() || b.is_empty() || a.iter().any(|mode| b.contains(mode))
+}
+
+/// Range of numeric values a condition matches, if it compares against a number
+#[allow(clippy::cast_precision_loss)]
+fn numeric_range(on: &ConditionOp) -> Option<(Bound<f64>, Bound<f64>)> {
+    let value = match condition_value(on)? {
+        Measurement::Integer(value) => *value as f64,
+        Measurement::Double(value) => *value,
+        _ => return None,
+    };
+
+    Some(match on {
+        ConditionOp::Any => return None,
+        ConditionOp::Equal { .. } => (Bound::Included(value), Bound::Included(value)),
+        ConditionOp::GreaterThan { .. } => (Bound::Excluded(value), Bound::Unbounded),
+        ConditionOp::LessThan { .. } => (Bound::Unbounded, Bound::Excluded(value)),
+        ConditionOp::GreaterOrEqualThan { .. } => (Bound::Included(value), Bound::Unbounded),
+        ConditionOp::LessOrEqualThan { .. } => (Bound::Unbounded, Bound::Included(value)),
+    })
+}
+
+fn bound_value(bound: Bound<f64>) -> Option<f64> {
+    match bound {
+        Bound::Included(value) | Bound::Excluded(value) => Some(value),
+        Bound::Unbounded => None,
+    }
+}
+
+/// Picks the most restrictive of two lower bounds, or of two upper bounds
+#[allow(clippy::float_cmp)]
+fn tighter(a: Bound<f64>, b: Bound<f64>, lower: bool) -> Bound<f64> {
+    match (bound_value(a), bound_value(b)) {
+        (None, _) => b,
+        (_, None) => a,
+        (Some(x), Some(y)) if x == y => {
+            if matches!(a, Bound::Excluded(_)) {
+                a
+            } else {
+                b
+            }
+        }
+        (Some(x), Some(y)) => {
+            if (x > y) == lower {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// Whether some measurement could match both conditions
+fn may_both_match(a: &ConditionOp, b: &ConditionOp) -> bool {
+    let (Some(value_a), Some(value_b)) = (condition_value(a), condition_value(b)) else {
+        return true;
+    };
+    // Measurements are only ever compared with values of their own kind
+    if value_a.kind() != value_b.kind() {
+        return false;
+    }
+    if let (ConditionOp::Equal { value: a }, ConditionOp::Equal { value: b }) = (a, b) {
+        return a == b;
+    }
+
+    let (Some((low_a, high_a)), Some((low_b, high_b))) = (numeric_range(a), numeric_range(b))
+    else {
+        return false;
+    };
+    match (tighter(low_a, low_b, true), tighter(high_a, high_b, false)) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+        (Bound::Included(low), Bound::Included(high)) => low <= high,
+        (
+            Bound::Included(low) | Bound::Excluded(low),
+            Bound::Included(high) | Bound::Excluded(high),
+        ) => low < high,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        control::ModesConfig,
+        store::{LocalPeerDevice, MemoryBackend, Storage},
+        system::LocalPeerData,
+    };
+
+    fn nodes() -> NodeResolver {
+        let device = |device_type| LocalPeerDevice {
+            device_type,
+            config: json!({}),
+        };
+        let local_peer_data = LocalPeerData {
+            name: String::from("test"),
+            devices: vec![
+                (String::from("logger-1"), device(HardwareDeviceType::Logger)),
+                (String::from("timer-1"), device(HardwareDeviceType::Timer)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let storage = Storage::new(
+            PeerId::random(),
+            local_peer_data,
+            Arc::new(MemoryBackend::new()),
+        )
+        .unwrap();
+        NodeResolver::new(Arc::new(storage), HashMap::new())
+    }
+
+    /// Rule setting the logger to `data` when the temperature of a node matches `on`
+    fn rule(id: &str, node: &str, on: &serde_json::Value, data: i64, modes: &[&str]) -> Rule {
+        serde_json::from_value(json!({
+            "id": id,
+            "modes": modes,
+            "sensor": { "node": node, "device": "dht11-1", "sensor_name": "temperature" },
+            "on": on,
+            "then": { "device": "logger-1", "actuator_name": "log", "data": { "signed": data } },
+        }))
+        .unwrap()
+    }
+
+    fn above(value: f64) -> serde_json::Value {
+        json!({ "operation": "greater_than", "value": { "double": value } })
+    }
+
+    fn at_most(value: f64) -> serde_json::Value {
+        json!({ "operation": "less_or_equal_than", "value": { "double": value } })
+    }
+
+    fn at_least(value: f64) -> serde_json::Value {
+        json!({ "operation": "greater_or_equal_than", "value": { "double": value } })
+    }
+
+    fn validate(rules: &[Rule]) -> Vec<RuleIssue> {
+        let nodes = nodes();
+        let modes = Modes::new(ModesConfig::default());
+        Validator::validate(rules, &nodes, &HashMap::new(), &modes)
+    }
+
+    #[test]
+    fn overlapping_conditions_setting_different_values_conflict() {
+        let node = PeerId::random().to_base58();
+        let issues = validate(&[
+            rule("heat", &node, &above(20.0), 1, &[]),
+            rule("cool", &node, &at_most(25.0), 0, &[]),
+        ]);
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].rule, "cool");
+        assert!(issues[0].problem.starts_with("Conflicts with rule heat"));
+    }
+
+    #[test]
+    fn rules_that_cant_fire_together_dont_conflict() {
+        let node = PeerId::random().to_base58();
+
+        // Disjoint ranges, touching at a bound only one of them includes
+        assert!(validate(&[
+            rule("heat", &node, &above(20.0), 1, &[]),
+            rule("cool", &node, &at_most(20.0), 0, &[]),
+        ])
+        .is_empty());
+        // Same value
+        assert!(validate(&[
+            rule("heat", &node, &above(20.0), 1, &[]),
+            rule("heat-more", &node, &above(25.0), 1, &[]),
+        ])
+        .is_empty());
+        // Different modes
+        assert!(validate(&[
+            rule("heat", &node, &above(20.0), 1, &["home"]),
+            rule("cool", &node, &at_most(25.0), 0, &["away"]),
+        ])
+        .is_empty());
+        // Different sensors
+        assert!(validate(&[
+            rule("heat", &node, &above(20.0), 1, &[]),
+            rule(
+                "cool",
+                &PeerId::random().to_base58(),
+                &at_most(25.0),
+                0,
+                &[]
+            ),
+        ])
+        .is_empty());
+    }
+
+    #[test]
+    fn ranges_sharing_an_included_bound_conflict() {
+        let node = PeerId::random().to_base58();
+        let issues = validate(&[
+            rule("heat", &node, &at_least(20.0), 1, &[]),
+            rule("cool", &node, &at_most(20.0), 0, &["home"]),
+        ]);
+        assert_eq!(issues.len(), 1);
+    }
+
+    #[test]
+    fn actions_on_unknown_devices_and_actuators_are_flagged() {
+        let node = PeerId::random().to_base58();
+        let mut unknown_device = rule("unknown-device", &node, &above(20.0), 1, &[]);
+        if let RuleAction::Single(action) = &mut unknown_device.then {
+            action.device = String::from("relay-1");
+        }
+        let mut unknown_actuator = rule("unknown-actuator", &node, &above(20.0), 1, &[]);
+        if let RuleAction::Single(action) = &mut unknown_actuator.then {
+            action.device = String::from("timer-1");
+        }
+
+        let issues = validate(&[unknown_device, unknown_actuator]);
+        let problems: Vec<_> = issues.iter().map(|issue| issue.problem.as_str()).collect();
+        assert_eq!(
+            problems,
+            [
+                "Actuates unknown device \"relay-1\" on this node",
+                "Device \"timer-1\" (timer) on this node has no actuator \"log\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use super::SensorKey;
+
+/// How much history of a sensor an aggregation looks at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowSize {
+    /// Measurements taken within the last given seconds
+    Seconds(f64),
+    /// The last given number of measurements
+    Samples(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateFunction {
+    Mean,
+    Median,
+    Min,
+    Max,
+    Stddev,
+    /// Change between the first and last measurement, per minute
+    RatePerMinute,
+}
+
+/// Aggregation of the recent measurements of a sensor, to evaluate a condition on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub function: AggregateFunction,
+    pub over: WindowSize,
+}
+
+impl Aggregate {
+    /// Computes the aggregate over a window, or `None` if there isn't enough data yet
+    fn compute(&self, window: &VecDeque<(SystemTime, f64)>, now: SystemTime) -> Option<f64> {
+        let samples: Vec<(SystemTime, f64)> = match self.over {
+            WindowSize::Seconds(seconds) => {
+                let since = now.checked_sub(Duration::from_secs_f64(seconds.max(0.0)))?;
+                window
+                    .iter()
+                    .filter(|(at, _)| *at >= since)
+                    .copied()
+                    .collect()
+            }
+            WindowSize::Samples(count) => {
+                if window.len() < count {
+                    return None;
+                }
+                window.iter().skip(window.len() - count).copied().collect()
+            }
+        };
+        if samples.is_empty() {
+            return None;
+        }
+
+        let values: Vec<f64> = samples.iter().map(|(_, value)| *value).collect();
+        #[allow(clippy::cast_precision_loss)]
+        let len = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / len;
+
+        Some(match self.function {
+            AggregateFunction::Mean => mean,
+            AggregateFunction::Median => {
+                let mut sorted = values;
+                sorted.sort_by(f64::total_cmp);
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 0 {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                }
+            }
+            AggregateFunction::Min => values.into_iter().fold(f64::INFINITY, f64::min),
+            AggregateFunction::Max => values.into_iter().fold(f64::NEG_INFINITY, f64::max),
+            AggregateFunction::Stddev => {
+                let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / len;
+                variance.sqrt()
+            }
+            AggregateFunction::RatePerMinute => {
+                let (first_at, first) = samples.first()?;
+                let (last_at, last) = samples.last()?;
+                let minutes = last_at.duration_since(*first_at).ok()?.as_secs_f64() / 60.0;
+                if minutes <= 0.0 {
+                    return None;
+                }
+                (last - first) / minutes
+            }
+        })
+    }
+}
+
+/// How much history to keep for a sensor to satisfy every aggregation on it
+#[derive(Debug, Clone, Copy, Default)]
+struct Retention {
+    duration: Duration,
+    samples: usize,
+}
+
+/// Rolling windows of recent numeric measurements, for sensors with aggregations on them
+#[derive(Default)]
+pub struct SensorWindows {
+    retention: HashMap<SensorKey, Retention>,
+    windows: HashMap<SensorKey, VecDeque<(SystemTime, f64)>>,
+}
+
+impl SensorWindows {
+    /// Sets which sensors need windows, and for which aggregations
+    pub fn set_aggregates<'a>(
+        &mut self,
+        aggregates: impl Iterator<Item = (SensorKey, &'a Aggregate)>,
+    ) {
+        let mut retention: HashMap<SensorKey, Retention> = HashMap::new();
+        for (key, aggregate) in aggregates {
+            let entry = retention.entry(key).or_default();
+            match aggregate.over {
+                WindowSize::Seconds(seconds) => {
+                    entry.duration = entry
+                        .duration
+                        .max(Duration::from_secs_f64(seconds.max(0.0)));
+                }
+                WindowSize::Samples(count) => entry.samples = entry.samples.max(count),
+            }
+        }
+
+        self.windows.retain(|key, _| retention.contains_key(key));
+        self.retention = retention;
+    }
+
+    /// Drops the windows of the sensors of a device which went away
+    pub fn forget_device(&mut self, node: Option<PeerId>, device: &str) {
+        self.windows
+            .retain(|key, _| key.node != node || key.device != device);
+    }
+
+    /// Adds a measurement to the window of its sensor, if it has one
+    pub fn record(&mut self, key: &SensorKey, at: SystemTime, value: &Measurement) {
+        let retention = match self.retention.get(key) {
+            Some(retention) => *retention,
+            None => return,
+        };
+        #[allow(clippy::cast_precision_loss)]
+        let value = match value {
+            Measurement::Integer(value) => *value as f64,
+            Measurement::Double(value) => *value,
+            _ => return,
+        };
+
+        let window = self.windows.entry(key.clone()).or_default();
+        window.push_back((at, value));
+
+        let oldest = at.checked_sub(retention.duration);
+        while window.len() > retention.samples.max(1)
+            && window
+                .front()
+                .zip(oldest)
+                .is_some_and(|((front, _), oldest)| *front < oldest)
+        {
+            window.pop_front();
+        }
+    }
+
+    /// Computes an aggregate over the window of a sensor
+    pub fn aggregate(
+        &self,
+        key: &SensorKey,
+        aggregate: &Aggregate,
+        now: SystemTime,
+    ) -> Option<f64> {
+        aggregate.compute(self.windows.get(key)?, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(device: &str) -> SensorKey {
+        SensorKey {
+            node: None,
+            device: device.to_string(),
+            sensor_name: String::from("temperature"),
+        }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    /// Windows tracking `aggregates` on a single sensor, fed a measurement every 10 seconds
+    fn windows(aggregates: &[Aggregate], values: &[i64]) -> SensorWindows {
+        let mut windows = SensorWindows::default();
+        windows.set_aggregates(
+            aggregates
+                .iter()
+                .map(|aggregate| (key("dht11-1"), aggregate)),
+        );
+        for (i, value) in (0..).zip(values) {
+            windows.record(&key("dht11-1"), at(i * 10), &Measurement::Integer(*value));
+        }
+        windows
+    }
+
+    fn aggregate(function: AggregateFunction, over: WindowSize) -> Aggregate {
+        Aggregate { function, over }
+    }
+
+    #[test]
+    fn sample_windows_compute_every_function() {
+        let over = WindowSize::Samples(4);
+        let compute = |function| {
+            let aggregate = aggregate(function, over);
+            windows(std::slice::from_ref(&aggregate), &[100, 1, 4, 2, 5])
+                .aggregate(&key("dht11-1"), &aggregate, at(40))
+                .unwrap()
+        };
+
+        assert!((compute(AggregateFunction::Mean) - 3.0).abs() < 1e-9);
+        assert!((compute(AggregateFunction::Median) - 3.0).abs() < 1e-9);
+        assert!((compute(AggregateFunction::Min) - 1.0).abs() < 1e-9);
+        assert!((compute(AggregateFunction::Max) - 5.0).abs() < 1e-9);
+        assert!((compute(AggregateFunction::Stddev) - 2.5_f64.sqrt()).abs() < 1e-9);
+        // From 1 to 5 over 30 seconds
+        assert!((compute(AggregateFunction::RatePerMinute) - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sample_windows_wait_until_full() {
+        let aggregate = aggregate(AggregateFunction::Mean, WindowSize::Samples(3));
+        let windows = windows(std::slice::from_ref(&aggregate), &[1, 2]);
+        assert_eq!(windows.aggregate(&key("dht11-1"), &aggregate, at(10)), None);
+    }
+
+    #[test]
+    fn time_windows_only_look_at_recent_measurements() {
+        let aggregate = aggregate(AggregateFunction::Max, WindowSize::Seconds(15.0));
+        let windows = windows(std::slice::from_ref(&aggregate), &[9, 1, 2]);
+
+        assert_eq!(
+            windows.aggregate(&key("dht11-1"), &aggregate, at(20)),
+            Some(2.0)
+        );
+        assert_eq!(windows.aggregate(&key("dht11-1"), &aggregate, at(60)), None);
+    }
+
+    #[test]
+    fn windows_keep_enough_for_the_largest_aggregate() {
+        let small = aggregate(AggregateFunction::Mean, WindowSize::Samples(2));
+        let large = aggregate(AggregateFunction::Mean, WindowSize::Samples(4));
+        let windows = windows(&[small.clone(), large.clone()], &[1, 2, 3, 4, 5]);
+
+        assert_eq!(
+            windows.aggregate(&key("dht11-1"), &small, at(40)),
+            Some(4.5)
+        );
+        assert_eq!(
+            windows.aggregate(&key("dht11-1"), &large, at(40)),
+            Some(3.5)
+        );
+        assert_eq!(windows.windows[&key("dht11-1")].len(), 4);
+    }
+
+    #[test]
+    fn only_numeric_measurements_of_tracked_sensors_are_recorded() {
+        let aggregate = aggregate(AggregateFunction::Mean, WindowSize::Samples(1));
+        let mut windows = windows(std::slice::from_ref(&aggregate), &[]);
+
+        windows.record(&key("dht11-2"), at(0), &Measurement::Integer(1));
+        windows.record(&key("dht11-1"), at(0), &Measurement::Signal);
+        assert!(windows.windows.is_empty());
+
+        windows.record(&key("dht11-1"), at(0), &Measurement::Double(1.5));
+        assert_eq!(
+            windows.aggregate(&key("dht11-1"), &aggregate, at(0)),
+            Some(1.5)
+        );
+        windows.forget_device(None, "dht11-1");
+        assert_eq!(windows.aggregate(&key("dht11-1"), &aggregate, at(0)), None);
+    }
+}
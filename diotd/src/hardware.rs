@@ -128,10 +128,12 @@ impl HardwareSupervisor {
         Some(())
     }
 
+    /// Carries out an actuation requested by another node, calling `on_result` with its outcome
     pub fn actuate_device_remote(
         &self,
         actuation_data: FullActuatorData,
         chan: ResponseChannel<RemoteActuationResponse>,
+        on_result: impl FnOnce(&ActuationResult) + Send + 'static,
     ) -> Option<()> {
         let device = self.device(&actuation_data.device)?;
 
@@ -147,6 +149,7 @@ impl HardwareSupervisor {
                     error_description: err.to_string(),
                 },
             };
+            on_result(&result);
 
             if chan.send_response(result.into()).is_err() {
                 error!(
@@ -0,0 +1,190 @@
+use std::{
+    convert::TryFrom,
+    sync::Arc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use futures::FutureExt;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    hardware::FullSensorData,
+    store::{SeriesId, StorageBackend},
+};
+
+mod catch_up;
+mod query;
+mod retention;
+mod transfer;
+
+pub use catch_up::{CatchUpPage, CaughtUpSample};
+pub use query::{HistoryPage, HistoryPoints, HistoryQuery};
+pub use retention::{RetentionPolicy, Rollup};
+pub use transfer::{export_history, import_history, ExportFilter, TransferFormat};
+
+use retention::Compactor;
+
+/// How many samples the writer thread may put into a single batch
+const MAX_BATCH_SIZE: usize = 512;
+
+fn default_compact_every() -> f64 {
+    600.0
+}
+
+fn default_catch_up_window() -> f64 {
+    86400.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// How long to keep the history of each sensor for, the first matching policy applying
+    /// (everything is kept forever if none does)
+    #[serde(default)]
+    pub retention: Vec<RetentionPolicy>,
+    /// Seconds between applications of the retention policies
+    #[serde(default = "default_compact_every")]
+    pub compact_every: f64,
+    /// Seconds of measurements to ask a node for at most when it comes online
+    #[serde(default = "default_catch_up_window")]
+    pub catch_up_window: f64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            retention: Vec::new(),
+            compact_every: default_compact_every(),
+            catch_up_window: default_catch_up_window(),
+        }
+    }
+}
+
+/// A measurement of a sensor of some node at some point in time
+#[derive(Debug, Clone)]
+pub struct HistorySample {
+    /// Unix timestamp of the measurement, in milliseconds
+    pub at: u64,
+    pub node: PeerId,
+    pub data: FullSensorData,
+}
+
+/// Current Unix timestamp in milliseconds, as measurements and events are timestamped with
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+/// Time series of every measurement seen by this node, persisted across restarts
+///
+/// Writes happen on a thread of their own, so that recording a measurement never blocks. Samples
+/// of the same sensor taken on the same millisecond overwrite each other.
+///
+/// On an unclean shutdown, samples still queued for the writer thread are lost, along with
+/// whatever the backend hadn't made durable yet: up to `flush_every_ms` of writes with sled, none
+/// with `SQLite`, which commits each batch.
+pub struct SensorHistory {
+    backend: Arc<dyn StorageBackend>,
+    writer: UnboundedSender<HistorySample>,
+    catch_up_window: f64,
+}
+
+impl SensorHistory {
+    pub fn open(backend: Arc<dyn StorageBackend>, config: HistoryConfig) -> Result<Self> {
+        let (writer, queue) = unbounded_channel();
+        {
+            let backend = backend.clone();
+            thread::Builder::new()
+                .name(String::from("history-writer"))
+                .spawn(move || write_samples(&*backend, queue))
+                .context("Couldn't spawn history writer thread")?;
+        }
+
+        if !config.retention.is_empty() {
+            Compactor::new(backend.clone(), config.retention).spawn(config.compact_every)?;
+        }
+
+        Ok(Self {
+            backend,
+            writer,
+            catch_up_window: config.catch_up_window,
+        })
+    }
+
+    /// Queues a measurement taken right now to be stored
+    pub fn record(&self, node: PeerId, data: FullSensorData) {
+        let sample = HistorySample {
+            at: now_millis(),
+            node,
+            data,
+        };
+        if self.writer.send(sample).is_err() {
+            error!("History writer thread is gone, measurement won't be stored");
+        }
+    }
+
+    /// Returns the part of the history of a sensor matching a query
+    pub fn query(
+        &self,
+        node: PeerId,
+        device: &str,
+        sensor_name: &str,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage> {
+        query::run(
+            &*self.backend,
+            &SeriesId::new(node, device, sensor_name),
+            query,
+        )
+        .context("Couldn't query sensor history")
+    }
+
+    /// Returns the time from which to ask a node for the measurements of its sensors, to fill in
+    /// what was missed while either was offline
+    pub fn catch_up_since(&self, node: PeerId) -> Result<u64> {
+        catch_up::since(&*self.backend, node, self.catch_up_window)
+            .context("Couldn't find where to catch up from")
+    }
+
+    /// Returns the oldest measurements of the sensors of `node` taken since the given time, up to
+    /// `limit` of them
+    pub fn catch_up_page(&self, node: PeerId, since: u64, limit: usize) -> Result<CatchUpPage> {
+        catch_up::page(&*self.backend, node, since, limit)
+            .context("Couldn't read measurements to catch up on")
+    }
+
+    /// Stores measurements another node sent of its own sensors, returning how many were new
+    pub fn merge_caught_up(&self, node: PeerId, samples: Vec<CaughtUpSample>) -> Result<usize> {
+        catch_up::merge(&*self.backend, node, samples)
+            .context("Couldn't store measurements caught up on")
+    }
+}
+
+/// Stores queued samples in batches, until every handle to the history is dropped
+fn write_samples(backend: &dyn StorageBackend, mut queue: UnboundedReceiver<HistorySample>) {
+    while let Some(sample) = queue.blocking_recv() {
+        let mut batch = vec![sample];
+        while batch.len() < MAX_BATCH_SIZE {
+            match queue.recv().now_or_never() {
+                Some(Some(sample)) => batch.push(sample),
+                _ => break,
+            }
+        }
+
+        if let Err(err) = backend.append_samples(&batch) {
+            error!(
+                "Couldn't write {} samples to history: {:#}",
+                batch.len(),
+                err
+            );
+        }
+    }
+
+    debug!("History writer stopped");
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use super::{now_millis, retention::seconds_to_millis, HistorySample};
+use crate::{hardware::FullSensorData, store::StorageBackend};
+
+/// A measurement of one of the sensors of the node sending it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaughtUpSample {
+    /// Unix timestamp of the measurement, in milliseconds, as recorded by the sending node
+    pub at: u64,
+    pub device: String,
+    pub sensor_name: String,
+    pub value: Measurement,
+}
+
+/// Measurements of the sensors of a node taken since some time, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatchUpPage {
+    pub samples: Vec<CaughtUpSample>,
+    /// Time to ask for measurements since to get the next page, if there are more
+    pub next_since: Option<u64>,
+}
+
+/// Time from which to ask a node for the measurements of its sensors: right after the newest one
+/// already stored, but no earlier than `window` seconds ago
+pub(super) fn since(backend: &dyn StorageBackend, node: PeerId, window: f64) -> Result<u64> {
+    let mut since = now_millis().saturating_sub(seconds_to_millis(window));
+    for series in backend.series()? {
+        if series.node == node {
+            if let Some(newest) = backend.newest_sample_at(&series)? {
+                since = since.max(newest.saturating_add(1));
+            }
+        }
+    }
+    Ok(since)
+}
+
+/// Returns the `limit` oldest measurements of the sensors of `node` taken since the given time
+pub(super) fn page(
+    backend: &dyn StorageBackend,
+    node: PeerId,
+    since: u64,
+    limit: usize,
+) -> Result<CatchUpPage> {
+    // The oldest `limit` measurements overall are among the oldest `limit` of each sensor
+    let mut samples = Vec::new();
+    for series in backend.series()? {
+        if series.node != node {
+            continue;
+        }
+        for (at, value) in backend.samples(&series, since, u64::MAX, limit + 1)? {
+            samples.push(CaughtUpSample {
+                at,
+                device: series.device.clone(),
+                sensor_name: series.sensor_name.clone(),
+                value,
+            });
+        }
+    }
+    samples.sort_by_key(|sample| sample.at);
+
+    // Measurements taken on the same millisecond go on the same page, so that the next one
+    // starts right after them
+    let mut next_since = None;
+    if let Some(cut) = samples.get(limit).map(|sample| sample.at) {
+        if samples[0].at < cut {
+            samples.retain(|sample| sample.at < cut);
+            next_since = Some(cut);
+        } else {
+            // There are at most as many of them as there are sensors
+            samples.retain(|sample| sample.at == cut);
+            next_since = Some(cut.saturating_add(1));
+        }
+    }
+
+    Ok(CatchUpPage {
+        samples,
+        next_since,
+    })
+}
+
+/// Stores measurements of the sensors of `node`, skipping those already stored
+pub(super) fn merge(
+    backend: &dyn StorageBackend,
+    node: PeerId,
+    samples: Vec<CaughtUpSample>,
+) -> Result<usize> {
+    let samples: Vec<HistorySample> = samples
+        .into_iter()
+        .map(|sample| HistorySample {
+            at: sample.at,
+            node,
+            data: FullSensorData {
+                device: sample.device,
+                sensor_name: sample.sensor_name,
+                value: sample.value,
+            },
+        })
+        .collect();
+    backend.insert_samples(&samples)
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use diot_core::device::Measurement;
+use serde::{Deserialize, Serialize};
+
+use super::retention::{numeric_value, seconds_to_millis};
+use super::Rollup;
+use crate::store::{SeriesId, StorageBackend};
+
+/// Points returned when a query doesn't give a limit
+const DEFAULT_LIMIT: usize = 1000;
+/// Most points a single page may have
+const MAX_LIMIT: usize = 10_000;
+/// Samples read at once while filling up buckets
+const SCAN_CHUNK: usize = 4096;
+
+/// Time range and shape of the history to get out of a sensor
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    /// Unix timestamp in milliseconds to start at, inclusive
+    pub from: Option<u64>,
+    /// Unix timestamp in milliseconds to end at, exclusive
+    pub to: Option<u64>,
+    /// Seconds of each interval to summarize measurements over, instead of returning them as-is
+    pub bucket: Option<f64>,
+    /// Maximum number of measurements or intervals to return
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    /// Unix timestamp of the measurement, in milliseconds
+    pub at: u64,
+    pub value: Measurement,
+}
+
+/// Summary of the measurements of an interval
+// Not flattening a `Rollup` in, as pages are also sent to other nodes with bincode
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryBucket {
+    /// Unix timestamp in milliseconds the interval starts at
+    pub start: u64,
+    pub count: u64,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryPoints {
+    Samples(Vec<HistoryPoint>),
+    Buckets(Vec<HistoryBucket>),
+}
+
+impl HistoryPoints {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            HistoryPoints::Samples(samples) => samples.is_empty(),
+            HistoryPoints::Buckets(buckets) => buckets.is_empty(),
+        }
+    }
+}
+
+/// Part of the history of a sensor matching a query
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub points: HistoryPoints,
+    /// Value of `from` to get the next page with, if there is more history within the range
+    pub next_from: Option<u64>,
+}
+
+pub(super) fn run(
+    backend: &dyn StorageBackend,
+    series: &SeriesId,
+    query: &HistoryQuery,
+) -> Result<HistoryPage> {
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or(u64::MAX);
+
+    query.bucket.map_or_else(
+        || samples(backend, series, from, to, query.limit()),
+        |bucket| {
+            let every = seconds_to_millis(bucket).max(1);
+            buckets(backend, series, every, from, to, query.limit())
+        },
+    )
+}
+
+fn samples(
+    backend: &dyn StorageBackend,
+    series: &SeriesId,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Result<HistoryPage> {
+    let mut samples = backend.samples(series, from, to, limit + 1)?;
+    let next_from = (samples.len() > limit).then(|| samples[limit].0);
+    samples.truncate(limit);
+
+    Ok(HistoryPage {
+        points: HistoryPoints::Samples(
+            samples
+                .into_iter()
+                .map(|(at, value)| HistoryPoint { at, value })
+                .collect(),
+        ),
+        next_from,
+    })
+}
+
+/// Summarizes the history of a sensor over intervals of `every` milliseconds
+///
+/// Intervals are aligned to multiples of their length since the Unix epoch, the first and last
+/// ones only covering the part of them within the range. Where raw measurements have been dropped
+/// already, rollups made of them over intervals of the same length are used instead.
+fn buckets(
+    backend: &dyn StorageBackend,
+    series: &SeriesId,
+    every: u64,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Result<HistoryPage> {
+    let mut buckets: BTreeMap<u64, Rollup> = BTreeMap::new();
+    let mut scanned_until = to;
+    let mut cursor = from;
+    'scan: loop {
+        let chunk = backend.samples(series, cursor, to, SCAN_CHUNK)?;
+        let exhausted = chunk.len() < SCAN_CHUNK;
+        for (at, value) in chunk {
+            cursor = at.saturating_add(1);
+            let Some(value) = numeric_value(&value) else {
+                continue;
+            };
+            let start = at - at % every;
+            if !buckets.contains_key(&start) && buckets.len() == limit {
+                scanned_until = start;
+                break 'scan;
+            }
+            buckets
+                .entry(start)
+                .and_modify(|bucket| bucket.add(value))
+                .or_insert_with(|| Rollup::new(value));
+        }
+        if exhausted {
+            break;
+        }
+    }
+
+    // Rollups only exist for complete intervals, so they may be used as-is; when part of the raw
+    // measurements of an interval are gone, its rollup accounts for more of them
+    let rollups_from = from.saturating_add(every - 1) / every * every;
+    for (start, rollup) in backend.rollups(series, every, rollups_from, scanned_until)? {
+        let bucket = buckets.entry(start).or_insert(rollup);
+        if rollup.count > bucket.count {
+            *bucket = rollup;
+        }
+    }
+
+    let next_from = if let Some(&cut) = buckets.keys().nth(limit) {
+        buckets.split_off(&cut);
+        Some(cut)
+    } else {
+        (scanned_until < to).then_some(scanned_until)
+    };
+
+    Ok(HistoryPage {
+        points: HistoryPoints::Buckets(
+            buckets
+                .into_iter()
+                .map(|(start, rollup)| HistoryBucket {
+                    start,
+                    count: rollup.count,
+                    min: rollup.min,
+                    mean: rollup.mean,
+                    max: rollup.max,
+                })
+                .collect(),
+        ),
+        next_from,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::{hardware::FullSensorData, history::HistorySample, store::MemoryBackend};
+
+    /// Backend holding a measurement every 10 seconds over the first minute, each being the
+    /// number of seconds it was taken at
+    fn backend(node: PeerId) -> MemoryBackend {
+        let backend = MemoryBackend::new();
+        let samples: Vec<_> = (0..6)
+            .map(|i| HistorySample {
+                at: i * 10_000,
+                node,
+                data: FullSensorData {
+                    device: String::from("dht11-1"),
+                    sensor_name: String::from("temperature"),
+                    value: Measurement::Integer(i64::try_from(i * 10).unwrap()),
+                },
+            })
+            .collect();
+        backend.append_samples(&samples).unwrap();
+        backend
+    }
+
+    fn query(from: Option<u64>, bucket: Option<f64>, limit: usize) -> HistoryQuery {
+        HistoryQuery {
+            from,
+            to: None,
+            bucket,
+            limit: Some(limit),
+        }
+    }
+
+    fn bucket_starts(page: &HistoryPage) -> Vec<u64> {
+        match &page.points {
+            HistoryPoints::Buckets(buckets) => buckets.iter().map(|bucket| bucket.start).collect(),
+            HistoryPoints::Samples(_) => panic!("expected buckets"),
+        }
+    }
+
+    #[test]
+    fn sample_pages_resume_where_the_last_one_ended() {
+        let node = PeerId::random();
+        let (backend, series) = (backend(node), SeriesId::new(node, "dht11-1", "temperature"));
+
+        let page = run(&backend, &series, &query(None, None, 4)).unwrap();
+        assert_eq!(page.next_from, Some(40_000));
+        let page = run(&backend, &series, &query(page.next_from, None, 4)).unwrap();
+        assert_eq!(page.next_from, None);
+        assert!(matches!(
+            &page.points,
+            HistoryPoints::Samples(samples) if samples.iter().map(|p| p.at).eq([40_000, 50_000])
+        ));
+    }
+
+    #[test]
+    fn bucket_pages_stop_at_the_limit_and_cover_everything() {
+        let node = PeerId::random();
+        let (backend, series) = (backend(node), SeriesId::new(node, "dht11-1", "temperature"));
+
+        let mut starts = Vec::new();
+        let mut from = None;
+        loop {
+            let page = run(&backend, &series, &query(from, Some(20.0), 2)).unwrap();
+            assert!(bucket_starts(&page).len() <= 2);
+            starts.extend(bucket_starts(&page));
+            from = page.next_from;
+            if from.is_none() {
+                break;
+            }
+        }
+        assert_eq!(starts, [0, 20_000, 40_000]);
+
+        let page = run(&backend, &series, &query(None, Some(20.0), 10)).unwrap();
+        assert_eq!(page.next_from, None);
+        assert_eq!(
+            page.points,
+            HistoryPoints::Buckets(
+                [(0, 0.0, 10.0), (20_000, 20.0, 30.0), (40_000, 40.0, 50.0)]
+                    .iter()
+                    .map(|(start, min, max)| HistoryBucket {
+                        start: *start,
+                        count: 2,
+                        min: *min,
+                        mean: (min + max) / 2.0,
+                        max: *max,
+                    })
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn rollups_stand_in_for_dropped_measurements() {
+        let node = PeerId::random();
+        let (backend, series) = (backend(node), SeriesId::new(node, "dht11-1", "temperature"));
+        let rollup = Rollup {
+            count: 20,
+            min: -5.0,
+            mean: 2.0,
+            max: 15.0,
+        };
+        backend
+            .put_rollups(&series, 20_000, &[(0, rollup)], 20_000)
+            .unwrap();
+        backend.remove_samples(&series, 10_000).unwrap();
+
+        let page = run(&backend, &series, &query(None, Some(20.0), 10)).unwrap();
+        let HistoryPoints::Buckets(buckets) = page.points else {
+            panic!("expected buckets");
+        };
+        assert_eq!(buckets.len(), 3);
+        assert_eq!((buckets[0].count, buckets[0].min), (20, -5.0));
+        assert_eq!(buckets[1].count, 2);
+    }
+}
@@ -0,0 +1,330 @@
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc, thread, time::Duration};
+
+use anyhow::{Context, Result};
+use diot_core::device::Measurement;
+use serde::{Deserialize, Serialize};
+
+use super::now_millis;
+use crate::store::{SeriesId, StorageBackend};
+
+pub(super) fn seconds_to_millis(seconds: f64) -> u64 {
+    u64::try_from(Duration::from_secs_f64(seconds.max(0.0)).as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Summary of the measurements of a sensor over a period of time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rollup {
+    pub count: u64,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+impl Rollup {
+    pub(super) fn new(value: f64) -> Self {
+        Self {
+            count: 1,
+            min: value,
+            mean: value,
+            max: value,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub(super) fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.mean += (value - self.mean) / self.count as f64;
+    }
+}
+
+/// Interval raw measurements are rolled up into, and for how long to keep the rollups
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollupPolicy {
+    /// Length of each interval, in seconds
+    pub every: f64,
+    /// Seconds to keep rollups for (forever if missing)
+    pub keep: Option<f64>,
+}
+
+/// How long to keep the history of some sensors for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Device the policy applies to (all of them if missing)
+    pub device: Option<String>,
+    /// Sensor the policy applies to (all of them if missing)
+    pub sensor_name: Option<String>,
+    /// Seconds to keep raw measurements for (forever if missing)
+    pub keep_raw: Option<f64>,
+    /// Rollups to make from raw measurements before dropping them
+    #[serde(default)]
+    pub rollups: Vec<RollupPolicy>,
+}
+
+impl RetentionPolicy {
+    fn matches(&self, device: &str, sensor_name: &str) -> bool {
+        self.device.as_deref().is_none_or(|name| name == device)
+            && self
+                .sensor_name
+                .as_deref()
+                .is_none_or(|name| name == sensor_name)
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub(super) fn numeric_value(value: &Measurement) -> Option<f64> {
+    match value {
+        Measurement::Integer(value) => Some(*value as f64),
+        Measurement::Double(value) => Some(*value),
+        Measurement::Signal | Measurement::String(_) => None,
+    }
+}
+
+/// Rolls up and drops the history of sensors, according to the first policy matching each
+pub(super) struct Compactor {
+    backend: Arc<dyn StorageBackend>,
+    policies: Vec<RetentionPolicy>,
+}
+
+impl Compactor {
+    pub(super) fn new(
+        backend: Arc<dyn StorageBackend>,
+        mut policies: Vec<RetentionPolicy>,
+    ) -> Self {
+        for policy in &mut policies {
+            policy.rollups.retain(|rollup| {
+                let valid = rollup.every > 0.0;
+                if !valid {
+                    warn!(
+                        "Ignoring rollup every {} seconds of history: interval must be positive",
+                        rollup.every
+                    );
+                }
+                valid
+            });
+        }
+
+        Self { backend, policies }
+    }
+
+    /// Compacts the history every given number of seconds, on a thread of its own
+    pub(super) fn spawn(self, every: f64) -> Result<()> {
+        let every = Duration::from_secs_f64(every.max(1.0));
+        thread::Builder::new()
+            .name(String::from("history-compactor"))
+            .spawn(move || loop {
+                thread::sleep(every);
+                if let Err(err) = self.compact(now_millis()) {
+                    error!("Couldn't compact sensor history: {:#}", err);
+                }
+            })
+            .context("Couldn't spawn history compactor thread")?;
+
+        Ok(())
+    }
+
+    fn compact(&self, now: u64) -> Result<()> {
+        for series in self.backend.series()? {
+            if let Some(policy) = self
+                .policies
+                .iter()
+                .find(|policy| policy.matches(&series.device, &series.sensor_name))
+            {
+                self.compact_series(&series, policy, now).with_context(|| {
+                    format!(
+                        "Couldn't compact history of {}/{}",
+                        series.device, series.sensor_name
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compact_series(&self, series: &SeriesId, policy: &RetentionPolicy, now: u64) -> Result<()> {
+        // Raw measurements are only dropped once every rollup has been made out of them
+        let mut raw_cutoff = policy
+            .keep_raw
+            .map_or(0, |keep| now.saturating_sub(seconds_to_millis(keep)));
+
+        for rollup in &policy.rollups {
+            let every = seconds_to_millis(rollup.every).max(1);
+            let complete_until = now - now % every;
+            let rolled_until = self.backend.rolled_until(series, every)?;
+
+            if rolled_until < complete_until {
+                let mut buckets: BTreeMap<u64, Rollup> = BTreeMap::new();
+                for (at, value) in
+                    self.backend
+                        .samples(series, rolled_until, complete_until, usize::MAX)?
+                {
+                    let Some(value) = numeric_value(&value) else {
+                        continue;
+                    };
+                    buckets
+                        .entry(at - at % every)
+                        .and_modify(|bucket| bucket.add(value))
+                        .or_insert_with(|| Rollup::new(value));
+                }
+
+                let buckets: Vec<(u64, Rollup)> = buckets.into_iter().collect();
+                self.backend
+                    .put_rollups(series, every, &buckets, complete_until)?;
+            }
+
+            raw_cutoff = raw_cutoff.min(rolled_until.max(complete_until));
+
+            if let Some(keep) = rollup.keep {
+                let cutoff = now.saturating_sub(seconds_to_millis(keep));
+                self.backend.remove_rollups(series, every, cutoff)?;
+            }
+        }
+
+        if policy.keep_raw.is_some() {
+            self.backend.remove_samples(series, raw_cutoff)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::{hardware::FullSensorData, history::HistorySample, store::MemoryBackend};
+
+    const MINUTE: u64 = 60_000;
+
+    fn policy(keep_raw: Option<f64>, rollups: &[(f64, Option<f64>)]) -> RetentionPolicy {
+        RetentionPolicy {
+            device: Some(String::from("dht11-1")),
+            sensor_name: None,
+            keep_raw,
+            rollups: rollups
+                .iter()
+                .map(|(every, keep)| RollupPolicy {
+                    every: *every,
+                    keep: *keep,
+                })
+                .collect(),
+        }
+    }
+
+    /// Backend holding one measurement every 30 seconds over the first `minutes` minutes, each
+    /// being the number of seconds it was taken at
+    fn backend(node: PeerId, minutes: u64) -> Arc<dyn StorageBackend> {
+        let backend = Arc::new(MemoryBackend::new());
+        let samples: Vec<_> = (0..minutes * 2)
+            .map(|i| HistorySample {
+                at: i * 30_000,
+                node,
+                data: FullSensorData {
+                    device: String::from("dht11-1"),
+                    sensor_name: String::from("temperature"),
+                    value: Measurement::Integer(i64::try_from(i * 30).unwrap()),
+                },
+            })
+            .collect();
+        backend.append_samples(&samples).unwrap();
+        backend
+    }
+
+    #[test]
+    fn complete_intervals_are_rolled_up_once() {
+        let node = PeerId::random();
+        let series = SeriesId::new(node, "dht11-1", "temperature");
+        let backend = backend(node, 3);
+        let compactor = Compactor::new(backend.clone(), vec![policy(None, &[(60.0, None)])]);
+
+        // Halfway through the third minute, which isn't complete yet
+        compactor.compact(2 * MINUTE + 30_000).unwrap();
+        let rollups = backend.rollups(&series, MINUTE, 0, u64::MAX).unwrap();
+        assert_eq!(
+            rollups,
+            [
+                (
+                    0,
+                    Rollup {
+                        count: 2,
+                        min: 0.0,
+                        mean: 15.0,
+                        max: 30.0
+                    }
+                ),
+                (
+                    MINUTE,
+                    Rollup {
+                        count: 2,
+                        min: 60.0,
+                        mean: 75.0,
+                        max: 90.0
+                    }
+                ),
+            ]
+        );
+        assert_eq!(backend.rolled_until(&series, MINUTE).unwrap(), 2 * MINUTE);
+
+        compactor.compact(3 * MINUTE).unwrap();
+        let rollups = backend.rollups(&series, MINUTE, 0, u64::MAX).unwrap();
+        assert_eq!(rollups.len(), 3);
+        assert_eq!(rollups[0].1.count, 2);
+    }
+
+    #[test]
+    fn raw_measurements_outlive_their_rollups_being_made() {
+        let node = PeerId::random();
+        let series = SeriesId::new(node, "dht11-1", "temperature");
+        let backend = backend(node, 3);
+        let compactor = Compactor::new(backend.clone(), vec![policy(Some(0.0), &[(60.0, None)])]);
+
+        compactor.compact(2 * MINUTE + 30_000).unwrap();
+        let kept = backend.samples(&series, 0, u64::MAX, usize::MAX).unwrap();
+        assert_eq!(kept.first().map(|(at, _)| *at), Some(2 * MINUTE));
+    }
+
+    #[test]
+    fn old_rollups_and_measurements_are_dropped() {
+        let node = PeerId::random();
+        let series = SeriesId::new(node, "dht11-1", "temperature");
+        let backend = backend(node, 3);
+        let compactor = Compactor::new(
+            backend.clone(),
+            vec![policy(Some(60.0), &[(60.0, Some(90.0)), (0.0, None)])],
+        );
+
+        // Rollups of intervals starting over 90 seconds ago go; the one every 0 seconds is ignored
+        compactor.compact(3 * MINUTE).unwrap();
+        let rollups = backend.rollups(&series, MINUTE, 0, u64::MAX).unwrap();
+        assert_eq!(
+            rollups.iter().map(|(at, _)| *at).collect::<Vec<_>>(),
+            [2 * MINUTE]
+        );
+        let kept = backend.samples(&series, 0, u64::MAX, usize::MAX).unwrap();
+        assert_eq!(kept.first().map(|(at, _)| *at), Some(2 * MINUTE));
+    }
+
+    #[test]
+    fn unmatched_sensors_are_left_alone() {
+        let node = PeerId::random();
+        let series = SeriesId::new(node, "dht11-1", "temperature");
+        let backend = backend(node, 3);
+        let mut policy = policy(Some(0.0), &[(60.0, None)]);
+        policy.sensor_name = Some(String::from("humidity"));
+
+        Compactor::new(backend.clone(), vec![policy])
+            .compact(3 * MINUTE)
+            .unwrap();
+        assert_eq!(backend.rolled_until(&series, MINUTE).unwrap(), 0);
+        assert_eq!(
+            backend
+                .samples(&series, 0, u64::MAX, usize::MAX)
+                .unwrap()
+                .len(),
+            6
+        );
+    }
+}
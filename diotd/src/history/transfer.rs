@@ -0,0 +1,412 @@
+//! Moving sensor history in and out of storage, as CSV or JSON Lines
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use super::HistorySample;
+use crate::{
+    hardware::FullSensorData,
+    store::{SeriesId, StorageBackend},
+};
+
+/// Samples read from storage at once while exporting
+const EXPORT_CHUNK: usize = 4096;
+/// Samples written to storage at once while importing
+const IMPORT_BATCH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferFormat {
+    #[serde(rename = "csv")]
+    Csv,
+    #[serde(rename = "jsonl")]
+    JsonLines,
+}
+
+impl TransferFormat {
+    /// Guesses the format of a file from its extension
+    pub fn of_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TransferFormat::Csv => "text/csv; charset=utf-8",
+            TransferFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TransferFormat::Csv => "csv",
+            TransferFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+impl FromStr for TransferFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "csv" => Ok(TransferFormat::Csv),
+            "jsonl" | "ndjson" => Ok(TransferFormat::JsonLines),
+            _ => Err(anyhow!(
+                "Unknown history format \"{}\" (expected csv or jsonl)",
+                format
+            )),
+        }
+    }
+}
+
+/// Sensors and time range to export; empty lists select everything
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    /// Nodes, as peer IDs or display names
+    pub nodes: Vec<String>,
+    pub devices: Vec<String>,
+    pub sensors: Vec<String>,
+    /// Unix timestamp in milliseconds to start at, inclusive
+    pub from: Option<u64>,
+    /// Unix timestamp in milliseconds to end at, exclusive
+    pub to: Option<u64>,
+}
+
+impl ExportFilter {
+    fn matches(&self, series: &SeriesId, names: &HashMap<PeerId, String>) -> bool {
+        (self.nodes.is_empty()
+            || self.nodes.iter().any(|node| {
+                *node == series.node.to_base58() || names.get(&series.node) == Some(node)
+            }))
+            && (self.devices.is_empty() || self.devices.contains(&series.device))
+            && (self.sensors.is_empty() || self.sensors.contains(&series.sensor_name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ValueKind {
+    Signal,
+    Integer,
+    Double,
+    String,
+}
+
+/// A measurement as exported, one per row or line
+///
+/// The value is a plain number or string (or nothing for signals), its kind telling which
+/// measurement it stands for.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedSample<V> {
+    at: u64,
+    node: String,
+    device: String,
+    sensor_name: String,
+    kind: ValueKind,
+    value: V,
+}
+
+impl<V> ExportedSample<V> {
+    fn new(series: &SeriesId, at: u64, (kind, value): (ValueKind, V)) -> Self {
+        Self {
+            at,
+            node: series.node.to_base58(),
+            device: series.device.clone(),
+            sensor_name: series.sensor_name.clone(),
+            kind,
+            value,
+        }
+    }
+
+    fn into_sample(self, value: Measurement) -> Result<HistorySample> {
+        Ok(HistorySample {
+            at: self.at,
+            node: self
+                .node
+                .parse()
+                .map_err(|_| anyhow!("Invalid peer ID \"{}\"", self.node))?,
+            data: FullSensorData {
+                device: self.device,
+                sensor_name: self.sensor_name,
+                value,
+            },
+        })
+    }
+}
+
+fn csv_value(value: &Measurement) -> (ValueKind, String) {
+    match value {
+        Measurement::Signal => (ValueKind::Signal, String::new()),
+        Measurement::Integer(value) => (ValueKind::Integer, value.to_string()),
+        Measurement::Double(value) => (ValueKind::Double, value.to_string()),
+        Measurement::String(value) => (ValueKind::String, value.clone()),
+    }
+}
+
+fn json_value(value: &Measurement) -> (ValueKind, serde_json::Value) {
+    match value {
+        Measurement::Signal => (ValueKind::Signal, serde_json::Value::Null),
+        Measurement::Integer(value) => (ValueKind::Integer, (*value).into()),
+        Measurement::Double(value) => (ValueKind::Double, (*value).into()),
+        Measurement::String(value) => (ValueKind::String, value.clone().into()),
+    }
+}
+
+fn measurement_from_csv(kind: ValueKind, value: &str) -> Result<Measurement> {
+    Ok(match kind {
+        ValueKind::Signal => Measurement::Signal,
+        ValueKind::Integer => Measurement::Integer(value.parse().context("Invalid integer")?),
+        ValueKind::Double => Measurement::Double(value.parse().context("Invalid decimal")?),
+        ValueKind::String => Measurement::String(value.to_string()),
+    })
+}
+
+fn measurement_from_json(kind: ValueKind, value: serde_json::Value) -> Result<Measurement> {
+    Ok(match (kind, value) {
+        (ValueKind::Signal, _) => Measurement::Signal,
+        (ValueKind::Integer, value) => {
+            Measurement::Integer(value.as_i64().context("Invalid integer")?)
+        }
+        (ValueKind::Double, value) => {
+            Measurement::Double(value.as_f64().context("Invalid decimal")?)
+        }
+        (ValueKind::String, serde_json::Value::String(value)) => Measurement::String(value),
+        (ValueKind::String, _) => bail!("Invalid string"),
+    })
+}
+
+enum SampleWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl<W: Write> SampleWriter<W> {
+    fn new(format: TransferFormat, out: W) -> Result<Self> {
+        Ok(match format {
+            TransferFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(out);
+                writer.write_record(["at", "node", "device", "sensor_name", "kind", "value"])?;
+                SampleWriter::Csv(Box::new(writer))
+            }
+            TransferFormat::JsonLines => SampleWriter::JsonLines(out),
+        })
+    }
+
+    fn write(&mut self, series: &SeriesId, at: u64, value: &Measurement) -> Result<()> {
+        match self {
+            SampleWriter::Csv(writer) => {
+                writer.serialize(ExportedSample::new(series, at, csv_value(value)))?;
+            }
+            SampleWriter::JsonLines(out) => {
+                serde_json::to_writer(
+                    &mut *out,
+                    &ExportedSample::new(series, at, json_value(value)),
+                )?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            SampleWriter::Csv(mut writer) => writer.flush()?,
+            SampleWriter::JsonLines(mut out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes the stored history of the selected sensors, oldest first within each sensor, and
+/// returns how many measurements were written
+pub fn export_history(
+    backend: &dyn StorageBackend,
+    filter: &ExportFilter,
+    format: TransferFormat,
+    out: impl Write,
+) -> Result<usize> {
+    let names: HashMap<PeerId, String> = backend
+        .load_peers()?
+        .into_iter()
+        .map(|(peer, data)| (peer, data.name))
+        .collect();
+    let mut series: Vec<SeriesId> = backend
+        .series()?
+        .into_iter()
+        .filter(|series| filter.matches(series, &names))
+        .collect();
+    series.sort_by_cached_key(|series| {
+        (
+            series.node.to_base58(),
+            series.device.clone(),
+            series.sensor_name.clone(),
+        )
+    });
+
+    let mut writer = SampleWriter::new(format, BufWriter::with_capacity(64 * 1024, out))?;
+    let mut written = 0;
+    let to = filter.to.unwrap_or(u64::MAX);
+    for series in &series {
+        let mut cursor = filter.from.unwrap_or(0);
+        loop {
+            let chunk = backend.samples(series, cursor, to, EXPORT_CHUNK)?;
+            for (at, value) in &chunk {
+                writer.write(series, *at, value)?;
+            }
+            written += chunk.len();
+
+            match chunk.last() {
+                Some((at, _)) if chunk.len() == EXPORT_CHUNK => cursor = at.saturating_add(1),
+                _ => break,
+            }
+        }
+    }
+
+    writer.finish()?;
+    Ok(written)
+}
+
+/// Outcome of an import
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    /// Measurements found in the input
+    pub read: usize,
+    /// Measurements which weren't stored already
+    pub imported: usize,
+}
+
+/// Stores measurements from an export, skipping those already stored
+///
+/// Measurements are stored in batches as they are read, so the ones before an invalid record are
+/// kept.
+pub fn import_history(
+    backend: &dyn StorageBackend,
+    format: TransferFormat,
+    input: impl Read,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH);
+    let mut store = |batch: &mut Vec<HistorySample>| -> Result<()> {
+        report.read += batch.len();
+        report.imported += backend.insert_samples(batch)?;
+        batch.clear();
+        Ok(())
+    };
+
+    match format {
+        TransferFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            for (index, record) in reader.deserialize::<ExportedSample<String>>().enumerate() {
+                let sample = record
+                    .map_err(anyhow::Error::from)
+                    .and_then(|record| {
+                        let value = measurement_from_csv(record.kind, &record.value)?;
+                        record.into_sample(value)
+                    })
+                    .with_context(|| format!("Invalid record #{}", index + 1))?;
+                batch.push(sample);
+                if batch.len() == IMPORT_BATCH {
+                    store(&mut batch)?;
+                }
+            }
+        }
+        TransferFormat::JsonLines => {
+            for (index, line) in BufReader::new(input).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let sample = serde_json::from_str::<ExportedSample<serde_json::Value>>(&line)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|mut record| {
+                        let value = measurement_from_json(record.kind, record.value.take())?;
+                        record.into_sample(value)
+                    })
+                    .with_context(|| format!("Invalid record on line {}", index + 1))?;
+                batch.push(sample);
+                if batch.len() == IMPORT_BATCH {
+                    store(&mut batch)?;
+                }
+            }
+        }
+    }
+
+    store(&mut batch)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryBackend;
+
+    fn sample(at: u64, node: PeerId, sensor_name: &str, value: Measurement) -> HistorySample {
+        HistorySample {
+            at,
+            node,
+            data: FullSensorData {
+                device: String::from("probe"),
+                sensor_name: String::from(sensor_name),
+                value,
+            },
+        }
+    }
+
+    fn stored(backend: &dyn StorageBackend) -> Vec<(SeriesId, Vec<(u64, Measurement)>)> {
+        let mut series = backend.series().unwrap();
+        series.sort_by_key(|series| series.sensor_name.clone());
+        series
+            .into_iter()
+            .map(|series| {
+                let samples = backend.samples(&series, 0, u64::MAX, usize::MAX).unwrap();
+                (series, samples)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_are_imported_back_unchanged() {
+        let node = PeerId::random();
+        let source = MemoryBackend::new();
+        source
+            .append_samples(&[
+                sample(1_000, node, "button", Measurement::Signal),
+                sample(1_000, node, "count", Measurement::Integer(-42)),
+                sample(2_000, node, "count", Measurement::Integer(i64::MAX)),
+                sample(1_000, node, "level", Measurement::Double(0.1)),
+                sample(
+                    1_000,
+                    node,
+                    "status",
+                    Measurement::String(String::from("ok, \"mostly\"")),
+                ),
+                sample(2_000, node, "status", Measurement::String(String::new())),
+            ])
+            .unwrap();
+
+        for format in [TransferFormat::Csv, TransferFormat::JsonLines] {
+            let mut export = Vec::new();
+            let written =
+                export_history(&source, &ExportFilter::default(), format, &mut export).unwrap();
+            assert_eq!(written, 6, "{format:?}");
+
+            let target = MemoryBackend::new();
+            let report = import_history(&target, format, export.as_slice()).unwrap();
+            assert_eq!((report.read, report.imported), (6, 6), "{format:?}");
+            assert_eq!(stored(&target), stored(&source), "{format:?}");
+
+            let report = import_history(&target, format, export.as_slice()).unwrap();
+            assert_eq!((report.read, report.imported), (6, 0), "{format:?}");
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::{convert::TryFrom, time::Duration};
+
+use diot_core::device::Measurement;
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::FullSensorData;
+
+/// Pseudo-device whose sensor tells whether a node is online, for rules and scripts to listen to
+pub const LIVENESS_DEVICE: &str = "liveness";
+/// Sensor of the liveness pseudo-device, measuring 1 when a node comes online and 0 when it
+/// goes offline
+pub const ONLINE_SENSOR: &str = "online";
+
+/// Seconds between identity broadcasts of each node
+pub const IDENTITY_INTERVAL: Duration = Duration::from_secs(5);
+
+fn default_missed_broadcasts() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LivenessConfig {
+    /// Number of identity broadcasts a peer may miss in a row before being considered offline
+    #[serde(default = "default_missed_broadcasts")]
+    pub missed_broadcasts: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            missed_broadcasts: default_missed_broadcasts(),
+        }
+    }
+}
+
+impl LivenessConfig {
+    /// Milliseconds of silence after which a peer is considered offline
+    pub fn timeout(&self) -> u64 {
+        let timeout = IDENTITY_INTERVAL * self.missed_broadcasts.max(1);
+        u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerStatus {
+    Online,
+    Offline,
+}
+
+impl PeerStatus {
+    /// Measurement of the liveness pseudo-device reporting this status
+    pub fn as_sensor_data(self) -> FullSensorData {
+        FullSensorData {
+            device: LIVENESS_DEVICE.to_string(),
+            sensor_name: ONLINE_SENSOR.to_string(),
+            value: Measurement::Integer(match self {
+                PeerStatus::Online => 1,
+                PeerStatus::Offline => 0,
+            }),
+        }
+    }
+}
+
+/// Whether a peer is reachable, and when it was last heard from
+///
+/// Times are Unix timestamps in milliseconds, and only cover what happened since this node
+/// started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Liveness {
+    pub status: PeerStatus,
+    /// First time the peer was heard from
+    pub first_seen: Option<u64>,
+    /// Last time the peer was heard from
+    pub last_seen: Option<u64>,
+    /// Whether there is an open connection to the peer
+    pub connected: bool,
+    /// Whether the peer is currently announced on the local network
+    pub discovered: bool,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            status: PeerStatus::Offline,
+            first_seen: None,
+            last_seen: None,
+            connected: false,
+            discovered: false,
+        }
+    }
+}
+
+impl Liveness {
+    /// Sets the status of the peer, returning it if it changed
+    fn set_status(&mut self, status: PeerStatus) -> Option<PeerStatus> {
+        if self.status == status {
+            return None;
+        }
+        self.status = status;
+        Some(status)
+    }
+
+    /// Records that the peer was heard from
+    pub fn heard(&mut self, now: u64) -> Option<PeerStatus> {
+        self.first_seen.get_or_insert(now);
+        self.last_seen = Some(now);
+        self.set_status(PeerStatus::Online)
+    }
+
+    pub fn set_connected(&mut self, connected: bool, now: u64) -> Option<PeerStatus> {
+        self.connected = connected;
+        if connected {
+            self.heard(now)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_discovered(&mut self, discovered: bool) -> Option<PeerStatus> {
+        self.discovered = discovered;
+        if !discovered && !self.connected {
+            self.set_status(PeerStatus::Offline)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the peer offline if it hasn't been heard from for longer than `timeout`
+    pub fn check(&mut self, now: u64, timeout: u64) -> Option<PeerStatus> {
+        if self
+            .last_seen
+            .is_none_or(|last_seen| now.saturating_sub(last_seen) > timeout)
+        {
+            self.set_status(PeerStatus::Offline)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_go_online_once_and_offline_after_missed_broadcasts() {
+        let timeout = LivenessConfig::default().timeout();
+        assert_eq!(timeout, 15_000);
+        let mut liveness = Liveness::default();
+
+        assert_eq!(liveness.heard(1_000), Some(PeerStatus::Online));
+        assert_eq!(liveness.heard(6_000), None);
+        assert_eq!(liveness.first_seen, Some(1_000));
+        assert_eq!(liveness.last_seen, Some(6_000));
+
+        assert_eq!(liveness.check(6_000 + timeout, timeout), None);
+        assert_eq!(
+            liveness.check(6_001 + timeout, timeout),
+            Some(PeerStatus::Offline)
+        );
+        assert_eq!(liveness.check(30_000, timeout), None);
+        assert_eq!(liveness.heard(30_000), Some(PeerStatus::Online));
+    }
+
+    #[test]
+    fn peers_never_heard_from_stay_offline() {
+        let mut liveness = Liveness::default();
+        assert_eq!(liveness.check(0, 15_000), None);
+        assert_eq!(liveness.status, PeerStatus::Offline);
+    }
+
+    #[test]
+    fn losing_discovery_only_matters_without_a_connection() {
+        let mut liveness = Liveness::default();
+        assert_eq!(
+            liveness.set_connected(true, 1_000),
+            Some(PeerStatus::Online)
+        );
+        assert_eq!(liveness.set_discovered(true), None);
+        assert_eq!(liveness.set_discovered(false), None);
+        assert_eq!(liveness.status, PeerStatus::Online);
+
+        assert_eq!(liveness.set_connected(false, 2_000), None);
+        assert_eq!(liveness.set_discovered(false), Some(PeerStatus::Offline));
+    }
+
+    #[test]
+    fn statuses_map_to_liveness_measurements() {
+        let online = PeerStatus::Online.as_sensor_data();
+        assert_eq!(online.device, LIVENESS_DEVICE);
+        assert_eq!(online.sensor_name, ONLINE_SENSOR);
+        assert_eq!(online.value, Measurement::Integer(1));
+        assert_eq!(
+            PeerStatus::Offline.as_sensor_data().value,
+            Measurement::Integer(0)
+        );
+    }
+}
@@ -19,15 +19,27 @@ extern crate tracing;
 #[macro_use]
 extern crate async_trait;
 
+mod audit;
+mod authorization;
+mod backtest;
 mod control;
 mod hardware;
+mod history;
+mod liveness;
+mod pairing;
 mod store;
 mod swarm;
 mod system;
 mod web;
 
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
 use anyhow::{Context, Result};
 
+use history::{ExportFilter, TransferFormat};
 use libp2p::{
     identity::{ed25519, Keypair},
     pnet::PreSharedKey,
@@ -36,10 +48,12 @@ use rand::Rng;
 use rand::{prelude::StdRng, SeedableRng};
 use system::{PeerSecrets, System, SystemConfig};
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::File,
+    io::{AsyncReadExt, BufReader},
 };
 
+const CONFIG_PATH: &str = "config.json";
+
 fn generate_psk() -> Result<PreSharedKey> {
     let mut rng = StdRng::from_entropy();
     let mut out = [0_u8; 32];
@@ -57,12 +71,152 @@ fn generate_keypair() -> ed25519::Keypair {
     }
 }
 
+async fn save_config(config: &SystemConfig) -> Result<()> {
+    let config_json =
+        serde_json::to_vec_pretty(config).context("Couldn't re-serialize the config file")?;
+    tokio::fs::write(CONFIG_PATH, config_json)
+        .await
+        .context("Couldn't write back to the config file")
+}
+
+/// `diotd pair <code> [<host>:<port>]`: gets the pre-shared key of the swarm from a node in
+/// pairing mode, found on the local network unless given, then starts as usual
+async fn run_pair(config: &mut SystemConfig, args: &[String]) -> Result<()> {
+    let Some(code) = args.first() else {
+        anyhow::bail!("Usage: diotd pair <code> [<host>:<port>]");
+    };
+    let keypair = match config.secrets.take() {
+        Some(secrets) => secrets.keypair,
+        None => generate_keypair(),
+    };
+    let port = config.pairing.clone().unwrap_or_default().port;
+    let grant = pairing::join(code, args.get(1).map(String::as_str), port, &keypair)
+        .await
+        .context("Couldn't pair")?;
+    info!(
+        "Paired as \"{}\"; saving pre-shared key to config file",
+        grant.role
+    );
+
+    config.secrets = Some(PeerSecrets {
+        psk: grant.psk,
+        keypair,
+    });
+    let network = config.network.get_or_insert_with(Default::default);
+    for address in grant.addresses {
+        if !network.bootstrap.contains(&address) {
+            network.bootstrap.push(address);
+        }
+    }
+    save_config(config).await
+}
+
+/// `diotd backtest <recording.jsonl> [rules.json]`: prints what the rules would have done
+fn run_backtest(config: &SystemConfig, args: &[String]) -> Result<()> {
+    let recording = match args.first() {
+        Some(path) => PathBuf::from(path),
+        None => anyhow::bail!("Usage: diotd backtest <recording.jsonl> [rules.json]"),
+    };
+    let rules = match args.get(1) {
+        Some(path) => backtest::read_rules(Path::new(path))?,
+        None => config.rules.clone().unwrap_or_default(),
+    };
+
+    let samples = backtest::read_recording(&recording)?;
+    let report = backtest::Backtest::new(config, rules)?.run(samples);
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).context("Couldn't serialize backtest report")?
+    );
+
+    Ok(())
+}
+
+const HISTORY_USAGE: &str = "Usage:
+    diotd history export <csv|jsonl> [--node <node>]... [--device <device>]... \
+[--sensor <sensor>]... [--from <ms>] [--to <ms>]
+    diotd history import <file.csv|file.jsonl>";
+
+/// `diotd history export|import ...`: moves sensor history out of and into storage
+fn run_history(config: &SystemConfig, args: &[String]) -> Result<()> {
+    let Some(command) = args.first() else {
+        anyhow::bail!(HISTORY_USAGE);
+    };
+    let backend = config
+        .storage
+        .clone()
+        .unwrap_or_default()
+        .open()
+        .context("Couldn't open storage backend (is diotd running?)")?;
+
+    match command.as_str() {
+        "export" => {
+            let Some(format) = args.get(1) else {
+                anyhow::bail!(HISTORY_USAGE);
+            };
+            let format: TransferFormat = format.parse()?;
+            let mut filter = ExportFilter::default();
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                let Some(value) = options.next() else {
+                    anyhow::bail!("Missing value for {}\n{}", option, HISTORY_USAGE);
+                };
+                match option.as_str() {
+                    "--node" => filter.nodes.push(value.clone()),
+                    "--device" => filter.devices.push(value.clone()),
+                    "--sensor" => filter.sensors.push(value.clone()),
+                    "--from" => filter.from = Some(value.parse().context("Invalid --from")?),
+                    "--to" => filter.to = Some(value.parse().context("Invalid --to")?),
+                    _ => anyhow::bail!("Unknown option {}\n{}", option, HISTORY_USAGE),
+                }
+            }
+
+            let written = history::export_history(&*backend, &filter, format, io::stdout().lock())
+                .context("Couldn't export history")?;
+            eprintln!("Exported {written} measurements");
+        }
+        "import" => {
+            let Some(path) = args.get(1).map(PathBuf::from) else {
+                anyhow::bail!(HISTORY_USAGE);
+            };
+            let format = TransferFormat::of_path(&path).with_context(|| {
+                format!(
+                    "Couldn't tell the format of {} (expected a .csv or .jsonl file)",
+                    path.display()
+                )
+            })?;
+            let file = std::fs::File::open(&path)
+                .with_context(|| format!("Couldn't open {}", path.display()))?;
+
+            let report = history::import_history(&*backend, format, file)
+                .context("Couldn't import history")?;
+            println!(
+                "Imported {} new measurements out of {}",
+                report.imported, report.read
+            );
+        }
+        _ => anyhow::bail!(HISTORY_USAGE),
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    if args.first().map(String::as_str) == Some("history") {
+        // Keeps logs out of exports written to stdout
+        subscriber.with_writer(io::stderr).init();
+    } else {
+        subscriber.init();
+    }
 
     let mut config: SystemConfig = {
-        let file = File::open("config.json")
+        let file = File::open(CONFIG_PATH)
             .await
             .context("Couldn't open config file")?;
         let mut rdr = BufReader::new(file);
@@ -74,25 +228,23 @@ async fn main() -> Result<()> {
         serde_json::from_slice(&buf).context("Couldn't parse config file")?
     };
 
+    match args.first().map(String::as_str) {
+        Some("backtest") => return run_backtest(&config, &args[1..]),
+        Some("history") => return run_history(&config, &args[1..]),
+        Some("pair") => run_pair(&mut config, &args[1..]).await?,
+        _ => {}
+    }
+
     if config.secrets.is_none() {
         info!("Generating new peer keypair and pre-shared key");
         let psk = generate_psk().context("Couldn't generate pre-shared key")?;
         let keypair = generate_keypair();
         info!("Saving new secrets to config file");
-        let mut file = OpenOptions::new()
-            .write(true)
-            .open("config.json")
-            .await
-            .context("Couldn't open config file for writing")?;
         config.secrets = Some(PeerSecrets { psk, keypair });
-        let config_json =
-            serde_json::to_vec_pretty(&config).context("Couldn't re-serialize the config file")?;
-        file.write(&config_json)
-            .await
-            .context("Couldn't write back to the config file")?;
+        save_config(&config).await?;
     }
 
-    let mut system = System::from_config(config)
+    let mut system = System::from_config(config, Path::new(CONFIG_PATH))
         .await
         .context("Failed to initialize system")?;
 
@@ -0,0 +1,580 @@
+//! Letting new nodes into the swarm without copying its pre-shared key around by hand
+//!
+//! A node in pairing mode shows a one-time code and waits for new nodes on a plain TCP port,
+//! outside of the swarm. The new node proves it knows the code through SPAKE2, which lets both
+//! ends derive the same key out of it without the code being guessable from what goes over the
+//! network, and then gets the pre-shared key of the swarm sealed with that key.
+//!
+//! Along with the proof, the new node signs the SPAKE2 message of the node in pairing mode with
+//! its identity key, so the role it is given is bound to a key it holds rather than to a peer ID
+//! it merely claims.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_bincode::{AsyncBincodeStream, AsyncDestination};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures::{SinkExt, StreamExt};
+use libp2p::{
+    identity::{ed25519, PublicKey},
+    pnet::PreSharedKey,
+    Multiaddr, PeerId,
+};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use spake2::{Ed25519Group, Identity, Password, SPAKE2};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::unbounded_channel,
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::system::psk_parse;
+
+/// Identity both ends of a pairing bind their SPAKE2 messages to
+const SPAKE2_IDENTITY: &[u8] = b"/diodt/pairing/1.0";
+/// Datagram new nodes broadcast to find nodes in pairing mode, which send it back
+const DISCOVERY_PROBE: &[u8] = b"/diodt/pairing/1.0/discover";
+/// Nonce the new node seals its proof with
+const JOINER_NONCE: [u8; 12] = [0; 12];
+/// Nonce the node in pairing mode seals the grant with
+const HOST_NONCE: [u8; 12] = [1; 12];
+/// How long a single pairing attempt may take
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for nodes in pairing mode to answer a discovery probe
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn default_port() -> u16 {
+    4455
+}
+
+fn default_expires_after() -> f64 {
+    300.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingConfig {
+    /// TCP and UDP port to wait for new nodes on while in pairing mode, and to find nodes in
+    /// pairing mode on
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Seconds a pairing code may be used for
+    #[serde(default = "default_expires_after")]
+    pub expires_after: f64,
+    /// Role to give new nodes; the default role of `authorization` if missing
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+impl PairingConfig {
+    pub fn code_lifetime(&self) -> Duration {
+        Duration::from_secs_f64(self.expires_after.max(0.0))
+    }
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            expires_after: default_expires_after(),
+            role: None,
+        }
+    }
+}
+
+/// Pairing code to give to the new node, and until when it may be used
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingTicket {
+    /// Only ever shown on the console of the node in pairing mode
+    #[serde(skip)]
+    pub code: String,
+    /// Role the new node will be given
+    pub role: String,
+    pub port: u16,
+    /// Unix timestamp in milliseconds the code expires at
+    pub expires_at: u64,
+}
+
+/// What a new node gets out of pairing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingGrant {
+    #[serde(with = "psk_parse")]
+    pub psk: PreSharedKey,
+    /// Role the new node was given by the node it paired with
+    pub role: String,
+    /// Addresses of the node it paired with, ending with its peer ID
+    pub addresses: Vec<Multiaddr>,
+}
+
+/// Identity of the new node, sealed with the shared key to prove it was derived from the code
+#[derive(Debug, Serialize, Deserialize)]
+struct JoinerProof {
+    /// Ed25519 public key of the new node
+    public_key: Vec<u8>,
+    /// Signature of the SPAKE2 message of the node in pairing mode with that key
+    signature: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum JoinerMessage {
+    /// SPAKE2 message of the new node
+    Start(Vec<u8>),
+    /// Sealed `JoinerProof`
+    Confirm(Vec<u8>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum HostMessage {
+    /// SPAKE2 message of the node in pairing mode
+    Start(Vec<u8>),
+    /// Grant for the new node, sealed with the shared key
+    Welcome(Vec<u8>),
+    Rejected(String),
+}
+
+type Channel<In, Out> = AsyncBincodeStream<TcpStream, In, Out, AsyncDestination>;
+
+async fn receive<In: DeserializeOwned, Out>(channel: &mut Channel<In, Out>) -> Result<In> {
+    channel
+        .next()
+        .await
+        .context("Connection closed")?
+        .context("Invalid message")
+}
+
+/// Starts a SPAKE2 exchange, returning its state and the message to send to the other end
+fn start_spake2(code: &str) -> (SPAKE2<Ed25519Group>, Vec<u8>) {
+    SPAKE2::<Ed25519Group>::start_symmetric(
+        &Password::new(code.as_bytes()),
+        &Identity::new(SPAKE2_IDENTITY),
+    )
+}
+
+/// Finishes a SPAKE2 exchange, returning a cipher keyed with the shared key
+fn finish_spake2(spake2: SPAKE2<Ed25519Group>, inbound: &[u8]) -> Result<ChaCha20Poly1305> {
+    let key = spake2
+        .finish(inbound)
+        .map_err(|err| anyhow!("Invalid SPAKE2 message: {:?}", err))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Generates a short one-time pairing code
+pub fn generate_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+/// Waits for a new node which knows `code` to pair with, hands it `grant` and returns its peer ID
+///
+/// Gives up once the code expires, and as soon as a node fails to prove it has the code, so that
+/// each code may only be guessed once.
+pub async fn host(config: PairingConfig, code: String, grant: PairingGrant) -> Result<PeerId> {
+    let listener = TcpListener::bind(("0.0.0.0", config.port))
+        .await
+        .with_context(|| format!("Couldn't listen on TCP port {}", config.port))?;
+    let discovery = UdpSocket::bind(("0.0.0.0", config.port))
+        .await
+        .with_context(|| format!("Couldn't listen on UDP port {}", config.port))?;
+
+    host_on(listener, discovery, config.code_lifetime(), code, grant).await
+}
+
+async fn host_on(
+    listener: TcpListener,
+    discovery: UdpSocket,
+    lifetime: Duration,
+    code: String,
+    grant: PairingGrant,
+) -> Result<PeerId> {
+    let grant = bincode::serialize(&grant).context("Couldn't serialize pairing grant")?;
+
+    tokio::time::timeout(
+        lifetime,
+        wait_for_joiner(&listener, &discovery, Arc::from(code), Arc::from(grant)),
+    )
+    .await
+    .map_err(|_| anyhow!("Pairing code expired"))?
+}
+
+/// Exchanges with new nodes still running, aborted once pairing mode ends
+struct Exchanges(Vec<JoinHandle<()>>);
+
+impl Drop for Exchanges {
+    fn drop(&mut self) {
+        for exchange in &self.0 {
+            exchange.abort();
+        }
+    }
+}
+
+/// How an exchange with a new node ended, once its proof of the code was checked
+enum Verdict {
+    /// The proof held, and the new node with this peer ID was handed the grant
+    Paired(PeerId),
+    /// The proof didn't hold, or the exchange failed afterwards, which ends pairing mode
+    Refused(anyhow::Error),
+}
+
+/// Answers discovery probes and pairs with new nodes until one of them succeeds or has its proof
+/// refused
+///
+/// Each new node is served on a task of its own, so that one which connects and stalls doesn't
+/// hold back the others.
+async fn wait_for_joiner(
+    listener: &TcpListener,
+    discovery: &UdpSocket,
+    code: Arc<str>,
+    grant: Arc<[u8]>,
+) -> Result<PeerId> {
+    // Set once a proof is about to be checked, so that a code is only ever guessed once even if
+    // several nodes pair at the same time
+    let guessed = Arc::new(AtomicBool::new(false));
+    let (outcomes_tx, mut outcomes) = unbounded_channel();
+    let mut exchanges = Exchanges(Vec::new());
+    let mut probe = [0_u8; 64];
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, address) = accepted.context("Couldn't accept pairing connection")?;
+                info!("Pairing with {}", address);
+                let (code, grant, guessed) = (code.clone(), grant.clone(), guessed.clone());
+                let outcomes_tx = outcomes_tx.clone();
+                exchanges.0.push(tokio::spawn(async move {
+                    let outcome = serve(stream, &code, &grant, &guessed).await;
+                    let _ = outcomes_tx.send((address, outcome));
+                }));
+            }
+            Some((address, outcome)) = outcomes.recv() => match outcome {
+                Ok(Verdict::Paired(peer)) => return Ok(peer),
+                Ok(Verdict::Refused(err)) => {
+                    return Err(err.context(format!("Couldn't pair with {address}")));
+                }
+                Err(err) => warn!("Couldn't pair with {}: {:#}", address, err),
+            },
+            received = discovery.recv_from(&mut probe) => {
+                let (len, address) = received.context("Couldn't receive discovery probe")?;
+                if probe[..len] == *DISCOVERY_PROBE {
+                    debug!("Answering discovery probe from {}", address);
+                    if let Err(err) = discovery.send_to(DISCOVERY_PROBE, address).await {
+                        warn!("Couldn't answer discovery probe from {}: {}", address, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs the exchange with a new node
+///
+/// Fails if the exchange ends before the proof of the new node is checked, which leaves pairing
+/// mode on for others.
+async fn serve(
+    stream: TcpStream,
+    code: &str,
+    grant: &[u8],
+    guessed: &AtomicBool,
+) -> Result<Verdict> {
+    let deadline = Instant::now() + EXCHANGE_TIMEOUT;
+    let mut channel: Channel<JoinerMessage, HostMessage> =
+        AsyncBincodeStream::from(stream).for_async();
+
+    let (cipher, outbound, proof) = tokio::time::timeout_at(deadline, async {
+        let JoinerMessage::Start(inbound) = receive(&mut channel).await? else {
+            bail!("Unexpected message");
+        };
+        let (spake2, outbound) = start_spake2(code);
+        channel.send(HostMessage::Start(outbound.clone())).await?;
+        let cipher = finish_spake2(spake2, &inbound)?;
+
+        let JoinerMessage::Confirm(proof) = receive(&mut channel).await? else {
+            bail!("Unexpected message");
+        };
+        Ok((cipher, outbound, proof))
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out"))??;
+
+    if guessed.swap(true, Ordering::SeqCst) {
+        let _ = channel
+            .send(HostMessage::Rejected(String::from(
+                "Another node is already pairing",
+            )))
+            .await;
+        bail!("Another node is already pairing");
+    }
+
+    // The code counts as guessed from here on, so any failure has to end pairing mode
+    let welcomed = tokio::time::timeout_at(deadline, async {
+        let public_key = match check_proof(&cipher, &outbound, &proof) {
+            Ok(public_key) => public_key,
+            Err(err) => {
+                let _ = channel
+                    .send(HostMessage::Rejected(format!("{err:#}")))
+                    .await;
+                return Err(err);
+            }
+        };
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&HOST_NONCE), grant)
+            .map_err(|_| anyhow!("Couldn't seal pairing grant"))?;
+        channel.send(HostMessage::Welcome(sealed)).await?;
+        Ok(public_key)
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out"))
+    .and_then(|welcomed| welcomed);
+
+    Ok(match welcomed {
+        Ok(public_key) => Verdict::Paired(PeerId::from(PublicKey::Ed25519(public_key))),
+        Err(err) => Verdict::Refused(err),
+    })
+}
+
+/// Opens the proof of a new node, returning its public key if it holds
+fn check_proof(
+    cipher: &ChaCha20Poly1305,
+    outbound: &[u8],
+    proof: &[u8],
+) -> Result<ed25519::PublicKey> {
+    // Only a node which derived the same key, and so had the same code, can seal anything with it
+    let proof = cipher
+        .decrypt(Nonce::from_slice(&JOINER_NONCE), proof)
+        .map_err(|_| anyhow!("Wrong pairing code"))?;
+    let proof: JoinerProof = bincode::deserialize(&proof).context("Invalid proof")?;
+    let public_key =
+        ed25519::PublicKey::decode(&proof.public_key).map_err(|_| anyhow!("Invalid public key"))?;
+    if !public_key.verify(outbound, &proof.signature) {
+        bail!("Invalid signature");
+    }
+    Ok(public_key)
+}
+
+/// Pairs with the node in pairing mode at `address`, or with the first one found on the local
+/// network on `port`, proving to know `code` and to hold `keypair`
+pub async fn join(
+    code: &str,
+    address: Option<&str>,
+    port: u16,
+    keypair: &ed25519::Keypair,
+) -> Result<PairingGrant> {
+    let stream = match address {
+        Some(address) => TcpStream::connect(address).await,
+        None => TcpStream::connect(discover(port).await?).await,
+    }
+    .context("Couldn't connect to the node to pair with")?;
+
+    tokio::time::timeout(EXCHANGE_TIMEOUT, exchange(stream, code, keypair))
+        .await
+        .map_err(|_| anyhow!("Pairing timed out"))?
+}
+
+/// Finds a node in pairing mode on the local network, by broadcasting a probe it answers to
+async fn discover(port: u16) -> Result<SocketAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))
+        .await
+        .context("Couldn't open discovery socket")?;
+    socket
+        .set_broadcast(true)
+        .context("Couldn't enable broadcast on discovery socket")?;
+    socket
+        .send_to(DISCOVERY_PROBE, ("255.255.255.255", port))
+        .await
+        .context("Couldn't broadcast discovery probe")?;
+
+    let mut answer = [0_u8; 64];
+    let found = tokio::time::timeout(DISCOVERY_TIMEOUT, async {
+        loop {
+            let (len, address) = socket.recv_from(&mut answer).await?;
+            if answer[..len] == *DISCOVERY_PROBE {
+                return Ok::<_, std::io::Error>(address);
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("No node in pairing mode found on the local network"))?
+    .context("Couldn't receive discovery answer")?;
+
+    info!("Found node in pairing mode at {}", found);
+    Ok(found)
+}
+
+async fn exchange(
+    stream: TcpStream,
+    code: &str,
+    keypair: &ed25519::Keypair,
+) -> Result<PairingGrant> {
+    exchange_with(stream, code, |inbound| JoinerProof {
+        public_key: keypair.public().encode().to_vec(),
+        signature: keypair.sign(inbound),
+    })
+    .await
+}
+
+/// Runs the exchange with a node in pairing mode, proving the identity `prove` makes out of its
+/// SPAKE2 message
+async fn exchange_with(
+    stream: TcpStream,
+    code: &str,
+    prove: impl FnOnce(&[u8]) -> JoinerProof,
+) -> Result<PairingGrant> {
+    let mut channel: Channel<HostMessage, JoinerMessage> =
+        AsyncBincodeStream::from(stream).for_async();
+
+    let (spake2, outbound) = start_spake2(code);
+    channel.send(JoinerMessage::Start(outbound)).await?;
+    let inbound = match receive(&mut channel).await? {
+        HostMessage::Start(inbound) => inbound,
+        HostMessage::Rejected(reason) => bail!("Pairing rejected: {}", reason),
+        HostMessage::Welcome(_) => bail!("Unexpected message"),
+    };
+    let cipher = finish_spake2(spake2, &inbound)?;
+
+    let proof = bincode::serialize(&prove(&inbound)).context("Couldn't serialize proof")?;
+    let proof = cipher
+        .encrypt(Nonce::from_slice(&JOINER_NONCE), proof.as_slice())
+        .map_err(|_| anyhow!("Couldn't seal proof"))?;
+    channel.send(JoinerMessage::Confirm(proof)).await?;
+    let sealed = match receive(&mut channel).await? {
+        HostMessage::Welcome(sealed) => sealed,
+        HostMessage::Rejected(reason) => bail!("Pairing rejected: {}", reason),
+        HostMessage::Start(_) => bail!("Unexpected message"),
+    };
+
+    let grant = cipher
+        .decrypt(Nonce::from_slice(&HOST_NONCE), sealed.as_slice())
+        .map_err(|_| anyhow!("Couldn't open pairing grant"))?;
+    bincode::deserialize(&grant).context("Invalid pairing grant")
+}
+
+#[cfg(test)]
+mod tests {
+    use diot_core::device::ActuatorValue;
+
+    use super::*;
+    use crate::{
+        authorization::{Authorization, AuthorizationConfig},
+        hardware::FullActuatorData,
+    };
+
+    /// Starts a node in pairing mode on loopback, returning its address and outcome
+    async fn spawn_host(code: &str) -> (String, JoinHandle<Result<PeerId>>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let discovery = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let grant = PairingGrant {
+            psk: PreSharedKey::new([7; 32]),
+            role: String::from("viewer"),
+            addresses: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+        };
+        let host = tokio::spawn(host_on(
+            listener,
+            discovery,
+            Duration::from_secs(30),
+            code.to_string(),
+            grant,
+        ));
+        (address, host)
+    }
+
+    #[tokio::test]
+    async fn right_code_hands_over_the_grant() {
+        let (address, host) = spawn_host("123456").await;
+        let keypair = ed25519::Keypair::generate();
+
+        let grant = join("123456", Some(&address), 0, &keypair).await.unwrap();
+        assert!(grant.psk == PreSharedKey::new([7; 32]));
+        assert_eq!(grant.role, "viewer");
+        assert_eq!(grant.addresses.len(), 1);
+
+        let peer = host.await.unwrap().unwrap();
+        assert_eq!(peer, PeerId::from(PublicKey::Ed25519(keypair.public())));
+    }
+
+    #[tokio::test]
+    async fn wrong_code_is_rejected_and_ends_pairing() {
+        let (address, host) = spawn_host("123456").await;
+        let keypair = ed25519::Keypair::generate();
+
+        let err = join("654321", Some(&address), 0, &keypair)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Wrong pairing code"));
+
+        let err = host.await.unwrap().unwrap_err();
+        assert!(format!("{err:#}").contains("Wrong pairing code"));
+        assert!(join("123456", Some(&address), 0, &keypair).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stalled_node_does_not_hold_back_others() {
+        let (address, host) = spawn_host("123456").await;
+        let _stalled = TcpStream::connect(&address).await.unwrap();
+        let keypair = ed25519::Keypair::generate();
+
+        let grant = tokio::time::timeout(
+            Duration::from_secs(5),
+            join("123456", Some(&address), 0, &keypair),
+        )
+        .await
+        .expect("pairing waited for the stalled node")
+        .unwrap();
+        assert_eq!(grant.role, "viewer");
+        assert!(host.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn bad_signature_is_rejected_and_ends_pairing() {
+        let (address, host) = spawn_host("123456").await;
+        let (keypair, impostor) = (ed25519::Keypair::generate(), ed25519::Keypair::generate());
+
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let err = exchange_with(stream, "123456", |inbound| JoinerProof {
+            public_key: keypair.public().encode().to_vec(),
+            signature: impostor.sign(inbound),
+        })
+        .await
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("Invalid signature"));
+
+        let err = tokio::time::timeout(Duration::from_secs(5), host)
+            .await
+            .expect("pairing mode stayed on")
+            .unwrap()
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Invalid signature"));
+        assert!(join("123456", Some(&address), 0, &keypair).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn granted_role_limits_the_paired_node() {
+        let (address, host) = spawn_host("123456").await;
+        let keypair = ed25519::Keypair::generate();
+        let grant = join("123456", Some(&address), 0, &keypair).await.unwrap();
+        let peer = host.await.unwrap().unwrap();
+
+        // As the node in pairing mode does once paired, other nodes keeping the default role
+        let mut authorization = Authorization::new(AuthorizationConfig::default()).unwrap();
+        authorization.assign(peer, grant.role);
+        let data = FullActuatorData {
+            device: String::from("relay-1"),
+            actuator_name: String::from("fan"),
+            data: ActuatorValue::Unsigned(1),
+        };
+
+        // The new node is known on the swarm by the peer ID of the key it paired with
+        let swarm_peer = PublicKey::Ed25519(keypair.public()).into_peer_id();
+        assert!(authorization.check(swarm_peer, &data).is_err());
+        assert!(authorization.check(PeerId::random(), &data).is_ok());
+    }
+}
@@ -1,12 +1,198 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use dashmap::DashMap;
 use diot_core::device::{HardwareDeviceType, Measurement};
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-use crate::{hardware::FullSensorData, swarm::PeerData, system::LocalPeerData};
+use crate::{
+    audit::AuditEntry,
+    control::Rule,
+    hardware::FullSensorData,
+    history::now_millis,
+    history::{HistorySample, Rollup},
+    liveness::{Liveness, PeerStatus},
+    swarm::PeerData,
+    system::LocalPeerData,
+};
+
+#[cfg(test)]
+mod conformance;
+mod memory;
+mod sled_backend;
+mod sqlite;
+
+pub use memory::MemoryBackend;
+pub use sled_backend::SledBackend;
+pub use sqlite::SqliteBackend;
+
+fn default_sled_path() -> PathBuf {
+    PathBuf::from("diotd.db")
+}
+
+fn default_sqlite_path() -> PathBuf {
+    PathBuf::from("diotd.sqlite")
+}
+
+fn default_flush_every_ms() -> u64 {
+    1000
+}
+
+/// Where the node keeps its data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// Only in memory, everything being lost on restart
+    Memory,
+    Sled {
+        /// Directory of the database
+        #[serde(default = "default_sled_path")]
+        path: PathBuf,
+        /// How often to make written data durable, in milliseconds
+        #[serde(default = "default_flush_every_ms")]
+        flush_every_ms: u64,
+    },
+    Sqlite {
+        /// Database file
+        #[serde(default = "default_sqlite_path")]
+        path: PathBuf,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Sled {
+            path: default_sled_path(),
+            flush_every_ms: default_flush_every_ms(),
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn open(&self) -> Result<Arc<dyn StorageBackend>> {
+        Ok(match self {
+            Self::Memory => Arc::new(MemoryBackend::new()),
+            Self::Sled {
+                path,
+                flush_every_ms,
+            } => {
+                Arc::new(SledBackend::open(path, *flush_every_ms).with_context(|| {
+                    format!("Couldn't open sled database at {}", path.display())
+                })?)
+            }
+            Self::Sqlite { path } => {
+                Arc::new(SqliteBackend::open(path).with_context(|| {
+                    format!("Couldn't open SQLite database at {}", path.display())
+                })?)
+            }
+        })
+    }
+}
+
+/// Sensor whose measurements make up a time series
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SeriesId {
+    pub node: PeerId,
+    pub device: String,
+    pub sensor_name: String,
+}
+
+impl SeriesId {
+    pub fn new(node: PeerId, device: &str, sensor_name: &str) -> Self {
+        Self {
+            node,
+            device: device.to_string(),
+            sensor_name: sensor_name.to_string(),
+        }
+    }
+
+    pub fn of(sample: &HistorySample) -> Self {
+        Self::new(sample.node, &sample.data.device, &sample.data.sensor_name)
+    }
+}
+
+/// Persistence of everything a node keeps track of: known peers, the latest value and history
+/// of each sensor, the ruleset and the audit log
+///
+/// Time ranges are given as Unix timestamps in milliseconds, the start being inclusive and the
+/// end exclusive.
+pub trait StorageBackend: Send + Sync {
+    fn save_peer(&self, peer: PeerId, data: &PeerData) -> Result<()>;
+    fn load_peers(&self) -> Result<Vec<(PeerId, PeerData)>>;
+
+    /// Appends measurements to the history of their sensors, each also becoming the latest
+    /// value of its sensor
+    fn append_samples(&self, samples: &[HistorySample]) -> Result<()>;
+    /// Stores measurements taken in the past, skipping those already stored for the same sensor
+    /// and time, and returns how many were new
+    ///
+    /// Latest values are only replaced by measurements newer than every stored one.
+    fn insert_samples(&self, samples: &[HistorySample]) -> Result<usize>;
+    /// Returns the last measurement appended for every sensor
+    fn latest_values(&self) -> Result<Vec<(PeerId, FullSensorData)>>;
+    /// Returns every sensor which ever had measurements appended
+    fn series(&self) -> Result<Vec<SeriesId>>;
+    /// Returns up to `limit` measurements of a sensor within a time range, oldest first
+    fn samples(
+        &self,
+        series: &SeriesId,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Measurement)>>;
+    /// Returns when the newest stored measurement of a sensor was taken
+    fn newest_sample_at(&self, series: &SeriesId) -> Result<Option<u64>>;
+    /// Drops the measurements of a sensor taken before the given time
+    fn remove_samples(&self, series: &SeriesId, before: u64) -> Result<()>;
+
+    /// Stores rollups of a sensor over intervals of `every` milliseconds, keyed by the start of
+    /// their interval, and records that the sensor has been rolled up until the given time
+    fn put_rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        rollups: &[(u64, Rollup)],
+        rolled_until: u64,
+    ) -> Result<()>;
+    /// Returns the time until which a sensor was rolled up over intervals of `every`
+    /// milliseconds, or 0 if it never was
+    fn rolled_until(&self, series: &SeriesId, every: u64) -> Result<u64>;
+    /// Returns the rollups of a sensor whose interval starts within a time range, oldest first
+    fn rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<(u64, Rollup)>>;
+    /// Drops the rollups of a sensor whose interval starts before the given time
+    fn remove_rollups(&self, series: &SeriesId, every: u64, before: u64) -> Result<()>;
+
+    fn save_rules(&self, rules: &[Rule]) -> Result<()>;
+    /// Returns the last saved ruleset, if any
+    fn load_rules(&self) -> Result<Option<Vec<Rule>>>;
+
+    fn append_audit(&self, entries: &[AuditEntry]) -> Result<()>;
+    /// Visits the audit entries within a time range, newest first, until `visit` returns false
+    fn visit_audit(
+        &self,
+        from: u64,
+        to: u64,
+        visit: &mut dyn FnMut(AuditEntry) -> bool,
+    ) -> Result<()>;
+    fn audit_len(&self) -> Result<usize>;
+    /// Drops the audit entries older than the given time, returning how many were dropped
+    fn remove_audit_before(&self, before: u64) -> Result<usize>;
+    /// Drops the given number of oldest audit entries, returning how many were dropped
+    fn remove_oldest_audit(&self, count: usize) -> Result<usize>;
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalPeerDevice {
@@ -74,6 +260,37 @@ impl PeerState {
     }
 }
 
+/// A change to the identity of a peer, found when merging an identity update into its state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum PeerChange {
+    /// The peer wasn't known before
+    Joined {
+        name: String,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+    /// A device appeared; devices whose type changed are removed and added back, dropping their
+    /// sensor values
+    DeviceAdded {
+        device: String,
+        device_type: HardwareDeviceType,
+    },
+    DeviceRemoved {
+        device: String,
+    },
+    /// A device seemingly got a new name, keeping its type
+    ///
+    /// Its sensor values aren't carried over, as nothing tells it isn't another device of the
+    /// same type taking the place of the old one.
+    DeviceRenamed {
+        from: String,
+        to: String,
+    },
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct StoredPeerId {
     peer_id: PeerId,
@@ -95,24 +312,152 @@ impl Serialize for StoredPeerId {
     }
 }
 
+fn merge_into(state: &mut PeerState, peer_data: &PeerData) -> Vec<PeerChange> {
+    let mut changes = Vec::new();
+
+    if state.name != peer_data.name {
+        changes.push(PeerChange::Renamed {
+            from: std::mem::replace(&mut state.name, peer_data.name.clone()),
+            to: peer_data.name.clone(),
+        });
+    }
+
+    // Devices gone under their name, and the ones appearing under a new one
+    let missing: Vec<_> = state
+        .devices
+        .iter()
+        .filter(|(device, _)| !peer_data.devices.contains_key(*device))
+        .map(|(device, device_state)| (device.clone(), device_state.device_type))
+        .collect();
+    let appeared: Vec<_> = peer_data
+        .devices
+        .iter()
+        .filter(|(device, _)| !state.devices.contains_key(*device))
+        .map(|(device, remote)| (device.clone(), remote.device_type))
+        .collect();
+
+    // A device is only taken as renamed when it's the only one of its type to go missing and
+    // another is the only one of that type to appear, as otherwise there's no telling which is
+    // which
+    for (from, device_type) in &missing {
+        let missing_of_type = missing.iter().filter(|(_, other)| other == device_type);
+        let mut appeared_of_type = appeared.iter().filter(|(_, other)| other == device_type);
+        if let (1, Some((to, _)), None) = (
+            missing_of_type.count(),
+            appeared_of_type.next(),
+            appeared_of_type.next(),
+        ) {
+            state.devices.remove(from);
+            state
+                .devices
+                .insert(to.clone(), DeviceState::from_device_type(*device_type));
+            changes.push(PeerChange::DeviceRenamed {
+                from: from.clone(),
+                to: to.clone(),
+            });
+        }
+    }
+
+    state.devices.retain(|device, device_state| {
+        let kept = peer_data
+            .devices
+            .get(device)
+            .is_some_and(|remote| remote.device_type == device_state.device_type);
+        if !kept {
+            changes.push(PeerChange::DeviceRemoved {
+                device: device.clone(),
+            });
+        }
+        kept
+    });
+
+    for (device, remote) in &peer_data.devices {
+        if !state.devices.contains_key(device) {
+            state.devices.insert(
+                device.clone(),
+                DeviceState::from_device_type(remote.device_type),
+            );
+            changes.push(PeerChange::DeviceAdded {
+                device: device.clone(),
+                device_type: remote.device_type,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Saves queued identity updates, until the storage is dropped
+fn save_peers(backend: &dyn StorageBackend, mut queue: UnboundedReceiver<(PeerId, PeerData)>) {
+    while let Some((peer, peer_data)) = queue.blocking_recv() {
+        if let Err(err) = backend.save_peer(peer, &peer_data) {
+            error!("Couldn't save identity of {}: {:#}", peer, err);
+        }
+    }
+
+    debug!("Peer writer stopped");
+}
+
 #[derive(Debug, Default, Serialize)]
 pub struct FullSystemState {
     pub peers: DashMap<StoredPeerId, PeerState>,
+    /// Whether each peer is reachable, including those whose identity isn't known yet
+    pub liveness: DashMap<StoredPeerId, Liveness>,
 }
 
 pub struct Storage {
     local_peer_id: PeerId,
     cache: FullSystemState,
+    backend: Arc<dyn StorageBackend>,
+    /// Identity updates waiting to be saved on the peer writer thread
+    peer_writer: UnboundedSender<(PeerId, PeerData)>,
 }
 
 impl Storage {
-    pub fn new(local_peer_id: PeerId, local_peer_data: LocalPeerData) -> Result<Self> {
+    /// Sets up the storage, restoring the peers and latest values kept by the backend
+    pub fn new(
+        local_peer_id: PeerId,
+        local_peer_data: LocalPeerData,
+        backend: Arc<dyn StorageBackend>,
+    ) -> Result<Self> {
+        let (peer_writer, queue) = unbounded_channel();
+        {
+            let backend = backend.clone();
+            thread::Builder::new()
+                .name(String::from("peer-writer"))
+                .spawn(move || save_peers(&*backend, queue))
+                .context("Couldn't spawn peer writer thread")?;
+        }
+
         let storage = Self {
             local_peer_id,
             cache: FullSystemState::default(),
+            backend,
+            peer_writer,
         };
 
-        storage.insert_peer_data(storage.local_peer_id(), local_peer_data.into())?;
+        for (peer, peer_data) in storage
+            .backend
+            .load_peers()
+            .context("Couldn't load stored peers")?
+        {
+            if peer != local_peer_id {
+                storage
+                    .cache
+                    .peers
+                    .insert(peer.into(), PeerState::from_peer_data(peer_data));
+            }
+        }
+        storage.merge_peer_data(storage.local_peer_id(), local_peer_data.into());
+        storage.update_liveness(local_peer_id, |liveness| liveness.heard(now_millis()));
+
+        for (peer, sensor_data) in storage
+            .backend
+            .latest_values()
+            .context("Couldn't load stored sensor values")?
+        {
+            storage.insert_sensor_data(peer, sensor_data);
+        }
 
         Ok(storage)
     }
@@ -121,63 +466,258 @@ impl Storage {
         self.local_peer_id
     }
 
-    pub fn peer_name(&self, peer: PeerId) -> Result<Option<String>> {
-        let peer = match self.cache.peers.get(&peer.into()) {
-            Some(peer) => peer,
-            None => return Ok(None),
-        };
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    pub fn peer_name(&self, peer: PeerId) -> Option<String> {
+        self.cache
+            .peers
+            .get(&peer.into())
+            .map(|peer| peer.name.clone())
+    }
+
+    /// Returns all known peers with the given display name
+    pub fn peers_named(&self, name: &str) -> Vec<PeerId> {
+        self.cache
+            .peers
+            .iter()
+            .filter(|entry| entry.value().name == name)
+            .map(|entry| entry.key().peer_id)
+            .collect()
+    }
+
+    /// Returns the devices of a known peer along with their types
+    pub fn peer_devices(&self, peer: PeerId) -> Option<HashMap<String, HardwareDeviceType>> {
+        self.cache.peers.get(&peer.into()).map(|peer| {
+            peer.devices
+                .iter()
+                .map(|(name, device)| (name.clone(), device.device_type))
+                .collect()
+        })
+    }
 
-        Ok(Some(peer.name.clone()))
+    /// Returns every sensor of every other known peer
+    pub fn remote_sensors(&self) -> HashSet<SeriesId> {
+        let mut sensors = HashSet::new();
+        for entry in &self.cache.peers {
+            let peer = entry.key().peer_id;
+            if peer == self.local_peer_id {
+                continue;
+            }
+            for (device, data) in &entry.value().devices {
+                for (sensor_name, _) in data.device_type.capabilities().sensors {
+                    sensors.insert(SeriesId::new(peer, device, sensor_name));
+                }
+            }
+        }
+        sensors
     }
 
-    #[allow(dead_code)]
     pub fn sensor_data(
         &self,
         peer: PeerId,
         device_name: &str,
         sensor_name: &str,
-    ) -> Result<Option<Measurement>> {
-        let peer = match self.cache.peers.get(&peer.into()) {
-            Some(peer) => peer,
-            None => return Ok(None),
-        };
+    ) -> Option<Measurement> {
+        let peer = self.cache.peers.get(&peer.into())?;
 
-        Ok(peer
-            .devices
+        peer.devices
             .get(device_name)
             .and_then(|device_state| device_state.sensors.get(sensor_name))
-            .map(|sensor_state| sensor_state.current_value.clone()))
+            .map(|sensor_state| sensor_state.current_value.clone())
+    }
+
+    /// Updates the liveness of a peer, returning its new status if it changed
+    ///
+    /// Peers are tracked even before their identity is known.
+    pub fn update_liveness(
+        &self,
+        peer: PeerId,
+        update: impl FnOnce(&mut Liveness) -> Option<PeerStatus>,
+    ) -> Option<PeerStatus> {
+        update(&mut self.cache.liveness.entry(peer.into()).or_default())
+    }
+
+    /// Marks offline the remote peers which haven't been heard from for longer than `timeout`
+    /// milliseconds, returning them
+    pub fn expire_silent_peers(&self, now: u64, timeout: u64) -> Vec<PeerId> {
+        self.cache
+            .liveness
+            .iter_mut()
+            .filter(|entry| entry.key().peer_id != self.local_peer_id)
+            .filter_map(|mut entry| entry.check(now, timeout).map(|_| entry.key().peer_id))
+            .collect()
     }
 
     pub fn full_system_state(&self) -> &FullSystemState {
         &self.cache
     }
 
-    pub fn insert_peer_data(&self, peer: PeerId, peer_data: PeerData) -> Result<()> {
-        self.cache
-            .peers
-            .insert(peer.into(), PeerState::from_peer_data(peer_data));
+    /// Merges an identity update into the state of a peer, keeping the sensor values of the
+    /// devices it still has, and returns what changed
+    ///
+    /// Changed identities are saved on a thread of their own, so that merging never blocks.
+    pub fn merge_peer_data(&self, peer: PeerId, peer_data: PeerData) -> Vec<PeerChange> {
+        let changes = if let Some(mut state) = self.cache.peers.get_mut(&peer.into()) {
+            merge_into(&mut state, &peer_data)
+        } else {
+            let mut changes = vec![PeerChange::Joined {
+                name: peer_data.name.clone(),
+            }];
+            changes.extend(peer_data.devices.iter().map(|(device, remote)| {
+                PeerChange::DeviceAdded {
+                    device: device.clone(),
+                    device_type: remote.device_type,
+                }
+            }));
+            self.cache
+                .peers
+                .insert(peer.into(), PeerState::from_peer_data(peer_data.clone()));
+            changes
+        };
+
+        if !changes.is_empty() && self.peer_writer.send((peer, peer_data)).is_err() {
+            error!(
+                "Peer writer thread is gone, identity of {} won't be saved",
+                peer
+            );
+        }
 
-        Ok(())
+        changes
     }
 
-    pub fn insert_sensor_data(
+    /// Registers a device on a known peer, if it wasn't already, returning whether the peer is known
+    pub fn insert_device(
         &self,
         peer: PeerId,
-        sensor_data: FullSensorData,
-    ) -> Result<Option<()>> {
-        let mut peer = match self.cache.peers.get_mut(&peer.into()) {
-            Some(peer) => peer,
-            None => return Ok(None),
+        device_name: &str,
+        device_type: HardwareDeviceType,
+    ) -> bool {
+        let Some(mut peer) = self.cache.peers.get_mut(&peer.into()) else {
+            return false;
         };
 
-        Ok(peer
-            .devices
+        peer.devices
+            .entry(device_name.to_string())
+            .or_insert_with(|| DeviceState::from_device_type(device_type));
+        true
+    }
+
+    /// Stores the latest value of a sensor, returning whether its peer and device are known
+    pub fn insert_sensor_data(&self, peer: PeerId, sensor_data: FullSensorData) -> bool {
+        let Some(mut peer) = self.cache.peers.get_mut(&peer.into()) else {
+            return false;
+        };
+
+        peer.devices
             .get_mut(&sensor_data.device)
             .map(|device_state| {
                 device_state
                     .sensors
                     .insert(sensor_data.sensor_name, SensorState::new(sensor_data.value));
-            }))
+            })
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_data(devices: &[(&str, HardwareDeviceType)]) -> PeerData {
+        PeerData {
+            name: String::from("greenhouse"),
+            devices: devices
+                .iter()
+                .map(|(device, device_type)| {
+                    let remote = RemotePeerDevice {
+                        device_type: *device_type,
+                    };
+                    (device.to_string(), remote)
+                })
+                .collect(),
+        }
+    }
+
+    fn state_with_value(devices: &[(&str, HardwareDeviceType)]) -> PeerState {
+        let mut state = PeerState::from_peer_data(peer_data(devices));
+        for device in state.devices.values_mut() {
+            device.sensors.insert(
+                String::from("tick"),
+                SensorState::new(Measurement::Integer(42)),
+            );
+        }
+        state
+    }
+
+    #[test]
+    fn renamed_device_starts_without_sensor_values() {
+        let mut state = state_with_value(&[
+            ("timer-1", HardwareDeviceType::Timer),
+            ("logger-1", HardwareDeviceType::Logger),
+        ]);
+
+        let changes = merge_into(
+            &mut state,
+            &peer_data(&[
+                ("timer-kitchen", HardwareDeviceType::Timer),
+                ("logger-1", HardwareDeviceType::Logger),
+            ]),
+        );
+
+        assert!(matches!(
+            &changes[..],
+            [PeerChange::DeviceRenamed { from, to }] if from == "timer-1" && to == "timer-kitchen"
+        ));
+        assert!(!state.devices.contains_key("timer-1"));
+        assert!(state.devices["timer-kitchen"].sensors.is_empty());
+        assert_eq!(
+            state.devices["logger-1"].sensors["tick"].current_value,
+            Measurement::Integer(42)
+        );
+    }
+
+    #[test]
+    fn ambiguous_renames_are_removals_and_additions() {
+        let mut state = state_with_value(&[
+            ("timer-1", HardwareDeviceType::Timer),
+            ("timer-2", HardwareDeviceType::Timer),
+        ]);
+
+        let changes = merge_into(
+            &mut state,
+            &peer_data(&[
+                ("timer-2", HardwareDeviceType::Timer),
+                ("timer-3", HardwareDeviceType::Timer),
+                ("timer-4", HardwareDeviceType::Timer),
+            ]),
+        );
+
+        assert!(changes
+            .iter()
+            .all(|change| !matches!(change, PeerChange::DeviceRenamed { .. })));
+        assert_eq!(changes.len(), 3);
+        assert!(state.devices["timer-3"].sensors.is_empty());
+        assert_eq!(
+            state.devices["timer-2"].sensors["tick"].current_value,
+            Measurement::Integer(42)
+        );
+    }
+
+    #[test]
+    fn devices_changing_type_are_not_renames() {
+        let mut state = state_with_value(&[("timer-1", HardwareDeviceType::Timer)]);
+
+        let changes = merge_into(
+            &mut state,
+            &peer_data(&[("logger-1", HardwareDeviceType::Logger)]),
+        );
+
+        assert!(matches!(
+            &changes[..],
+            [PeerChange::DeviceRemoved { device }, PeerChange::DeviceAdded { .. }]
+                if device == "timer-1"
+        ));
     }
 }
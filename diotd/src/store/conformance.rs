@@ -0,0 +1,357 @@
+//! Behaviour every storage backend must have, checked against each of them
+
+use std::collections::HashMap;
+
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+
+use super::{SeriesId, StorageBackend};
+use crate::{
+    audit::{AuditEntry, AuditEvent},
+    control::Rule,
+    hardware::FullSensorData,
+    history::{HistorySample, Rollup},
+    swarm::PeerData,
+};
+
+fn sample(node: PeerId, sensor_name: &str, at: u64, value: i64) -> HistorySample {
+    HistorySample {
+        at,
+        node,
+        data: FullSensorData {
+            device: String::from("dht11-1"),
+            sensor_name: sensor_name.to_string(),
+            value: Measurement::Integer(value),
+        },
+    }
+}
+
+fn audit_entry(at: u64, rule: &str) -> AuditEntry {
+    AuditEntry {
+        at,
+        event: AuditEvent::RuleFired {
+            rule: rule.to_string(),
+            node: PeerId::random().to_base58(),
+            device: String::from("timer-1"),
+            sensor_name: String::from("tick"),
+            value: Measurement::Signal,
+        },
+    }
+}
+
+fn audit_rules(backend: &dyn StorageBackend, from: u64, to: u64) -> Vec<String> {
+    let mut rules = Vec::new();
+    backend
+        .visit_audit(from, to, &mut |entry| {
+            if let AuditEvent::RuleFired { rule, .. } = entry.event {
+                rules.push(rule);
+            }
+            true
+        })
+        .unwrap();
+    rules
+}
+
+fn peers_are_saved_and_replaced(backend: &dyn StorageBackend) {
+    let peer = PeerId::random();
+    let data = |name: &str| PeerData {
+        name: name.to_string(),
+        devices: HashMap::new(),
+    };
+
+    backend.save_peer(peer, &data("greenhouse")).unwrap();
+    backend.save_peer(peer, &data("kitchen")).unwrap();
+
+    let peers = backend.load_peers().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].0, peer);
+    assert_eq!(peers[0].1.name, "kitchen");
+}
+
+fn samples_are_scanned_by_sensor_and_time(backend: &dyn StorageBackend) {
+    let node = PeerId::random();
+    let other = PeerId::random();
+    backend
+        .append_samples(&[
+            sample(node, "temperature", 3000, 3),
+            sample(node, "temperature", 1000, 1),
+            sample(node, "humidity", 1500, 50),
+            sample(other, "temperature", 2000, 20),
+            sample(node, "temperature", 2000, 2),
+        ])
+        .unwrap();
+
+    let series = SeriesId::new(node, "dht11-1", "temperature");
+    assert_eq!(
+        backend.samples(&series, 0, u64::MAX, usize::MAX).unwrap(),
+        vec![
+            (1000, Measurement::Integer(1)),
+            (2000, Measurement::Integer(2)),
+            (3000, Measurement::Integer(3)),
+        ]
+    );
+    assert_eq!(
+        backend.samples(&series, 1000, 3000, usize::MAX).unwrap(),
+        vec![
+            (1000, Measurement::Integer(1)),
+            (2000, Measurement::Integer(2)),
+        ]
+    );
+    assert!(backend
+        .samples(&series, 3000, 1000, usize::MAX)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        backend.samples(&series, 0, u64::MAX, 2).unwrap(),
+        vec![
+            (1000, Measurement::Integer(1)),
+            (2000, Measurement::Integer(2)),
+        ]
+    );
+
+    let all_series = backend.series().unwrap();
+    assert_eq!(all_series.len(), 3);
+    assert!(all_series.contains(&series));
+}
+
+fn latest_values_follow_appended_samples(backend: &dyn StorageBackend) {
+    let node = PeerId::random();
+    backend
+        .append_samples(&[sample(node, "temperature", 1000, 1)])
+        .unwrap();
+    backend
+        .append_samples(&[sample(node, "temperature", 2000, 2)])
+        .unwrap();
+
+    let series = SeriesId::new(node, "dht11-1", "temperature");
+    assert_eq!(backend.newest_sample_at(&series).unwrap(), Some(2000));
+    let unknown = SeriesId::new(node, "dht11-1", "humidity");
+    assert_eq!(backend.newest_sample_at(&unknown).unwrap(), None);
+
+    let latest = backend.latest_values().unwrap();
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].0, node);
+    assert_eq!(latest[0].1.sensor_name, "temperature");
+    assert_eq!(latest[0].1.value, Measurement::Integer(2));
+}
+
+fn inserted_samples_are_not_duplicated(backend: &dyn StorageBackend) {
+    let node = PeerId::random();
+    backend
+        .append_samples(&[sample(node, "temperature", 2000, 2)])
+        .unwrap();
+
+    let inserted = backend
+        .insert_samples(&[
+            sample(node, "temperature", 1000, 1),
+            sample(node, "temperature", 2000, 20),
+            sample(node, "temperature", 1000, 1),
+        ])
+        .unwrap();
+    assert_eq!(inserted, 1);
+
+    let series = SeriesId::new(node, "dht11-1", "temperature");
+    assert_eq!(
+        backend.samples(&series, 0, u64::MAX, usize::MAX).unwrap(),
+        vec![
+            (1000, Measurement::Integer(1)),
+            (2000, Measurement::Integer(2)),
+        ]
+    );
+    let latest = backend.latest_values().unwrap();
+    assert_eq!(latest[0].1.value, Measurement::Integer(2));
+
+    assert_eq!(
+        backend
+            .insert_samples(&[sample(node, "temperature", 3000, 3)])
+            .unwrap(),
+        1
+    );
+    let latest = backend.latest_values().unwrap();
+    assert_eq!(latest[0].1.value, Measurement::Integer(3));
+}
+
+fn samples_are_removed_before_cutoff(backend: &dyn StorageBackend) {
+    let node = PeerId::random();
+    backend
+        .append_samples(&[
+            sample(node, "temperature", 1000, 1),
+            sample(node, "temperature", 2000, 2),
+            sample(node, "humidity", 1000, 50),
+        ])
+        .unwrap();
+
+    let temperature = SeriesId::new(node, "dht11-1", "temperature");
+    backend.remove_samples(&temperature, 2000).unwrap();
+
+    assert_eq!(
+        backend
+            .samples(&temperature, 0, u64::MAX, usize::MAX)
+            .unwrap(),
+        vec![(2000, Measurement::Integer(2))]
+    );
+    let humidity = SeriesId::new(node, "dht11-1", "humidity");
+    assert_eq!(
+        backend
+            .samples(&humidity, 0, u64::MAX, usize::MAX)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+fn rollups_are_kept_per_interval(backend: &dyn StorageBackend) {
+    let series = SeriesId::new(PeerId::random(), "dht11-1", "temperature");
+    let rollup = |mean| Rollup {
+        count: 2,
+        min: mean - 1.0,
+        mean,
+        max: mean + 1.0,
+    };
+
+    assert_eq!(backend.rolled_until(&series, 60_000).unwrap(), 0);
+
+    backend
+        .put_rollups(
+            &series,
+            60_000,
+            &[(0, rollup(10.0)), (60_000, rollup(11.0))],
+            120_000,
+        )
+        .unwrap();
+    backend
+        .put_rollups(&series, 3_600_000, &[(0, rollup(10.5))], 3_600_000)
+        .unwrap();
+
+    assert_eq!(backend.rolled_until(&series, 60_000).unwrap(), 120_000);
+    assert_eq!(
+        backend.rollups(&series, 60_000, 0, u64::MAX).unwrap(),
+        vec![(0, rollup(10.0)), (60_000, rollup(11.0))]
+    );
+    assert_eq!(
+        backend.rollups(&series, 3_600_000, 0, u64::MAX).unwrap(),
+        vec![(0, rollup(10.5))]
+    );
+
+    backend.remove_rollups(&series, 60_000, 60_000).unwrap();
+    assert_eq!(
+        backend.rollups(&series, 60_000, 0, u64::MAX).unwrap(),
+        vec![(60_000, rollup(11.0))]
+    );
+    assert_eq!(
+        backend
+            .rollups(&series, 3_600_000, 0, u64::MAX)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+fn rules_are_saved_and_replaced(backend: &dyn StorageBackend) {
+    assert!(backend.load_rules().unwrap().is_none());
+
+    let rule: Rule = serde_json::from_value(serde_json::json!({
+        "id": "tick-logger",
+        "sensor": { "device": "timer-1", "sensor_name": "tick" },
+        "on": { "operation": "any" },
+        "then": { "device": "logger-1", "actuator_name": "ticker", "data": "signal" }
+    }))
+    .unwrap();
+
+    backend.save_rules(&[rule.clone(), rule.clone()]).unwrap();
+    backend.save_rules(&[rule]).unwrap();
+
+    let rules = backend.load_rules().unwrap().unwrap();
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0].id, "tick-logger");
+}
+
+fn audit_is_visited_newest_first(backend: &dyn StorageBackend) {
+    backend.append_audit(&[audit_entry(1000, "a")]).unwrap();
+    backend
+        .append_audit(&[
+            audit_entry(3000, "c"),
+            audit_entry(2000, "b"),
+            audit_entry(2000, "b2"),
+        ])
+        .unwrap();
+
+    assert_eq!(backend.audit_len().unwrap(), 4);
+    assert_eq!(audit_rules(backend, 0, u64::MAX), ["c", "b2", "b", "a"]);
+    assert_eq!(audit_rules(backend, 2000, 3000), ["b2", "b"]);
+
+    let mut visited = 0;
+    backend
+        .visit_audit(0, u64::MAX, &mut |_| {
+            visited += 1;
+            visited < 2
+        })
+        .unwrap();
+    assert_eq!(visited, 2);
+}
+
+fn audit_is_trimmed(backend: &dyn StorageBackend) {
+    for (at, rule) in [(1000, "a"), (2000, "b"), (3000, "c"), (4000, "d")] {
+        backend.append_audit(&[audit_entry(at, rule)]).unwrap();
+    }
+
+    assert_eq!(backend.remove_audit_before(2000).unwrap(), 1);
+    assert_eq!(backend.remove_oldest_audit(2).unwrap(), 2);
+    assert_eq!(backend.remove_oldest_audit(5).unwrap(), 1);
+    assert_eq!(backend.audit_len().unwrap(), 0);
+}
+
+macro_rules! conformance_tests {
+    ($backend:ident, $open:expr) => {
+        mod $backend {
+            #[test]
+            fn peers_are_saved_and_replaced() {
+                super::peers_are_saved_and_replaced(&$open);
+            }
+
+            #[test]
+            fn samples_are_scanned_by_sensor_and_time() {
+                super::samples_are_scanned_by_sensor_and_time(&$open);
+            }
+
+            #[test]
+            fn latest_values_follow_appended_samples() {
+                super::latest_values_follow_appended_samples(&$open);
+            }
+
+            #[test]
+            fn inserted_samples_are_not_duplicated() {
+                super::inserted_samples_are_not_duplicated(&$open);
+            }
+
+            #[test]
+            fn samples_are_removed_before_cutoff() {
+                super::samples_are_removed_before_cutoff(&$open);
+            }
+
+            #[test]
+            fn rollups_are_kept_per_interval() {
+                super::rollups_are_kept_per_interval(&$open);
+            }
+
+            #[test]
+            fn rules_are_saved_and_replaced() {
+                super::rules_are_saved_and_replaced(&$open);
+            }
+
+            #[test]
+            fn audit_is_visited_newest_first() {
+                super::audit_is_visited_newest_first(&$open);
+            }
+
+            #[test]
+            fn audit_is_trimmed() {
+                super::audit_is_trimmed(&$open);
+            }
+        }
+    };
+}
+
+conformance_tests!(memory, crate::store::MemoryBackend::new());
+conformance_tests!(sled, crate::store::SledBackend::temporary().unwrap());
+conformance_tests!(sqlite, crate::store::SqliteBackend::in_memory().unwrap());
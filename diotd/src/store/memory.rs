@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::Result;
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+
+use super::{SeriesId, StorageBackend};
+use crate::{
+    audit::AuditEntry,
+    control::Rule,
+    hardware::FullSensorData,
+    history::{HistorySample, Rollup},
+    swarm::PeerData,
+};
+
+#[derive(Default)]
+struct MemoryState {
+    peers: HashMap<PeerId, PeerData>,
+    latest: HashMap<SeriesId, Measurement>,
+    samples: HashMap<SeriesId, BTreeMap<u64, Measurement>>,
+    rollups: HashMap<(SeriesId, u64), BTreeMap<u64, Rollup>>,
+    rolled_until: HashMap<(SeriesId, u64), u64>,
+    rules: Option<Vec<Rule>>,
+    /// Audit entries keyed by their timestamp and order of insertion
+    audit: BTreeMap<(u64, u64), AuditEntry>,
+    next_audit_id: u64,
+}
+
+/// Backend keeping everything in memory, for tests and nodes without persistent storage
+#[derive(Default)]
+pub struct MemoryBackend {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state
+            .lock()
+            .expect("memory storage lock not to be poisoned")
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn save_peer(&self, peer: PeerId, data: &PeerData) -> Result<()> {
+        self.state().peers.insert(peer, data.clone());
+        Ok(())
+    }
+
+    fn load_peers(&self) -> Result<Vec<(PeerId, PeerData)>> {
+        Ok(self
+            .state()
+            .peers
+            .iter()
+            .map(|(peer, data)| (*peer, data.clone()))
+            .collect())
+    }
+
+    fn append_samples(&self, samples: &[HistorySample]) -> Result<()> {
+        let mut state = self.state();
+        for sample in samples {
+            let series = SeriesId::of(sample);
+            state
+                .samples
+                .entry(series.clone())
+                .or_default()
+                .insert(sample.at, sample.data.value.clone());
+            state.latest.insert(series, sample.data.value.clone());
+        }
+        drop(state);
+        Ok(())
+    }
+
+    fn insert_samples(&self, samples: &[HistorySample]) -> Result<usize> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        let mut inserted = 0;
+        for sample in samples {
+            let series = SeriesId::of(sample);
+            let stored = state.samples.entry(series.clone()).or_default();
+            if stored.contains_key(&sample.at) {
+                continue;
+            }
+            stored.insert(sample.at, sample.data.value.clone());
+            inserted += 1;
+
+            if stored.keys().next_back() == Some(&sample.at) {
+                state.latest.insert(series, sample.data.value.clone());
+            }
+        }
+        drop(guard);
+        Ok(inserted)
+    }
+
+    fn latest_values(&self) -> Result<Vec<(PeerId, FullSensorData)>> {
+        Ok(self
+            .state()
+            .latest
+            .iter()
+            .map(|(series, value)| {
+                (
+                    series.node,
+                    FullSensorData {
+                        device: series.device.clone(),
+                        sensor_name: series.sensor_name.clone(),
+                        value: value.clone(),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    fn series(&self) -> Result<Vec<SeriesId>> {
+        Ok(self.state().latest.keys().cloned().collect())
+    }
+
+    fn samples(
+        &self,
+        series: &SeriesId,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Measurement)>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .state()
+            .samples
+            .get(series)
+            .map_or_else(Vec::new, |samples| {
+                samples
+                    .range(from..to)
+                    .take(limit)
+                    .map(|(at, value)| (*at, value.clone()))
+                    .collect()
+            }))
+    }
+
+    fn newest_sample_at(&self, series: &SeriesId) -> Result<Option<u64>> {
+        Ok(self
+            .state()
+            .samples
+            .get(series)
+            .and_then(|samples| samples.keys().next_back().copied()))
+    }
+
+    fn remove_samples(&self, series: &SeriesId, before: u64) -> Result<()> {
+        if let Some(samples) = self.state().samples.get_mut(series) {
+            *samples = samples.split_off(&before);
+        }
+        Ok(())
+    }
+
+    fn put_rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        rollups: &[(u64, Rollup)],
+        rolled_until: u64,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let level = (series.clone(), every);
+        state
+            .rollups
+            .entry(level.clone())
+            .or_default()
+            .extend(rollups.iter().copied());
+        state.rolled_until.insert(level, rolled_until);
+        drop(state);
+        Ok(())
+    }
+
+    fn rolled_until(&self, series: &SeriesId, every: u64) -> Result<u64> {
+        Ok(self
+            .state()
+            .rolled_until
+            .get(&(series.clone(), every))
+            .copied()
+            .unwrap_or(0))
+    }
+
+    fn rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<(u64, Rollup)>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .state()
+            .rollups
+            .get(&(series.clone(), every))
+            .map_or_else(Vec::new, |rollups| {
+                rollups
+                    .range(from..to)
+                    .map(|(start, rollup)| (*start, *rollup))
+                    .collect()
+            }))
+    }
+
+    fn remove_rollups(&self, series: &SeriesId, every: u64, before: u64) -> Result<()> {
+        if let Some(rollups) = self.state().rollups.get_mut(&(series.clone(), every)) {
+            *rollups = rollups.split_off(&before);
+        }
+        Ok(())
+    }
+
+    fn save_rules(&self, rules: &[Rule]) -> Result<()> {
+        self.state().rules = Some(rules.to_vec());
+        Ok(())
+    }
+
+    fn load_rules(&self) -> Result<Option<Vec<Rule>>> {
+        Ok(self.state().rules.clone())
+    }
+
+    fn append_audit(&self, entries: &[AuditEntry]) -> Result<()> {
+        let mut state = self.state();
+        for entry in entries {
+            let id = state.next_audit_id;
+            state.next_audit_id += 1;
+            state.audit.insert((entry.at, id), entry.clone());
+        }
+        drop(state);
+        Ok(())
+    }
+
+    fn visit_audit(
+        &self,
+        from: u64,
+        to: u64,
+        visit: &mut dyn FnMut(AuditEntry) -> bool,
+    ) -> Result<()> {
+        if from >= to {
+            return Ok(());
+        }
+        let state = self.state();
+        for (_, entry) in state.audit.range((from, 0)..(to, 0)).rev() {
+            if !visit(entry.clone()) {
+                break;
+            }
+        }
+        drop(state);
+        Ok(())
+    }
+
+    fn audit_len(&self) -> Result<usize> {
+        Ok(self.state().audit.len())
+    }
+
+    fn remove_audit_before(&self, before: u64) -> Result<usize> {
+        let mut state = self.state();
+        let kept = state.audit.split_off(&(before, 0));
+        Ok(std::mem::replace(&mut state.audit, kept).len())
+    }
+
+    fn remove_oldest_audit(&self, count: usize) -> Result<usize> {
+        let mut state = self.state();
+        let mut dropped = 0;
+        while dropped < count && state.audit.pop_first().is_some() {
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+}
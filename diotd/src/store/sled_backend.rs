@@ -0,0 +1,415 @@
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+
+use super::{SeriesId, StorageBackend};
+use crate::{
+    audit::AuditEntry,
+    control::Rule,
+    hardware::FullSensorData,
+    history::{HistorySample, Rollup},
+    swarm::PeerData,
+};
+
+const PEERS_TREE: &str = "peers";
+const LATEST_TREE: &str = "latest_values";
+const SAMPLES_TREE: &str = "sensor_history";
+const ROLLUPS_TREE: &str = "sensor_rollups";
+const ROLLUP_MARKS_TREE: &str = "sensor_rollup_marks";
+const RULES_TREE: &str = "rules";
+const AUDIT_TREE: &str = "audit";
+
+const RULES_KEY: &[u8] = b"rules";
+
+fn push_segment(key: &mut Vec<u8>, segment: &[u8]) {
+    let len = u16::try_from(segment.len()).unwrap_or(u16::MAX);
+    key.extend_from_slice(&len.to_be_bytes());
+    key.extend_from_slice(&segment[..usize::from(len)]);
+}
+
+/// Common prefix of the keys of every sample of a sensor
+///
+/// Each part is prefixed by its length, so that no series' prefix is a prefix of another's.
+fn series_prefix(series: &SeriesId) -> Vec<u8> {
+    let mut key = Vec::new();
+    push_segment(&mut key, &series.node.to_bytes());
+    push_segment(&mut key, series.device.as_bytes());
+    push_segment(&mut key, series.sensor_name.as_bytes());
+    key
+}
+
+/// Splits the prefix of a series back into its parts
+fn parse_series(prefix: &[u8]) -> Option<SeriesId> {
+    let mut rest = prefix;
+    let mut segments = [&[] as &[u8]; 3];
+    for segment in &mut segments {
+        let (len, tail) = rest.split_first_chunk::<2>()?;
+        let len = usize::from(u16::from_be_bytes(*len));
+        if tail.len() < len {
+            return None;
+        }
+        (*segment, rest) = tail.split_at(len);
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let [node, device, sensor_name] = segments;
+    Some(SeriesId {
+        node: PeerId::from_bytes(node).ok()?,
+        device: String::from_utf8(device.to_vec()).ok()?,
+        sensor_name: String::from_utf8(sensor_name.to_vec()).ok()?,
+    })
+}
+
+/// Appends a big endian number to a prefix, so that keys sharing it are sorted by the number
+fn numbered_key(prefix: &[u8], number: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(prefix.len() + 8);
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(&number.to_be_bytes());
+    key
+}
+
+/// Reads back the number at the end of a key
+fn key_number(key: &[u8]) -> Result<u64> {
+    let number = key
+        .len()
+        .checked_sub(8)
+        .map(|start| &key[start..])
+        .context("Key too short")?;
+    Ok(u64::from_be_bytes(<[u8; 8]>::try_from(number)?))
+}
+
+/// Keys of audit entries are their timestamp followed by a unique ID
+fn audit_key(at: u64, id: u64) -> Vec<u8> {
+    numbered_key(&at.to_be_bytes(), id)
+}
+
+fn remove_range(tree: &sled::Tree, from: Vec<u8>, to: Vec<u8>) -> Result<usize> {
+    if from >= to {
+        return Ok(0);
+    }
+    let mut batch = sled::Batch::default();
+    let mut removed = 0;
+    for key in tree.range(from..to).keys() {
+        batch.remove(key?);
+        removed += 1;
+    }
+    tree.apply_batch(batch)?;
+    Ok(removed)
+}
+
+/// Backend on an embedded sled database
+///
+/// Measurements and rollups are keyed by sensor and time, so that the history of a sensor can be
+/// range scanned.
+pub struct SledBackend {
+    db: sled::Db,
+    peers: sled::Tree,
+    latest: sled::Tree,
+    samples: sled::Tree,
+    rollups: sled::Tree,
+    rollup_marks: sled::Tree,
+    rules: sled::Tree,
+    audit: sled::Tree,
+    /// Number of audit entries, kept around as counting them on sled is slow
+    audit_len: AtomicUsize,
+}
+
+impl SledBackend {
+    pub fn open(path: &Path, flush_every_ms: u64) -> Result<Self> {
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(Some(flush_every_ms))
+            .open()?;
+        Self::from_db(db)
+    }
+
+    /// Opens a database which is deleted once dropped
+    #[cfg(test)]
+    pub fn temporary() -> Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self> {
+        let audit = db.open_tree(AUDIT_TREE)?;
+        let audit_len = AtomicUsize::new(audit.len());
+
+        Ok(Self {
+            peers: db.open_tree(PEERS_TREE)?,
+            latest: db.open_tree(LATEST_TREE)?,
+            samples: db.open_tree(SAMPLES_TREE)?,
+            rollups: db.open_tree(ROLLUPS_TREE)?,
+            rollup_marks: db.open_tree(ROLLUP_MARKS_TREE)?,
+            rules: db.open_tree(RULES_TREE)?,
+            audit,
+            audit_len,
+            db,
+        })
+    }
+}
+
+impl StorageBackend for SledBackend {
+    fn save_peer(&self, peer: PeerId, data: &PeerData) -> Result<()> {
+        let value = serde_json::to_vec(data).context("Couldn't serialize peer")?;
+        self.peers.insert(peer.to_bytes(), value)?;
+        Ok(())
+    }
+
+    fn load_peers(&self) -> Result<Vec<(PeerId, PeerData)>> {
+        let mut peers = Vec::new();
+        for item in &self.peers {
+            let (key, value) = item?;
+            let peer = PeerId::from_bytes(&key).context("Invalid stored peer ID")?;
+            let data = serde_json::from_slice(&value).context("Couldn't parse stored peer")?;
+            peers.push((peer, data));
+        }
+        Ok(peers)
+    }
+
+    fn append_samples(&self, samples: &[HistorySample]) -> Result<()> {
+        let mut history = sled::Batch::default();
+        let mut latest = sled::Batch::default();
+        for sample in samples {
+            let prefix = series_prefix(&SeriesId::of(sample));
+            let value =
+                bincode::serialize(&sample.data.value).context("Couldn't serialize measurement")?;
+            history.insert(numbered_key(&prefix, sample.at), value.clone());
+            latest.insert(prefix, value);
+        }
+        self.samples.apply_batch(history)?;
+        self.latest.apply_batch(latest)?;
+        Ok(())
+    }
+
+    fn insert_samples(&self, samples: &[HistorySample]) -> Result<usize> {
+        let mut inserted = 0;
+        let mut touched = HashSet::new();
+        for sample in samples {
+            let prefix = series_prefix(&SeriesId::of(sample));
+            let value =
+                bincode::serialize(&sample.data.value).context("Couldn't serialize measurement")?;
+            let swapped = self.samples.compare_and_swap(
+                numbered_key(&prefix, sample.at),
+                None::<&[u8]>,
+                Some(value),
+            )?;
+            if swapped.is_ok() {
+                inserted += 1;
+                touched.insert(prefix);
+            }
+        }
+
+        // The latest value of a sensor is always its newest sample
+        for prefix in touched {
+            if let Some((_, value)) = self.samples.scan_prefix(&prefix).next_back().transpose()? {
+                self.latest.insert(prefix, value)?;
+            }
+        }
+        Ok(inserted)
+    }
+
+    fn latest_values(&self) -> Result<Vec<(PeerId, FullSensorData)>> {
+        let mut values = Vec::new();
+        for item in &self.latest {
+            let (key, value) = item?;
+            let series = parse_series(&key).context("Invalid stored sensor key")?;
+            let value =
+                bincode::deserialize(&value).context("Couldn't parse stored measurement")?;
+            values.push((
+                series.node,
+                FullSensorData {
+                    device: series.device,
+                    sensor_name: series.sensor_name,
+                    value,
+                },
+            ));
+        }
+        Ok(values)
+    }
+
+    fn series(&self) -> Result<Vec<SeriesId>> {
+        let mut series = Vec::new();
+        for key in self.latest.iter().keys() {
+            series.push(parse_series(&key?).context("Invalid stored sensor key")?);
+        }
+        Ok(series)
+    }
+
+    fn samples(
+        &self,
+        series: &SeriesId,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Measurement)>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        let prefix = series_prefix(series);
+        let mut samples = Vec::new();
+        for item in self
+            .samples
+            .range(numbered_key(&prefix, from)..numbered_key(&prefix, to))
+            .take(limit)
+        {
+            let (key, value) = item?;
+            let value =
+                bincode::deserialize(&value).context("Couldn't parse stored measurement")?;
+            samples.push((key_number(&key)?, value));
+        }
+        Ok(samples)
+    }
+
+    fn newest_sample_at(&self, series: &SeriesId) -> Result<Option<u64>> {
+        self.samples
+            .scan_prefix(series_prefix(series))
+            .keys()
+            .next_back()
+            .transpose()?
+            .map(|key| key_number(&key))
+            .transpose()
+    }
+
+    fn remove_samples(&self, series: &SeriesId, before: u64) -> Result<()> {
+        let prefix = series_prefix(series);
+        remove_range(
+            &self.samples,
+            numbered_key(&prefix, 0),
+            numbered_key(&prefix, before),
+        )?;
+        Ok(())
+    }
+
+    fn put_rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        rollups: &[(u64, Rollup)],
+        rolled_until: u64,
+    ) -> Result<()> {
+        let level = numbered_key(&series_prefix(series), every);
+        let mut batch = sled::Batch::default();
+        for (start, rollup) in rollups {
+            let value = bincode::serialize(rollup).context("Couldn't serialize rollup")?;
+            batch.insert(numbered_key(&level, *start), value);
+        }
+        self.rollups.apply_batch(batch)?;
+        self.rollup_marks
+            .insert(level, &rolled_until.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn rolled_until(&self, series: &SeriesId, every: u64) -> Result<u64> {
+        let level = numbered_key(&series_prefix(series), every);
+        self.rollup_marks
+            .get(level)?
+            .map_or(Ok(0), |mark| key_number(&mark))
+    }
+
+    fn rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<(u64, Rollup)>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        let level = numbered_key(&series_prefix(series), every);
+        let mut rollups = Vec::new();
+        for item in self
+            .rollups
+            .range(numbered_key(&level, from)..numbered_key(&level, to))
+        {
+            let (key, value) = item?;
+            let rollup = bincode::deserialize(&value).context("Couldn't parse stored rollup")?;
+            rollups.push((key_number(&key)?, rollup));
+        }
+        Ok(rollups)
+    }
+
+    fn remove_rollups(&self, series: &SeriesId, every: u64, before: u64) -> Result<()> {
+        let level = numbered_key(&series_prefix(series), every);
+        remove_range(
+            &self.rollups,
+            numbered_key(&level, 0),
+            numbered_key(&level, before),
+        )?;
+        Ok(())
+    }
+
+    fn save_rules(&self, rules: &[Rule]) -> Result<()> {
+        let value = serde_json::to_vec(rules).context("Couldn't serialize ruleset")?;
+        self.rules.insert(RULES_KEY, value)?;
+        Ok(())
+    }
+
+    fn load_rules(&self) -> Result<Option<Vec<Rule>>> {
+        self.rules
+            .get(RULES_KEY)?
+            .map(|value| serde_json::from_slice(&value).context("Couldn't parse stored ruleset"))
+            .transpose()
+    }
+
+    fn append_audit(&self, entries: &[AuditEntry]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for entry in entries {
+            let id = self
+                .db
+                .generate_id()
+                .context("Couldn't generate audit entry ID")?;
+            let value = serde_json::to_vec(entry).context("Couldn't serialize audit entry")?;
+            batch.insert(audit_key(entry.at, id), value);
+        }
+        self.audit.apply_batch(batch)?;
+        self.audit_len.fetch_add(entries.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn visit_audit(
+        &self,
+        from: u64,
+        to: u64,
+        visit: &mut dyn FnMut(AuditEntry) -> bool,
+    ) -> Result<()> {
+        if from >= to {
+            return Ok(());
+        }
+        for item in self.audit.range(audit_key(from, 0)..audit_key(to, 0)).rev() {
+            let (_, value) = item?;
+            let entry = serde_json::from_slice(&value).context("Couldn't parse audit entry")?;
+            if !visit(entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn audit_len(&self) -> Result<usize> {
+        Ok(self.audit_len.load(Ordering::Relaxed))
+    }
+
+    fn remove_audit_before(&self, before: u64) -> Result<usize> {
+        let removed = remove_range(&self.audit, audit_key(0, 0), audit_key(before, 0))?;
+        self.audit_len.fetch_sub(removed, Ordering::Relaxed);
+        Ok(removed)
+    }
+
+    fn remove_oldest_audit(&self, count: usize) -> Result<usize> {
+        let mut removed = 0;
+        while removed < count && self.audit.pop_min()?.is_some() {
+            removed += 1;
+        }
+        self.audit_len.fetch_sub(removed, Ordering::Relaxed);
+        Ok(removed)
+    }
+}
@@ -0,0 +1,517 @@
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{Context, Result};
+use diot_core::device::Measurement;
+use libp2p::PeerId;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{SeriesId, StorageBackend};
+use crate::{
+    audit::AuditEntry,
+    control::Rule,
+    hardware::FullSensorData,
+    history::{HistorySample, Rollup},
+    swarm::PeerData,
+};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+
+    CREATE TABLE IF NOT EXISTS peers (
+        peer BLOB PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS latest_values (
+        node BLOB NOT NULL,
+        device TEXT NOT NULL,
+        sensor_name TEXT NOT NULL,
+        value BLOB NOT NULL,
+        PRIMARY KEY (node, device, sensor_name)
+    ) WITHOUT ROWID;
+
+    CREATE TABLE IF NOT EXISTS samples (
+        node BLOB NOT NULL,
+        device TEXT NOT NULL,
+        sensor_name TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        value BLOB NOT NULL,
+        PRIMARY KEY (node, device, sensor_name, at)
+    ) WITHOUT ROWID;
+
+    CREATE TABLE IF NOT EXISTS rollups (
+        node BLOB NOT NULL,
+        device TEXT NOT NULL,
+        sensor_name TEXT NOT NULL,
+        every INTEGER NOT NULL,
+        start INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        min REAL NOT NULL,
+        mean REAL NOT NULL,
+        max REAL NOT NULL,
+        PRIMARY KEY (node, device, sensor_name, every, start)
+    ) WITHOUT ROWID;
+
+    CREATE TABLE IF NOT EXISTS rollup_marks (
+        node BLOB NOT NULL,
+        device TEXT NOT NULL,
+        sensor_name TEXT NOT NULL,
+        every INTEGER NOT NULL,
+        rolled_until INTEGER NOT NULL,
+        PRIMARY KEY (node, device, sensor_name, every)
+    ) WITHOUT ROWID;
+
+    CREATE TABLE IF NOT EXISTS rules (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        rules TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS audit (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at INTEGER NOT NULL,
+        entry TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS audit_by_time ON audit (at, id);
+";
+
+/// `SQLite` only has signed integers; timestamps past its range are clamped
+fn to_sql(number: u64) -> i64 {
+    i64::try_from(number).unwrap_or(i64::MAX)
+}
+
+fn from_sql(number: i64) -> u64 {
+    u64::try_from(number).unwrap_or(0)
+}
+
+/// Backend on an `SQLite` database file
+pub struct SqliteBackend {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Opens a database which only lives in memory
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)
+            .context("Couldn't set up database schema")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .expect("SQLite connection lock not to be poisoned")
+    }
+}
+
+// Statements and transactions borrow the connection, so its lock can't be let go any sooner
+#[allow(clippy::significant_drop_tightening)]
+impl StorageBackend for SqliteBackend {
+    fn save_peer(&self, peer: PeerId, data: &PeerData) -> Result<()> {
+        let data = serde_json::to_string(data).context("Couldn't serialize peer")?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO peers (peer, data) VALUES (?1, ?2)",
+            params![peer.to_bytes(), data],
+        )?;
+        Ok(())
+    }
+
+    fn load_peers(&self) -> Result<Vec<(PeerId, PeerData)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT peer, data FROM peers")?;
+        let mut rows = stmt.query([])?;
+
+        let mut peers = Vec::new();
+        while let Some(row) = rows.next()? {
+            let peer: Vec<u8> = row.get(0)?;
+            let data: String = row.get(1)?;
+            peers.push((
+                PeerId::from_bytes(&peer).context("Invalid stored peer ID")?,
+                serde_json::from_str(&data).context("Couldn't parse stored peer")?,
+            ));
+        }
+        Ok(peers)
+    }
+
+    fn append_samples(&self, samples: &[HistorySample]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut insert_sample = tx.prepare_cached(
+                "INSERT OR REPLACE INTO samples (node, device, sensor_name, at, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let mut insert_latest = tx.prepare_cached(
+                "INSERT OR REPLACE INTO latest_values (node, device, sensor_name, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for sample in samples {
+                let node = sample.node.to_bytes();
+                let value = bincode::serialize(&sample.data.value)
+                    .context("Couldn't serialize measurement")?;
+                insert_sample.execute(params![
+                    node,
+                    sample.data.device,
+                    sample.data.sensor_name,
+                    to_sql(sample.at),
+                    value,
+                ])?;
+                insert_latest.execute(params![
+                    node,
+                    sample.data.device,
+                    sample.data.sensor_name,
+                    value,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn insert_samples(&self, samples: &[HistorySample]) -> Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut insert_sample = tx.prepare_cached(
+                "INSERT OR IGNORE INTO samples (node, device, sensor_name, at, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let mut touched = HashSet::new();
+            for sample in samples {
+                let value = bincode::serialize(&sample.data.value)
+                    .context("Couldn't serialize measurement")?;
+                let changed = insert_sample.execute(params![
+                    sample.node.to_bytes(),
+                    sample.data.device,
+                    sample.data.sensor_name,
+                    to_sql(sample.at),
+                    value,
+                ])?;
+                if changed > 0 {
+                    inserted += changed;
+                    touched.insert(SeriesId::of(sample));
+                }
+            }
+
+            // The latest value of a sensor is always its newest sample
+            let mut update_latest = tx.prepare_cached(
+                "INSERT OR REPLACE INTO latest_values (node, device, sensor_name, value)
+                 SELECT node, device, sensor_name, value FROM samples
+                 WHERE node = ?1 AND device = ?2 AND sensor_name = ?3
+                 ORDER BY at DESC LIMIT 1",
+            )?;
+            for series in touched {
+                update_latest.execute(params![
+                    series.node.to_bytes(),
+                    series.device,
+                    series.sensor_name,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    fn latest_values(&self) -> Result<Vec<(PeerId, FullSensorData)>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT node, device, sensor_name, value FROM latest_values")?;
+        let mut rows = stmt.query([])?;
+
+        let mut values = Vec::new();
+        while let Some(row) = rows.next()? {
+            let node: Vec<u8> = row.get(0)?;
+            let value: Vec<u8> = row.get(3)?;
+            values.push((
+                PeerId::from_bytes(&node).context("Invalid stored peer ID")?,
+                FullSensorData {
+                    device: row.get(1)?,
+                    sensor_name: row.get(2)?,
+                    value: bincode::deserialize(&value)
+                        .context("Couldn't parse stored measurement")?,
+                },
+            ));
+        }
+        Ok(values)
+    }
+
+    fn series(&self) -> Result<Vec<SeriesId>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT node, device, sensor_name FROM latest_values")?;
+        let mut rows = stmt.query([])?;
+
+        let mut series = Vec::new();
+        while let Some(row) = rows.next()? {
+            let node: Vec<u8> = row.get(0)?;
+            series.push(SeriesId {
+                node: PeerId::from_bytes(&node).context("Invalid stored peer ID")?,
+                device: row.get(1)?,
+                sensor_name: row.get(2)?,
+            });
+        }
+        Ok(series)
+    }
+
+    fn samples(
+        &self,
+        series: &SeriesId,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Measurement)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT at, value FROM samples
+             WHERE node = ?1 AND device = ?2 AND sensor_name = ?3 AND at >= ?4 AND at < ?5
+             ORDER BY at LIMIT ?6",
+        )?;
+        let mut rows = stmt.query(params![
+            series.node.to_bytes(),
+            series.device,
+            series.sensor_name,
+            to_sql(from),
+            to_sql(to),
+            i64::try_from(limit).unwrap_or(i64::MAX),
+        ])?;
+
+        let mut samples = Vec::new();
+        while let Some(row) = rows.next()? {
+            let value: Vec<u8> = row.get(1)?;
+            samples.push((
+                from_sql(row.get(0)?),
+                bincode::deserialize(&value).context("Couldn't parse stored measurement")?,
+            ));
+        }
+        Ok(samples)
+    }
+
+    fn newest_sample_at(&self, series: &SeriesId) -> Result<Option<u64>> {
+        let newest: Option<i64> = self.conn().query_row(
+            "SELECT MAX(at) FROM samples WHERE node = ?1 AND device = ?2 AND sensor_name = ?3",
+            params![series.node.to_bytes(), series.device, series.sensor_name],
+            |row| row.get(0),
+        )?;
+        Ok(newest.map(from_sql))
+    }
+
+    fn remove_samples(&self, series: &SeriesId, before: u64) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM samples WHERE node = ?1 AND device = ?2 AND sensor_name = ?3 AND at < ?4",
+            params![
+                series.node.to_bytes(),
+                series.device,
+                series.sensor_name,
+                to_sql(before),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn put_rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        rollups: &[(u64, Rollup)],
+        rolled_until: u64,
+    ) -> Result<()> {
+        let node = series.node.to_bytes();
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO rollups
+                 (node, device, sensor_name, every, start, count, min, mean, max)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for (start, rollup) in rollups {
+                insert.execute(params![
+                    node,
+                    series.device,
+                    series.sensor_name,
+                    to_sql(every),
+                    to_sql(*start),
+                    to_sql(rollup.count),
+                    rollup.min,
+                    rollup.mean,
+                    rollup.max,
+                ])?;
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO rollup_marks (node, device, sensor_name, every, rolled_until)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                node,
+                series.device,
+                series.sensor_name,
+                to_sql(every),
+                to_sql(rolled_until),
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn rolled_until(&self, series: &SeriesId, every: u64) -> Result<u64> {
+        let rolled_until: Option<i64> = self
+            .conn()
+            .query_row(
+                "SELECT rolled_until FROM rollup_marks
+                 WHERE node = ?1 AND device = ?2 AND sensor_name = ?3 AND every = ?4",
+                params![
+                    series.node.to_bytes(),
+                    series.device,
+                    series.sensor_name,
+                    to_sql(every),
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(rolled_until.map_or(0, from_sql))
+    }
+
+    fn rollups(
+        &self,
+        series: &SeriesId,
+        every: u64,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<(u64, Rollup)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT start, count, min, mean, max FROM rollups
+             WHERE node = ?1 AND device = ?2 AND sensor_name = ?3 AND every = ?4
+               AND start >= ?5 AND start < ?6
+             ORDER BY start",
+        )?;
+        let mut rows = stmt.query(params![
+            series.node.to_bytes(),
+            series.device,
+            series.sensor_name,
+            to_sql(every),
+            to_sql(from),
+            to_sql(to),
+        ])?;
+
+        let mut rollups = Vec::new();
+        while let Some(row) = rows.next()? {
+            rollups.push((
+                from_sql(row.get(0)?),
+                Rollup {
+                    count: from_sql(row.get(1)?),
+                    min: row.get(2)?,
+                    mean: row.get(3)?,
+                    max: row.get(4)?,
+                },
+            ));
+        }
+        Ok(rollups)
+    }
+
+    fn remove_rollups(&self, series: &SeriesId, every: u64, before: u64) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM rollups
+             WHERE node = ?1 AND device = ?2 AND sensor_name = ?3 AND every = ?4 AND start < ?5",
+            params![
+                series.node.to_bytes(),
+                series.device,
+                series.sensor_name,
+                to_sql(every),
+                to_sql(before),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn save_rules(&self, rules: &[Rule]) -> Result<()> {
+        let rules = serde_json::to_string(rules).context("Couldn't serialize ruleset")?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO rules (id, rules) VALUES (0, ?1)",
+            params![rules],
+        )?;
+        Ok(())
+    }
+
+    fn load_rules(&self) -> Result<Option<Vec<Rule>>> {
+        let rules: Option<String> = self
+            .conn()
+            .query_row("SELECT rules FROM rules WHERE id = 0", [], |row| row.get(0))
+            .optional()?;
+        rules
+            .map(|rules| serde_json::from_str(&rules).context("Couldn't parse stored ruleset"))
+            .transpose()
+    }
+
+    fn append_audit(&self, entries: &[AuditEntry]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached("INSERT INTO audit (at, entry) VALUES (?1, ?2)")?;
+            for entry in entries {
+                let value =
+                    serde_json::to_string(entry).context("Couldn't serialize audit entry")?;
+                insert.execute(params![to_sql(entry.at), value])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn visit_audit(
+        &self,
+        from: u64,
+        to: u64,
+        visit: &mut dyn FnMut(AuditEntry) -> bool,
+    ) -> Result<()> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT entry FROM audit WHERE at >= ?1 AND at < ?2 ORDER BY at DESC, id DESC",
+        )?;
+        let mut rows = stmt.query(params![to_sql(from), to_sql(to)])?;
+
+        while let Some(row) = rows.next()? {
+            let entry: String = row.get(0)?;
+            let entry = serde_json::from_str(&entry).context("Couldn't parse audit entry")?;
+            if !visit(entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn audit_len(&self) -> Result<usize> {
+        let len: i64 = self
+            .conn()
+            .query_row("SELECT COUNT(*) FROM audit", [], |row| row.get(0))?;
+        Ok(usize::try_from(len).unwrap_or(0))
+    }
+
+    fn remove_audit_before(&self, before: u64) -> Result<usize> {
+        Ok(self
+            .conn()
+            .execute("DELETE FROM audit WHERE at < ?1", params![to_sql(before)])?)
+    }
+
+    fn remove_oldest_audit(&self, count: usize) -> Result<usize> {
+        Ok(self.conn().execute(
+            "DELETE FROM audit WHERE id IN (SELECT id FROM audit ORDER BY at, id LIMIT ?1)",
+            params![i64::try_from(count).unwrap_or(i64::MAX)],
+        )?)
+    }
+}
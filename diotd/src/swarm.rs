@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
     task::{Context as TaskContext, Poll},
     time::Duration,
@@ -12,11 +12,13 @@ use diot_core::device::ActuationResult;
 use futures::{sink::SinkExt, stream::StreamExt};
 
 use crate::{
+    control::{ModeState, UniversalSensorIdentifier},
     hardware::{FullActuatorData, FullSensorData},
-    store::RemotePeerDevice,
+    history::{CatchUpPage, HistoryPage, HistoryQuery},
+    store::{RemotePeerDevice, SeriesId},
     system::{LocalPeerData, PeerSecrets},
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use libp2p::{
     core::{transport::Boxed as BoxedTransport, ProtocolName},
@@ -24,19 +26,23 @@ use libp2p::{
         error::PublishError, Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic,
         MessageAuthenticity, ValidationMode,
     },
+    identify::{Identify, IdentifyEvent},
     identity::{ed25519::Keypair as Ed25519Keypair, Keypair},
+    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent, QueryResult},
+    multiaddr::Protocol,
     ping::{Ping, PingConfig, PingEvent},
     pnet::{PnetConfig, PreSharedKey},
     swarm::{
-        ExpandedSwarm, IntoProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
-        NetworkBehaviourEventProcess, PollParameters, ProtocolsHandler, SwarmBuilder,
+        toggle::Toggle, ExpandedSwarm, IntoProtocolsHandler, NetworkBehaviour,
+        NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters, ProtocolsHandler,
+        SwarmBuilder,
     },
-    NetworkBehaviour, PeerId, Transport,
+    Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport,
 };
 use libp2p_mdns::{Mdns, MdnsEvent};
 use libp2p_request_response::{
-    RequestId, RequestResponse, RequestResponseCodec, RequestResponseConfig, RequestResponseEvent,
-    RequestResponseMessage, ResponseChannel,
+    OutboundFailure, RequestId, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    RequestResponseEvent, RequestResponseMessage, ResponseChannel,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -58,10 +64,154 @@ impl From<LocalPeerData> for PeerData {
     }
 }
 
+/// Protocol of the Kademlia DHT nodes find each other through
+const KADEMLIA_PROTOCOL: &[u8] = b"/diodt/kad/1.0";
+/// Time between walks of the DHT looking for new nodes
+const KADEMLIA_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(300);
+
+fn default_mdns() -> bool {
+    true
+}
+
+fn default_listen() -> Vec<Multiaddr> {
+    vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid address")]
+}
+
+/// Reads lists of addresses, taking everything after `/unix/` as the path of a Unix-domain socket
+/// (up to a trailing `/p2p/<peer ID>`), which `Multiaddr` would cut at its first slash
+mod multiaddr_list_parse {
+    use libp2p::{multiaddr::Protocol, Multiaddr};
+    use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    fn parse(address: &str) -> Result<Multiaddr, String> {
+        let Some(path) = address.strip_prefix("/unix/") else {
+            return address
+                .parse()
+                .map_err(|err| format!("Invalid address \"{address}\": {err}"));
+        };
+        let (path, peer_id) = match path.rsplit_once("/p2p/") {
+            Some((path, peer_id)) => (path, Some(peer_id)),
+            None => (path, None),
+        };
+
+        let mut address = Multiaddr::empty().with(Protocol::Unix(path.to_string().into()));
+        if let Some(peer_id) = peer_id {
+            let peer_id: libp2p::PeerId = peer_id
+                .parse()
+                .map_err(|_| format!("Invalid peer ID \"{peer_id}\""))?;
+            address.push(Protocol::P2p(peer_id.into()));
+        }
+        Ok(address)
+    }
+
+    pub fn serialize<S>(data: &[Multiaddr], ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = ser.serialize_seq(Some(data.len()))?;
+        for address in data {
+            seq.serialize_element(&address.to_string())?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D>(de: D) -> Result<Vec<Multiaddr>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw_data: Vec<String> = Deserialize::deserialize(de)?;
+        raw_data
+            .iter()
+            .map(|address| parse(address).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Where to listen for other nodes, and how to find them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// Addresses to listen on: TCP over IPv4 or IPv6, or Unix-domain sockets
+    #[serde(default = "default_listen", with = "multiaddr_list_parse")]
+    pub listen: Vec<Multiaddr>,
+    /// Addresses other nodes can reach this one on besides those it listens on, e.g. through
+    /// port forwarding
+    #[serde(default, with = "multiaddr_list_parse")]
+    pub external: Vec<Multiaddr>,
+    /// Whether to look for nodes on the local network through mDNS
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+    /// Addresses of nodes to join the swarm through, e.g. `/ip4/10.0.2.15/tcp/4001/p2p/<peer ID>`
+    #[serde(default, with = "multiaddr_list_parse")]
+    pub bootstrap: Vec<Multiaddr>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen: default_listen(),
+            external: Vec::new(),
+            mdns: default_mdns(),
+            bootstrap: Vec::new(),
+        }
+    }
+}
+
+/// Splits the peer ID off the end of an address, if it has one
+fn split_peer_id(address: &Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    let mut address = address.clone();
+    match address.pop()? {
+        Protocol::P2p(hash) => Some((PeerId::from_multihash(hash).ok()?, address)),
+        _ => None,
+    }
+}
+
+/// Explains why a request to another node got no response
+fn describe_failure(peer: &PeerId, error: &OutboundFailure) -> String {
+    match error {
+        OutboundFailure::DialFailure => format!("Couldn't connect to peer {peer}"),
+        OutboundFailure::Timeout => format!("Peer {peer} didn't respond in time"),
+        OutboundFailure::ConnectionClosed => {
+            format!("Connection to peer {peer} closed before it responded")
+        }
+        OutboundFailure::UnsupportedProtocols => {
+            format!("Peer {peer} doesn't support the request")
+        }
+    }
+}
+
+/// Topic every node announces its identity and mode on, and listens to
+const NODES_TOPIC: &str = "diotd/nodes";
+/// Topic nodes announced their identity and published every measurement on before
+/// `NODES_TOPIC` and sensor topics, still listened to and announced on so that nodes which
+/// weren't upgraded yet keep seeing this one, and the other way around
+const LEGACY_TOPIC: &str = "default";
+
+/// Topic the measurements of a sensor are published on by its node
+fn sensor_topic(sensor: &SeriesId) -> IdentTopic {
+    IdentTopic::new(format!(
+        "diotd/sensors/{}/{}/{}",
+        sensor.node.to_base58(),
+        sensor.device,
+        sensor.sensor_name
+    ))
+}
+
+/// Which sensors of other nodes to receive the measurements of
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscriptionsConfig {
+    /// Receive the measurements of every sensor of every node, as dashboards do
+    #[serde(default)]
+    pub all: bool,
+    /// Sensors to receive the measurements of besides those rules and scripts listen to
+    #[serde(default)]
+    pub sensors: Vec<UniversalSensorIdentifier>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DiotdBroadcast {
     Identity(PeerData),
     SensorData(FullSensorData),
+    Mode(ModeState),
 }
 
 #[derive(Debug)]
@@ -73,7 +223,12 @@ pub struct ReceivedBroadcast {
 #[derive(Debug)]
 pub enum SwarmOutEvent {
     Broadcast(ReceivedBroadcast),
+    /// A peer was announced on the local network
+    PeerDiscovered(PeerId),
+    /// A peer stopped being announced on the local network
+    PeerExpired(PeerId),
     ActuatorRequest {
+        peer: PeerId,
         data: FullActuatorData,
         channel: ResponseChannel<RemoteActuationResponse>,
     },
@@ -81,6 +236,29 @@ pub enum SwarmOutEvent {
         id: RequestId,
         response: ActuationResult,
     },
+    ActuatorFailure {
+        id: RequestId,
+        error: String,
+    },
+    HistoryRequest {
+        peer: PeerId,
+        request: HistoryRequest,
+        channel: ResponseChannel<HistoryResponse>,
+    },
+    HistoryResponse {
+        id: RequestId,
+        response: HistoryResponse,
+    },
+    CatchUpRequest {
+        peer: PeerId,
+        request: CatchUpRequest,
+        channel: ResponseChannel<CatchUpResponse>,
+    },
+    CatchUpResponse {
+        id: RequestId,
+        peer: PeerId,
+        response: CatchUpResponse,
+    },
 }
 
 // HACK: `bincode` can't serialize `serde` tagged enums; thus, we need a different type
@@ -98,11 +276,16 @@ pub enum RemoteActuationResponse {
         error_code: i64,
         error_description: String,
     },
+    Forbidden {
+        reason: String,
+    },
 }
 
 impl From<RemoteActuationResponse> for ActuationResult {
     fn from(remote: RemoteActuationResponse) -> Self {
-        use RemoteActuationResponse::{ActuatorError, BadRequest, Ignored, NoResponse, Success};
+        use RemoteActuationResponse::{
+            ActuatorError, BadRequest, Forbidden, Ignored, NoResponse, Success,
+        };
 
         match remote {
             Success => Self::Success,
@@ -116,13 +299,14 @@ impl From<RemoteActuationResponse> for ActuationResult {
                 error_code,
                 error_description,
             },
+            Forbidden { reason } => Self::Forbidden { reason },
         }
     }
 }
 
 impl From<ActuationResult> for RemoteActuationResponse {
     fn from(remote: ActuationResult) -> Self {
-        use ActuationResult::{ActuatorError, BadRequest, Ignored, NoResponse, Success};
+        use ActuationResult::{ActuatorError, BadRequest, Forbidden, Ignored, NoResponse, Success};
 
         match remote {
             Success => Self::Success,
@@ -136,6 +320,7 @@ impl From<ActuationResult> for RemoteActuationResponse {
                 error_code,
                 error_description,
             },
+            Forbidden { reason } => Self::Forbidden { reason },
         }
     }
 }
@@ -153,6 +338,47 @@ impl ProtocolName for ActuatorRequestProtocol {
     }
 }
 
+/// Reads a single bincode-encoded message off a request-response stream
+async fn read_bincode<T, I>(io: &mut I, what: &str) -> std::io::Result<T>
+where
+    T: DeserializeOwned,
+    I: futures::AsyncRead + Unpin + Send,
+{
+    let mut deserializer = AsyncBincodeReader::from(io.compat());
+    let Some(item) = deserializer.next().await else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            anyhow!("Got None from {}", what),
+        ));
+    };
+    item.map_err(|err| {
+        error!("Error while deserializing {}: {}", what, err);
+        match *err {
+            bincode::ErrorKind::Io(err) => err,
+            err => std::io::Error::other(err),
+        }
+    })
+}
+
+/// Writes a single bincode-encoded message to a request-response stream
+async fn write_bincode<T, I>(io: &mut I, item: T, what: &str) -> std::io::Result<()>
+where
+    T: Serialize,
+    I: futures::AsyncWrite + Unpin + Send,
+{
+    let mut serializer = AsyncBincodeWriter::from(io.compat()).for_async();
+    match serializer.send(item).await {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            error!("Error while serializing {}: {}", what, err);
+            match *err {
+                bincode::ErrorKind::Io(err) => Err(err),
+                err => Err(std::io::Error::other(err)),
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ActuatorRequestsCodec;
 
@@ -170,23 +396,7 @@ impl RequestResponseCodec for ActuatorRequestsCodec {
     where
         T: futures::AsyncRead + Unpin + Send,
     {
-        let mut deserializer = AsyncBincodeReader::from(io.compat());
-        match deserializer.next().await {
-            Some(item) => match item {
-                Ok(item) => Ok(item),
-                Err(err) => {
-                    error!("Error while deserializing GossipSub request: {}", err);
-                    match *err {
-                        bincode::ErrorKind::Io(err) => Err(err),
-                        err => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
-                    }
-                }
-            },
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                anyhow!("Got None from request"),
-            )),
-        }
+        read_bincode(io, "actuator request").await
     }
 
     async fn read_response<T>(
@@ -197,23 +407,7 @@ impl RequestResponseCodec for ActuatorRequestsCodec {
     where
         T: futures::AsyncRead + Unpin + Send,
     {
-        let mut deserializer = AsyncBincodeReader::from(io.compat());
-        match deserializer.next().await {
-            Some(item) => match item {
-                Ok(item) => Ok(item),
-                Err(err) => {
-                    error!("Error while deserializing GossipSub response: {}", err);
-                    match *err {
-                        bincode::ErrorKind::Io(err) => Err(err),
-                        err => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
-                    }
-                }
-            },
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                anyhow!("Got None from request"),
-            )),
-        }
+        read_bincode(io, "actuator response").await
     }
 
     async fn write_request<T>(
@@ -225,18 +419,88 @@ impl RequestResponseCodec for ActuatorRequestsCodec {
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let mut serializer = AsyncBincodeWriter::from(io.compat()).for_async();
-        match serializer.send(req).await {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                error!("Error while serializing GossipSub request: {}", err);
-                match *err {
-                    bincode::ErrorKind::Io(err) => Err(err),
-                    err => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
-                }
-            }
+        write_bincode(io, req, "actuator request").await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        write_bincode(io, res, "actuator response").await
+    }
+}
+
+/// Query over the history of a sensor of the node it is sent to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub device: String,
+    pub sensor_name: String,
+    pub query: HistoryQuery,
+}
+
+/// Page of history matching a request, or why it couldn't be queried
+pub type HistoryResponse = Result<HistoryPage, String>;
+
+#[derive(Debug, Clone)]
+pub enum HistoryRequestProtocol {
+    V1,
+}
+
+impl ProtocolName for HistoryRequestProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        match *self {
+            HistoryRequestProtocol::V1 => b"/diodt/history/1.0",
         }
     }
+}
+
+#[derive(Clone)]
+pub struct HistoryRequestsCodec;
+
+#[async_trait]
+impl RequestResponseCodec for HistoryRequestsCodec {
+    type Protocol = HistoryRequestProtocol;
+    type Request = HistoryRequest;
+    type Response = HistoryResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        read_bincode(io, "history request").await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        read_bincode(io, "history response").await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        write_bincode(io, req, "history request").await
+    }
 
     async fn write_response<T>(
         &mut self,
@@ -247,30 +511,110 @@ impl RequestResponseCodec for ActuatorRequestsCodec {
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let mut serializer = AsyncBincodeWriter::from(io.compat()).for_async();
-        match serializer.send(res).await {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                error!("Error while serializing GossipSub response: {}", err);
-                match *err {
-                    bincode::ErrorKind::Io(err) => Err(err),
-                    err => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
-                }
-            }
+        write_bincode(io, res, "history response").await
+    }
+}
+
+/// Asks a node for the measurements of its own sensors taken since some time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatchUpRequest {
+    /// Unix timestamp in milliseconds, inclusive
+    pub since: u64,
+    /// Most measurements to send back at once
+    pub limit: usize,
+}
+
+/// Measurements asked for, or why they couldn't be read
+pub type CatchUpResponse = Result<CatchUpPage, String>;
+
+#[derive(Debug, Clone)]
+pub enum CatchUpProtocol {
+    V1,
+}
+
+impl ProtocolName for CatchUpProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        match *self {
+            CatchUpProtocol::V1 => b"/diodt/catchup/1.0",
         }
     }
 }
 
+#[derive(Clone)]
+pub struct CatchUpCodec;
+
+#[async_trait]
+impl RequestResponseCodec for CatchUpCodec {
+    type Protocol = CatchUpProtocol;
+    type Request = CatchUpRequest;
+    type Response = CatchUpResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        read_bincode(io, "catch-up request").await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Response>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        read_bincode(io, "catch-up response").await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        write_bincode(io, req, "catch-up request").await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _protocol: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        write_bincode(io, res, "catch-up response").await
+    }
+}
+
 /// Network behavior for use with libp2p for each node
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "SwarmOutEvent", poll_method = "poll")]
 pub struct DiotdBehavior {
     ping: Ping,
-    mdns: Mdns,
+    mdns: Toggle<Mdns>,
+    identify: Identify,
+    kademlia: Kademlia<MemoryStore>,
     pub(crate) gossipsub: Gossipsub,
     actuator_requests: RequestResponse<ActuatorRequestsCodec>,
+    history_requests: RequestResponse<HistoryRequestsCodec>,
+    catch_up: RequestResponse<CatchUpCodec>,
     #[behaviour(ignore)]
     local_peer_id: PeerId,
+    /// Sensors of other nodes whose topics are subscribed to
+    #[behaviour(ignore)]
+    watched: HashSet<SeriesId>,
+    #[behaviour(ignore)]
+    bootstrap_timer: tokio::time::Interval,
     #[behaviour(ignore)]
     out_ev: VecDeque<SwarmOutEvent>,
 }
@@ -278,17 +622,52 @@ pub struct DiotdBehavior {
 impl Debug for DiotdBehavior {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DiotdBehavior")
-            .field("mdns", &self.mdns)
-            .finish()
+            .field("mdns", &self.mdns.is_enabled())
+            .finish_non_exhaustive()
     }
 }
 
 impl DiotdBehavior {
-    pub async fn new(local_peer_id: PeerId, psk: PreSharedKey, keypair: Keypair) -> Result<Self> {
-        let service_name = format!("_p2p-{}._udp.local", psk.fingerprint().to_string());
-        let mdns = Mdns::with_service_name(service_name.as_bytes().to_vec())
-            .await
-            .context("Couldn't initialize mDNS")?;
+    pub async fn new(
+        local_peer_id: PeerId,
+        psk: PreSharedKey,
+        keypair: Keypair,
+        network: &NetworkConfig,
+    ) -> Result<Self> {
+        let mdns = if network.mdns {
+            let service_name = format!("_p2p-{}._udp.local", psk.fingerprint());
+            let mdns = Mdns::with_service_name(service_name.as_bytes().to_vec())
+                .await
+                .context("Couldn't initialize mDNS")?;
+            Some(mdns)
+        } else {
+            info!("mDNS is disabled, only finding peers through the DHT");
+            None
+        };
+
+        let identify = Identify::new(
+            String::from("/diodt/1.0"),
+            format!("diotd/{}", env!("CARGO_PKG_VERSION")),
+            keypair.public(),
+        );
+
+        let mut kademlia_conf = KademliaConfig::default();
+        kademlia_conf.set_protocol_name(KADEMLIA_PROTOCOL);
+        let mut kademlia = Kademlia::with_config(
+            local_peer_id,
+            MemoryStore::new(local_peer_id),
+            kademlia_conf,
+        );
+        for address in &network.bootstrap {
+            if let Some((peer_id, address)) = split_peer_id(address) {
+                kademlia.add_address(&peer_id, address);
+            } else {
+                warn!(
+                    "Bootstrap address {} has no /p2p/<peer ID> suffix, only dialing it",
+                    address
+                );
+            }
+        }
 
         let gossipsub_conf = GossipsubConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(5))
@@ -309,27 +688,70 @@ impl DiotdBehavior {
             RequestResponseConfig::default(),
         );
 
-        gossipsub
-            .subscribe(&IdentTopic::new("default"))
-            .expect("Couldn't subscribe to topic");
+        let history_requests = RequestResponse::new(
+            HistoryRequestsCodec,
+            std::iter::once((
+                HistoryRequestProtocol::V1,
+                libp2p_request_response::ProtocolSupport::Full,
+            )),
+            RequestResponseConfig::default(),
+        );
+
+        let catch_up = RequestResponse::new(
+            CatchUpCodec,
+            std::iter::once((
+                CatchUpProtocol::V1,
+                libp2p_request_response::ProtocolSupport::Full,
+            )),
+            RequestResponseConfig::default(),
+        );
+
+        for topic in [NODES_TOPIC, LEGACY_TOPIC] {
+            gossipsub
+                .subscribe(&IdentTopic::new(topic))
+                .expect("Couldn't subscribe to topic");
+        }
 
         Ok(Self {
             ping,
-            mdns,
+            mdns: mdns.into(),
+            identify,
+            kademlia,
             gossipsub,
             actuator_requests,
+            history_requests,
+            catch_up,
             local_peer_id,
+            watched: HashSet::new(),
+            bootstrap_timer: tokio::time::interval(KADEMLIA_BOOTSTRAP_INTERVAL),
             out_ev: VecDeque::new(),
         })
     }
 
-    pub async fn broadcast_identity(&mut self, peer_data: PeerData) {
+    pub fn broadcast_identity(&mut self, peer_data: PeerData) {
         //info!("Identity");
-        let topic = IdentTopic::new("default");
         let message = bincode::serialize(&DiotdBroadcast::Identity(peer_data))
             .expect("Failed to serialize config?!");
+        for topic in [NODES_TOPIC, LEGACY_TOPIC] {
+            match self
+                .gossipsub
+                .publish(IdentTopic::new(topic), message.clone())
+            {
+                Ok(id) => debug!("Sent identity msg with ID: {}", id),
+                Err(err) => match err {
+                    PublishError::InsufficientPeers => {}
+                    err => error!("Error while sending message: {:?}", err),
+                },
+            }
+        }
+    }
+
+    pub fn broadcast_mode(&mut self, mode: ModeState) {
+        let topic = IdentTopic::new(NODES_TOPIC);
+        let message =
+            bincode::serialize(&DiotdBroadcast::Mode(mode)).expect("Failed to serialize mode?!");
         match self.gossipsub.publish(topic, message) {
-            Ok(id) => debug!("Sent identity msg with ID: {}", id),
+            Ok(id) => debug!("Sent mode msg with ID: {}", id),
             Err(err) => match err {
                 PublishError::InsufficientPeers => {}
                 err => error!("Error while sending message: {:?}", err),
@@ -337,9 +759,13 @@ impl DiotdBehavior {
         }
     }
 
-    pub async fn broadcast_sensor_data(&mut self, sensor_data: FullSensorData) {
+    pub fn broadcast_sensor_data(&mut self, sensor_data: FullSensorData) {
         //info!("Sensor data: {:?}", sensor_data);
-        let topic = IdentTopic::new("default");
+        let topic = sensor_topic(&SeriesId::new(
+            self.local_peer_id,
+            &sensor_data.device,
+            &sensor_data.sensor_name,
+        ));
         let message = bincode::serialize(&DiotdBroadcast::SensorData(sensor_data))
             .expect("Failed to serialize config?!");
         match self.gossipsub.publish(topic, message) {
@@ -351,18 +777,47 @@ impl DiotdBehavior {
         }
     }
 
-    pub async fn send_actuator_request(
-        &mut self,
-        peer: &PeerId,
-        data: FullActuatorData,
-    ) -> RequestId {
+    pub fn send_actuator_request(&mut self, peer: &PeerId, data: FullActuatorData) -> RequestId {
         self.actuator_requests.send_request(peer, data)
     }
 
+    pub fn send_history_request(&mut self, peer: &PeerId, request: HistoryRequest) -> RequestId {
+        self.history_requests.send_request(peer, request)
+    }
+
+    pub fn send_catch_up_request(&mut self, peer: &PeerId, request: CatchUpRequest) -> RequestId {
+        self.catch_up.send_request(peer, request)
+    }
+
     pub fn local_peer_id(&self) -> PeerId {
         self.local_peer_id
     }
 
+    /// Subscribes to the measurements of exactly the given sensors of other nodes
+    pub fn watch_sensors(&mut self, sensors: HashSet<SeriesId>) {
+        for sensor in self.watched.difference(&sensors) {
+            debug!("Unsubscribing from sensor {:?}", sensor);
+            if let Err(err) = self.gossipsub.unsubscribe(&sensor_topic(sensor)) {
+                error!("Couldn't unsubscribe from sensor {:?}: {:?}", sensor, err);
+            }
+        }
+        for sensor in sensors.difference(&self.watched) {
+            debug!("Subscribing to sensor {:?}", sensor);
+            if let Err(err) = self.gossipsub.subscribe(&sensor_topic(sensor)) {
+                error!("Couldn't subscribe to sensor {:?}: {:?}", sensor, err);
+            }
+        }
+        self.watched = sensors;
+    }
+
+    /// Lets every protocol know where to reach a node
+    fn add_peer_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.gossipsub.add_explicit_peer(peer_id);
+        self.actuator_requests.add_address(peer_id, address.clone());
+        self.history_requests.add_address(peer_id, address.clone());
+        self.catch_up.add_address(peer_id, address);
+    }
+
     fn push_broadcast_event(&mut self, sender: PeerId, broadcast: DiotdBroadcast) {
         self.out_ev
             .push_back(SwarmOutEvent::Broadcast(ReceivedBroadcast {
@@ -373,11 +828,15 @@ impl DiotdBehavior {
 
     fn push_actuator_request_event(
         &mut self,
+        peer: PeerId,
         data: FullActuatorData,
         channel: ResponseChannel<RemoteActuationResponse>,
     ) {
-        self.out_ev
-            .push_back(SwarmOutEvent::ActuatorRequest { data, channel });
+        self.out_ev.push_back(SwarmOutEvent::ActuatorRequest {
+            peer,
+            data,
+            channel,
+        });
     }
 
     fn push_actuator_response_event(&mut self, id: RequestId, response: ActuationResult) {
@@ -385,11 +844,21 @@ impl DiotdBehavior {
             .push_back(SwarmOutEvent::ActuatorResponse { id, response });
     }
 
+    fn push_actuator_failure_event(&mut self, id: RequestId, error: String) {
+        self.out_ev
+            .push_back(SwarmOutEvent::ActuatorFailure { id, error });
+    }
+
     fn poll<TBehaviourIn>(
         &mut self,
-        _: &mut TaskContext,
+        cx: &mut TaskContext,
         _: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<TBehaviourIn, SwarmOutEvent>> {
+        while self.bootstrap_timer.poll_tick(cx).is_ready() {
+            if let Err(err) = self.kademlia.bootstrap() {
+                debug!("Couldn't look for peers in the DHT: {:?}", err);
+            }
+        }
         if let Some(ev) = self.out_ev.pop_front() {
             return Poll::Ready(NetworkBehaviourAction::GenerateEvent(ev));
         }
@@ -401,11 +870,27 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for DiotdBehavior {
     /// Called when `mdns` produces an event.
     #[instrument(skip(self))]
     fn inject_event(&mut self, event: MdnsEvent) {
-        if let MdnsEvent::Discovered(list) = event {
-            for (peer_id, multiaddr) in list {
-                debug!("Discovered peer {} with address {}", peer_id, multiaddr);
-                self.gossipsub.add_explicit_peer(&peer_id);
-                self.actuator_requests.add_address(&peer_id, multiaddr);
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer_id, multiaddr) in list {
+                    debug!("Discovered peer {} with address {}", peer_id, multiaddr);
+                    self.kademlia.add_address(&peer_id, multiaddr.clone());
+                    self.add_peer_address(&peer_id, multiaddr);
+                    self.out_ev
+                        .push_back(SwarmOutEvent::PeerDiscovered(peer_id));
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer_id, multiaddr) in list {
+                    debug!("Peer {} expired with address {}", peer_id, multiaddr);
+                    if !self
+                        .mdns
+                        .as_ref()
+                        .is_some_and(|mdns| mdns.has_node(&peer_id))
+                    {
+                        self.out_ev.push_back(SwarmOutEvent::PeerExpired(peer_id));
+                    }
+                }
             }
         }
     }
@@ -447,6 +932,59 @@ impl NetworkBehaviourEventProcess<GossipsubEvent> for DiotdBehavior {
     }
 }
 
+impl NetworkBehaviourEventProcess<IdentifyEvent> for DiotdBehavior {
+    /// Called when `identify` produces an event.
+    #[instrument(skip(self, event))]
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        match event {
+            IdentifyEvent::Received { peer_id, info, .. } => {
+                debug!(
+                    "Peer {} ({}) listens on {:?}",
+                    peer_id, info.agent_version, info.listen_addrs
+                );
+                if !info
+                    .protocols
+                    .iter()
+                    .any(|protocol| protocol.as_bytes() == KADEMLIA_PROTOCOL)
+                {
+                    return;
+                }
+                for address in info.listen_addrs {
+                    self.kademlia.add_address(&peer_id, address);
+                }
+            }
+            IdentifyEvent::Sent { .. } => {}
+            IdentifyEvent::Error { peer_id, error } => {
+                debug!("Couldn't identify peer {}: {:?}", peer_id, error);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for DiotdBehavior {
+    /// Called when `kademlia` produces an event.
+    #[instrument(skip(self, event))]
+    fn inject_event(&mut self, event: KademliaEvent) {
+        match event {
+            KademliaEvent::RoutingUpdated {
+                peer, addresses, ..
+            } => {
+                debug!("Found peer {} in the DHT at {:?}", peer, addresses);
+                for address in addresses.into_vec() {
+                    self.add_peer_address(&peer, address);
+                }
+            }
+            KademliaEvent::QueryResult {
+                result: QueryResult::Bootstrap(Err(err)),
+                ..
+            } => {
+                debug!("Couldn't finish looking for peers in the DHT: {:?}", err);
+            }
+            _ => {}
+        }
+    }
+}
+
 impl NetworkBehaviourEventProcess<PingEvent> for DiotdBehavior {
     #[instrument(skip(self, event))]
     fn inject_event(&mut self, event: PingEvent) {
@@ -470,7 +1008,7 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent<FullActuatorData, RemoteA
                     channel,
                 } => {
                     debug!("Received inbound request from peer {}", peer);
-                    self.push_actuator_request_event(request, channel);
+                    self.push_actuator_request_event(peer, request, channel);
                 }
                 RequestResponseMessage::Response {
                     request_id,
@@ -486,9 +1024,10 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent<FullActuatorData, RemoteA
                 error,
             } => {
                 error!(
-                    "Outbound failure on Gossipsub, peer id = {}, request id = {}, err = {:?}",
+                    "Couldn't request actuation from peer {}, request id = {}, err = {:?}",
                     peer, request_id, error
                 );
+                self.push_actuator_failure_event(request_id, describe_failure(&peer, &error));
             }
             RequestResponseEvent::InboundFailure {
                 peer,
@@ -496,7 +1035,7 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent<FullActuatorData, RemoteA
                 error,
             } => {
                 error!(
-                    "Inbound failure on Gossipsub, peer id = {}, request id = {}, err = {:?}",
+                    "Couldn't answer actuation request of peer {}, request id = {}, err = {:?}",
                     peer, request_id, error
                 );
             }
@@ -510,6 +1049,126 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent<FullActuatorData, RemoteA
     }
 }
 
+impl NetworkBehaviourEventProcess<RequestResponseEvent<HistoryRequest, HistoryResponse>>
+    for DiotdBehavior
+{
+    #[instrument(skip(self, event))]
+    fn inject_event(&mut self, event: RequestResponseEvent<HistoryRequest, HistoryResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request_id: _,
+                    request,
+                    channel,
+                } => {
+                    debug!("Received history request from peer {}", peer);
+                    self.out_ev.push_back(SwarmOutEvent::HistoryRequest {
+                        peer,
+                        request,
+                        channel,
+                    });
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    debug!("Received history response from peer {}", peer);
+                    self.out_ev.push_back(SwarmOutEvent::HistoryResponse {
+                        id: request_id,
+                        response,
+                    });
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(
+                    "Couldn't query history of peer {}, request id = {}, err = {:?}",
+                    peer, request_id, error
+                );
+                self.out_ev.push_back(SwarmOutEvent::HistoryResponse {
+                    id: request_id,
+                    response: Err(describe_failure(&peer, &error)),
+                });
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(
+                    "Couldn't answer history request of peer {}, request id = {}, err = {:?}",
+                    peer, request_id, error
+                );
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<CatchUpRequest, CatchUpResponse>>
+    for DiotdBehavior
+{
+    #[instrument(skip(self, event))]
+    fn inject_event(&mut self, event: RequestResponseEvent<CatchUpRequest, CatchUpResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request_id: _,
+                    request,
+                    channel,
+                } => {
+                    debug!("Received catch-up request from peer {}", peer);
+                    self.out_ev.push_back(SwarmOutEvent::CatchUpRequest {
+                        peer,
+                        request,
+                        channel,
+                    });
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    debug!("Received catch-up response from peer {}", peer);
+                    self.out_ev.push_back(SwarmOutEvent::CatchUpResponse {
+                        id: request_id,
+                        peer,
+                        response,
+                    });
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!(
+                    "Catch-up request {} to peer {} failed: {:?}",
+                    request_id, peer, error
+                );
+                self.out_ev.push_back(SwarmOutEvent::CatchUpResponse {
+                    id: request_id,
+                    peer,
+                    response: Err(describe_failure(&peer, &error)),
+                });
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(
+                    "Couldn't answer catch-up request of peer {}, request id = {}, err = {:?}",
+                    peer, request_id, error
+                );
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
 /// Sets up the appropiate encryption keypair for this node
 #[instrument(skip(db))]
 fn setup_keypair(db: &sled::Db) -> Result<Keypair> {
@@ -552,8 +1211,11 @@ async fn setup_transport(keypair: Keypair, psk: PreSharedKey) -> Result<Transpor
     info!("Setting up transport");
     let transport = {
         let tcp = libp2p::tcp::TokioTcpConfig::new().nodelay(true);
-        let tcp_pnet = tcp.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket));
-        libp2p::dns::DnsConfig::new(tcp_pnet)?
+        let uds = libp2p::uds::UdsConfig::new();
+        let pnet = tcp
+            .or_transport(uds)
+            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket));
+        libp2p::dns::DnsConfig::new(pnet)?
     };
 
     let noise_keys = libp2p::noise::Keypair::<libp2p::noise::X25519Spec>::new()
@@ -580,15 +1242,15 @@ pub type DiodtSwarm = ExpandedSwarm<
     <DiotdBehavior as NetworkBehaviour>::ProtocolsHandler,
 >;
 
-pub async fn setup_swarm(secrets: PeerSecrets) -> Result<DiodtSwarm> {
+pub async fn setup_swarm(secrets: PeerSecrets, network: &NetworkConfig) -> Result<DiodtSwarm> {
     let local_key = Keypair::Ed25519(secrets.keypair);
     let local_peer_id = PeerId::from(local_key.public());
     info!("My peer ID is: {}", local_peer_id.to_base58());
     let transport = setup_transport(local_key.clone(), secrets.psk)
         .await
         .context("Failed to create the transport")?;
-    let swarm = {
-        let behavior = DiotdBehavior::new(local_peer_id, secrets.psk, local_key)
+    let mut swarm = {
+        let behavior = DiotdBehavior::new(local_peer_id, secrets.psk, local_key, network)
             .await
             .context("Couldn't initialize the network behavior")?;
         SwarmBuilder::new(transport, behavior, local_peer_id)
@@ -597,5 +1259,55 @@ pub async fn setup_swarm(secrets: PeerSecrets) -> Result<DiodtSwarm> {
             }))
             .build()
     };
+    for address in &network.bootstrap {
+        if let Err(err) = Swarm::dial_addr(&mut swarm, address.clone()) {
+            warn!("Couldn't dial bootstrap peer {}: {:?}", address, err);
+        }
+    }
     Ok(swarm)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(addresses: &[&str]) -> Result<NetworkConfig, serde_json::Error> {
+        serde_json::from_value(serde_json::json!({ "listen": addresses }))
+    }
+
+    #[test]
+    fn unix_addresses_keep_their_whole_path() {
+        let peer = PeerId::random();
+        let with_peer = format!("/unix//run/diotd/diotd.sock/p2p/{peer}");
+        let config = network(&["/unix//run/diotd/diotd.sock", &with_peer]).unwrap();
+
+        let mut protocols = config.listen[0].iter();
+        assert!(
+            matches!(protocols.next(), Some(Protocol::Unix(path)) if path == "/run/diotd/diotd.sock")
+        );
+        assert!(protocols.next().is_none());
+
+        assert_eq!(
+            split_peer_id(&config.listen[1]),
+            Some((peer, config.listen[0].clone()))
+        );
+    }
+
+    #[test]
+    fn other_addresses_are_parsed_as_usual() {
+        let config = network(&["/ip4/127.0.0.1/tcp/4001", "/ip6/::/tcp/4001"]).unwrap();
+        assert_eq!(config.listen[0], "/ip4/127.0.0.1/tcp/4001".parse().unwrap());
+        assert_eq!(config.listen[1], "/ip6/::/tcp/4001".parse().unwrap());
+
+        assert!(network(&["/ip4/localhost/tcp/4001"]).is_err());
+        assert!(network(&["/unix//run/diotd.sock/p2p/not-a-peer-id"]).is_err());
+    }
+
+    #[test]
+    fn addresses_survive_a_round_trip() {
+        let config = network(&["/ip4/0.0.0.0/tcp/4001", "/unix//run/diotd/diotd.sock"]).unwrap();
+        let reparsed: NetworkConfig =
+            serde_json::from_value(serde_json::to_value(&config).unwrap()).unwrap();
+        assert_eq!(reparsed.listen, config.listen);
+    }
+}
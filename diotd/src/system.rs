@@ -1,11 +1,25 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use diot_core::device::ActuationResult;
 
-use libp2p::{identity::ed25519::Keypair, pnet::PreSharedKey, Multiaddr, PeerId, Swarm};
+use libp2p::{
+    identity::ed25519::Keypair,
+    multiaddr::Protocol,
+    pnet::PreSharedKey,
+    swarm::{AddressScore, SwarmEvent},
+    PeerId, Swarm,
+};
+use libp2p_request_response::RequestId;
 use tokio::{
     sync::{
         broadcast::{channel as broadcast_channel, Sender as BroadcastSender},
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
     task::JoinHandle,
@@ -13,10 +27,23 @@ use tokio::{
 use web::{WebserverConfig, WebserverMessage};
 
 use crate::{
-    control::{Action, ControlLayer, Rule},
+    audit::{AuditConfig, AuditEvent, AuditLog},
+    authorization::{Authorization, AuthorizationConfig},
+    control::{
+        Action, ActuationEvent, ActuationId, ActuationOutcome, ActuationSignal, ControlLayer,
+        ExecutionId, FiredRule, ModeState, ModesConfig, PlanEvent, PlanExecutionInfo, Rule,
+        RuleChange, RuleId, RuleIssue, Scene, ScriptConfig, ScriptOutput,
+    },
     hardware::{FullSensorData, HardwareSupervisor, SupervisorOutEvent},
-    store::{LocalPeerDevice, Storage},
-    swarm::{setup_swarm, DiodtSwarm, DiotdBroadcast, ReceivedBroadcast, SwarmOutEvent},
+    history::{now_millis, CatchUpPage, HistoryConfig, SensorHistory},
+    liveness::{Liveness, LivenessConfig, PeerStatus, IDENTITY_INTERVAL},
+    pairing::{self, PairingConfig, PairingGrant, PairingTicket},
+    store::{LocalPeerDevice, Storage, StorageConfig},
+    swarm::{
+        setup_swarm, CatchUpRequest, DiodtSwarm, DiotdBroadcast, HistoryRequest, HistoryResponse,
+        NetworkConfig, PeerData, ReceivedBroadcast, RemoteActuationResponse, SubscriptionsConfig,
+        SwarmOutEvent,
+    },
     web,
 };
 
@@ -47,7 +74,7 @@ mod keypair_parse {
     }
 }
 
-mod psk_parse {
+pub mod psk_parse {
     use std::str::FromStr;
 
     use libp2p::pnet::PreSharedKey;
@@ -110,6 +137,30 @@ pub struct SystemConfig {
     pub secrets: Option<PeerSecrets>,
     pub web: WebserverConfig,
     pub rules: Option<Vec<Rule>>,
+    /// Alternative names for nodes to use on rules, mapped to a peer ID or display name
+    pub aliases: Option<HashMap<String, String>>,
+    /// Modes the swarm can be in, which rules may be restricted to
+    pub modes: Option<ModesConfig>,
+    /// Named sets of actions to apply at once
+    pub scenes: Option<HashMap<String, Scene>>,
+    /// Scripts for automations beyond what rules can do
+    pub scripts: Option<Vec<ScriptConfig>>,
+    /// Where and for how long to keep the log of what automations did
+    pub audit: Option<AuditConfig>,
+    /// Where the node keeps its data
+    pub storage: Option<StorageConfig>,
+    /// How long to keep every measurement seen by this node for
+    pub history: Option<HistoryConfig>,
+    /// When to consider other nodes offline
+    pub liveness: Option<LivenessConfig>,
+    /// Which sensors of other nodes to receive the measurements of
+    pub subscriptions: Option<SubscriptionsConfig>,
+    /// How to find other nodes
+    pub network: Option<NetworkConfig>,
+    /// Which actuations other nodes may request from this one
+    pub authorization: Option<AuthorizationConfig>,
+    /// How to let new nodes into the swarm
+    pub pairing: Option<PairingConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -126,41 +177,196 @@ pub struct LocalPeerData {
     pub(crate) devices: HashMap<String, LocalPeerDevice>,
 }
 
+/// Requests to the running system coming from outside the system loop (e.g. the web API)
+pub enum SystemCommand {
+    ListRules(oneshot::Sender<Vec<Rule>>),
+    ListRuleIssues(oneshot::Sender<Vec<RuleIssue>>),
+    UpsertRule {
+        rule: Box<Rule>,
+        reply: oneshot::Sender<Result<RuleChange>>,
+    },
+    SetRuleEnabled {
+        id: RuleId,
+        enabled: bool,
+        reply: oneshot::Sender<Result<RuleChange>>,
+    },
+    DeleteRule {
+        id: RuleId,
+        reply: oneshot::Sender<Result<RuleChange>>,
+    },
+    ListPlans(oneshot::Sender<Vec<PlanExecutionInfo>>),
+    CancelPlan {
+        execution: ExecutionId,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    GetMode(oneshot::Sender<ModeState>),
+    SetMode {
+        mode: String,
+        reply: oneshot::Sender<Result<ModeState>>,
+    },
+    ListScenes(oneshot::Sender<HashMap<String, Scene>>),
+    ApplyScene {
+        scene: String,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Carries out an action, answering once it and its fallbacks settle for good
+    Actuate {
+        action: Box<Action>,
+        reply: oneshot::Sender<ActuationOutcome>,
+    },
+    /// Asks another node for the history of one of its sensors
+    QueryRemoteHistory {
+        peer: PeerId,
+        request: HistoryRequest,
+        reply: oneshot::Sender<HistoryResponse>,
+    },
+    /// Enters pairing mode, letting a new node in with the role of the pairing config
+    StartPairing(oneshot::Sender<Result<PairingTicket>>),
+    /// Leaves pairing mode
+    StopPairing(oneshot::Sender<Result<()>>),
+    /// Pairing mode ended, with the peer ID of the node let in if any
+    PairingEnded {
+        code: String,
+        result: Result<PeerId>,
+    },
+}
+
+/// Writes a section of the config back to the config file, leaving the rest of it untouched
+async fn persist_section(config_path: &Path, section: &str, value: impl Serialize) -> Result<()> {
+    let value =
+        serde_json::to_value(value).with_context(|| format!("Couldn't serialize \"{section}\""))?;
+    let config_path = config_path.to_path_buf();
+
+    let raw_config = tokio::fs::read(&config_path)
+        .await
+        .context("Couldn't read config file")?;
+    let mut config: serde_json::Value =
+        serde_json::from_slice(&raw_config).context("Couldn't parse config file")?;
+
+    config[section] = value;
+
+    let config_json =
+        serde_json::to_vec_pretty(&config).context("Couldn't re-serialize the config file")?;
+    let tmp_path = config_path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, config_json)
+        .await
+        .context("Couldn't write temporary config file")?;
+    tokio::fs::rename(&tmp_path, &config_path)
+        .await
+        .context("Couldn't replace config file")?;
+
+    Ok(())
+}
+
+/// Most measurements to ask for at once when catching up with another node
+const CATCH_UP_BATCH: usize = 1000;
+
+/// Pairing mode, while on
+struct PairingSession {
+    ticket: PairingTicket,
+    task: JoinHandle<()>,
+}
+
 pub struct System {
     swarm: DiodtSwarm,
     supervisor: HardwareSupervisor,
     storage: Arc<Storage>,
+    audit: Arc<AuditLog>,
+    authorization: Authorization,
+    history: Arc<SensorHistory>,
     config: SystemConfig,
+    /// Pre-shared key of the swarm, handed to new nodes on pairing
+    psk: PreSharedKey,
+    config_path: PathBuf,
     control: ControlLayer,
     webserver_task: Option<JoinHandle<()>>,
     webserver_tx: BroadcastSender<WebserverMessage>,
+    commands_tx: UnboundedSender<SystemCommand>,
+    commands: UnboundedReceiver<SystemCommand>,
+    /// History queries sent to other nodes, waiting for an answer
+    history_queries: HashMap<RequestId, oneshot::Sender<HistoryResponse>>,
+    /// Nodes whose measurements are being caught up on, with the request waiting for an answer
+    catching_up: HashMap<PeerId, RequestId>,
+    pairing: Option<PairingSession>,
 }
 
 impl System {
-    pub async fn from_config(mut config: SystemConfig) -> Result<Self> {
+    pub async fn from_config(mut config: SystemConfig, config_path: &Path) -> Result<Self> {
         let secrets = config.secrets.take().expect("secrets to be there");
+        let psk = secrets.psk;
 
         let supervisor = HardwareSupervisor::from_peer_data(config.peer.clone());
 
-        let swarm: DiodtSwarm = setup_swarm(secrets).await.context("Couldn't setup swarm")?;
+        let swarm: DiodtSwarm = setup_swarm(secrets, &config.network.clone().unwrap_or_default())
+            .await
+            .context("Couldn't setup swarm")?;
+
+        let backend = config
+            .storage
+            .clone()
+            .unwrap_or_default()
+            .open()
+            .context("Couldn't open storage backend")?;
 
         let storage = Arc::new(
-            Storage::new(swarm.local_peer_id(), config.peer.clone())
+            Storage::new(swarm.local_peer_id(), config.peer.clone(), backend.clone())
                 .context("Couldn't open storage")?,
         );
 
+        let audit = Arc::new(
+            AuditLog::open(backend.clone(), config.audit.clone().unwrap_or_default())
+                .context("Couldn't open audit log")?,
+        );
+
+        let authorization = Authorization::new(config.authorization.clone().unwrap_or_default())
+            .context("Invalid authorization config")?;
+
+        let history = Arc::new(
+            SensorHistory::open(backend.clone(), config.history.clone().unwrap_or_default())
+                .context("Couldn't open sensor history")?,
+        );
+
+        // Rules in the config file take precedence over the ones saved on the last run
+        let rules = match config.rules.clone() {
+            Some(rules) => rules,
+            None => backend
+                .load_rules()
+                .context("Couldn't load stored rules")?
+                .unwrap_or_default(),
+        };
+
         let (webserver_tx, _) = broadcast_channel(512);
 
-        let control = ControlLayer::from_ruleset(config.rules.clone().unwrap_or_default());
+        let (commands_tx, commands) = unbounded_channel();
+
+        let mut control = ControlLayer::from_ruleset(
+            rules,
+            storage.clone(),
+            config.aliases.clone().unwrap_or_default(),
+        )
+        .with_modes(config.modes.clone().unwrap_or_default())
+        .with_scenes(config.scenes.clone().unwrap_or_default())
+        .with_scripts(config.scripts.clone().unwrap_or_default());
+        control.validate();
 
         Ok(Self {
             swarm,
             supervisor,
             storage,
+            audit,
+            authorization,
+            history,
             config,
+            psk,
+            config_path: config_path.to_path_buf(),
             control,
             webserver_task: None,
             webserver_tx,
+            commands_tx,
+            commands,
+            history_queries: HashMap::new(),
+            catching_up: HashMap::new(),
+            pairing: None,
         })
     }
 
@@ -170,14 +376,23 @@ impl System {
             .await
             .context("Failed to start devices")?;
 
-        let addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-        Swarm::listen_on(&mut self.swarm, addr)
-            .context("Swarm was unable to start listening on the network")?;
+        let network = self.config.network.clone().unwrap_or_default();
+        for address in network.listen {
+            Swarm::listen_on(&mut self.swarm, address.clone())
+                .with_context(|| format!("Swarm was unable to start listening on {address}"))?;
+        }
+        for address in network.external {
+            info!("Announcing external address {}", address);
+            Swarm::add_external_address(&mut self.swarm, address, AddressScore::Infinite);
+        }
 
         self.webserver_task = Some(
             web::webserver_spawn(
                 self.storage.clone(),
+                self.audit.clone(),
+                self.history.clone(),
                 self.webserver_tx.clone(),
+                self.commands_tx.clone(),
                 self.config.web.clone(),
             )
             .await,
@@ -187,104 +402,556 @@ impl System {
     }
 
     async fn system_loop(&mut self) {
-        let mut timer = tokio::time::interval(Duration::from_secs(5));
-
-        let mut listening = false;
+        let mut timer = tokio::time::interval(IDENTITY_INTERVAL);
+        let liveness_timeout = self.config.liveness.clone().unwrap_or_default().timeout();
 
         loop {
             tokio::select! {
                 _ = timer.tick() => {
-                    self.swarm.broadcast_identity(self.config.peer.clone().into()).await;
-                }
-                swarm_event = self.swarm.next() => {
-                    self.handle_swarm_event(swarm_event).await;
+                    self.swarm.broadcast_identity(self.config.peer.clone().into());
+                    self.swarm.broadcast_mode(self.control.current_mode().clone());
+                    self.check_liveness(liveness_timeout);
+                    self.update_subscriptions();
                 }
+                swarm_event = self.swarm.next_event() => match swarm_event {
+                    SwarmEvent::Behaviour(event) => self.handle_swarm_event(event),
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        self.update_liveness(peer_id, |liveness| {
+                            liveness.set_connected(true, now_millis())
+                        });
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                        self.update_liveness(peer_id, |liveness| {
+                            liveness.set_connected(false, now_millis())
+                        });
+                    }
+                    SwarmEvent::NewListenAddr(address) => {
+                        info!("Now listening on {}", address);
+                    }
+                    SwarmEvent::ExpiredListenAddr(address) => {
+                        info!("No longer listening on {}", address);
+                    }
+                    SwarmEvent::ListenerClosed { addresses, reason: Err(err) } => {
+                        error!("Stopped listening on {:?}: {}", addresses, err);
+                    }
+                    SwarmEvent::ListenerError { error } => {
+                        warn!("Error on a listener: {}", error);
+                    }
+                    _ => {}
+                },
                 Some(sensor_data) = self.supervisor.device_inbox.recv() => {
                     match sensor_data {
                         SupervisorOutEvent::SensorData(sensor_data) => {
-                            self.swarm.broadcast_sensor_data(sensor_data.clone()).await;
-                            self.handle_local_sensor_data(&sensor_data).await;
+                            self.swarm.broadcast_sensor_data(sensor_data.clone());
+                            self.handle_local_sensor_data(&sensor_data);
                         }
                     }
                 }
-            }
-            if !listening {
-                for addr in Swarm::listeners(&self.swarm) {
-                    info!("Now listening on {:?}", addr);
-                    listening = true;
+                Some(plan_event) = self.control.plans.events.recv() => {
+                    self.handle_plan_event(plan_event);
+                }
+                Some(output) = self.control.scripts.outputs.recv() => {
+                    self.handle_script_output(output);
+                }
+                Some(signal) = self.control.actuations.signals.recv() => {
+                    self.handle_actuation_signal(signal);
+                }
+                Some(command) = self.commands.recv() => {
+                    self.handle_command(command).await;
                 }
             }
         }
     }
 
-    async fn handle_actions(&mut self, actions: Vec<Action>) {
+    fn handle_fired_rules(
+        &mut self,
+        node: PeerId,
+        sensor_data: &FullSensorData,
+        fired: Vec<FiredRule>,
+    ) {
+        for fired in fired {
+            self.audit.record(AuditEvent::rule_fired(
+                fired.rule.clone(),
+                node,
+                sensor_data,
+            ));
+            if let Some(mode) = fired.mode {
+                self.announce_mode(mode);
+            }
+            for action in fired.actions {
+                self.handle_action(fired.rule.clone(), None, action);
+            }
+        }
+    }
+
+    /// Lets the swarm and web clients know about a mode switch made by this node
+    fn announce_mode(&mut self, mode: ModeState) {
+        self.swarm.broadcast_mode(mode.clone());
+        self.notify_mode(mode);
+    }
+
+    fn notify_mode(&self, mode: ModeState) {
+        if let Err(err) = self
+            .webserver_tx
+            .send(WebserverMessage::ModeChanged { data: mode })
+        {
+            debug!(
+                "Error while sending mode change to web server (most likely OK): {}",
+                err
+            );
+        }
+    }
+
+    fn handle_action(&mut self, rule: RuleId, execution: Option<ExecutionId>, action: Action) {
+        let actuation = self.control.actuations.begin(rule, execution, action);
+        self.attempt_actuation(actuation);
+    }
+
+    fn handle_script_output(&mut self, output: ScriptOutput) {
+        for action in output.actions {
+            self.handle_action(format!("script:{}", output.script), None, action);
+        }
+    }
+
+    fn attempt_actuation(&mut self, actuation: ActuationId) {
+        let Some((action, attempt, event)) = self.control.actuations.attempt(actuation) else {
+            return;
+        };
         let local_peer_id = self.storage.local_peer_id();
-        for action in actions {
-            match action.node {
-                Some(node) if node != local_peer_id => {
-                    self.swarm
-                        .send_actuator_request(&node, action.actuator)
-                        .await;
-                }
-                _ => {
-                    let (sender, receiver) = oneshot::channel();
-
-                    tokio::spawn(async move {
-                        match receiver.await {
-                            Ok(res) => match res {
-                                Ok(res) => info!("Received actuation result from control layer: {:?}", res),
-                                Err(err) => error!("Error on actuation request triggered from local control layer: {}", err)
-                            }
-                            Err(send_err) => error!("Error while receiving result of actuation request from local control layer: {}", send_err)
+        if let ActuationEvent::Requested { rule, .. } = &event {
+            self.audit.record(AuditEvent::requested(
+                local_peer_id,
+                rule.clone(),
+                actuation,
+                attempt,
+                &action,
+            ));
+        }
+        self.send_actuation_event(event);
+
+        match action.node {
+            Some(node) if node != local_peer_id => {
+                let request = self.swarm.send_actuator_request(&node, action.actuator);
+                self.control
+                    .actuations
+                    .track_request(request, actuation, attempt);
+            }
+            _ => {
+                let (sender, receiver) = oneshot::channel();
+                let signals_tx = self.control.actuations.signal_sender();
+
+                tokio::spawn(async move {
+                    let result = match receiver.await {
+                        Ok(Ok(res)) => {
+                            info!("Received actuation result from control layer: {:?}", res);
+                            Ok(res)
                         }
+                        Ok(Err(err)) => Err(format!("{err:#}")),
+                        Err(send_err) => Err(format!(
+                            "Actuation task went away before answering: {send_err}"
+                        )),
+                    };
+                    let _ = signals_tx.send(ActuationSignal::Completed {
+                        actuation,
+                        attempt,
+                        result,
                     });
+                });
 
-                    if self
-                        .supervisor
-                        .actuate_device_local(action.actuator.clone(), sender)
-                        .is_none()
-                    {
-                        warn!(
-                            "Action attempted to triggered unknown actuator: {:?}",
-                            action.actuator
-                        )
-                    };
+                if self
+                    .supervisor
+                    .actuate_device_local(action.actuator.clone(), sender)
+                    .is_none()
+                {
+                    warn!(
+                        "Action attempted to triggered unknown actuator: {:?}",
+                        action.actuator
+                    );
                 }
             }
         }
     }
 
-    async fn handle_remote_sensor_data(&mut self, peer_id: PeerId, sensor_data: FullSensorData) {
-        self.handle_sensor_data(peer_id, sensor_data.clone()).await;
+    fn handle_actuation_signal(&mut self, signal: ActuationSignal) {
+        let (actuation, attempt, result) = match signal {
+            ActuationSignal::RetryDue { actuation } => {
+                self.attempt_actuation(actuation);
+                return;
+            }
+            ActuationSignal::Completed {
+                actuation,
+                attempt,
+                result,
+            } => (actuation, attempt, result),
+            ActuationSignal::TimedOut { actuation, attempt } => {
+                (actuation, attempt, Err("Timed out".to_string()))
+            }
+        };
+
+        let audit_event = self
+            .control
+            .actuations
+            .get(actuation)
+            .map(|(rule, action)| {
+                AuditEvent::settled(
+                    self.storage.local_peer_id(),
+                    rule.clone(),
+                    actuation,
+                    attempt,
+                    action,
+                    result.clone(),
+                )
+            });
+        let Some((event, next)) = self.control.actuations.settle(actuation, attempt, result) else {
+            return;
+        };
+        if let Some(audit_event) = audit_event {
+            self.audit.record(audit_event);
+        }
+        if let ActuationEvent::Failed {
+            rule,
+            reason,
+            will_retry,
+            ..
+        } = &event
+        {
+            warn!(
+                "Actuation {} of rule {} failed on attempt {}: {}{}",
+                actuation,
+                rule,
+                attempt,
+                reason,
+                if *will_retry { ", retrying" } else { "" }
+            );
+        } else {
+            debug!("Actuation {} settled: {:?}", actuation, event);
+        }
+        self.send_actuation_event(event);
 
-        if let Some(actions) = self.control.trigger_remote(peer_id, &sensor_data) {
-            self.handle_actions(actions).await;
+        if let Some(fallback) = next {
+            info!(
+                "Falling back to actuation {} after actuation {} failed",
+                fallback, actuation
+            );
+            self.attempt_actuation(fallback);
         }
     }
 
-    async fn handle_local_sensor_data(&mut self, sensor_data: &FullSensorData) {
-        let local_peer_id = self.storage.local_peer_id();
-        self.handle_sensor_data(local_peer_id, sensor_data.clone())
-            .await;
+    fn send_actuation_event(&self, event: ActuationEvent) {
+        if let Err(err) = self
+            .webserver_tx
+            .send(WebserverMessage::Actuation { data: event })
+        {
+            debug!(
+                "Error while sending actuation status to web server (most likely OK): {}",
+                err
+            );
+        }
+    }
 
-        if let Some(actions) = self.control.trigger_local(sensor_data) {
-            self.handle_actions(actions).await;
+    async fn handle_command(&mut self, command: SystemCommand) {
+        // The other end may have given up waiting; nothing to do about it
+        match command {
+            SystemCommand::ListRules(reply) => {
+                let _ = reply.send(self.control.rules().to_vec());
+            }
+            SystemCommand::ListRuleIssues(reply) => {
+                let _ = reply.send(self.control.issues().to_vec());
+            }
+            SystemCommand::UpsertRule { rule, reply } => {
+                let result = self.control.upsert_rule(*rule);
+                let _ = reply.send(self.apply_rule_change(result).await);
+            }
+            SystemCommand::SetRuleEnabled { id, enabled, reply } => {
+                let result = self.control.set_rule_enabled(&id, enabled);
+                let _ = reply.send(self.apply_rule_change(result).await);
+            }
+            SystemCommand::DeleteRule { id, reply } => {
+                let result = self.control.delete_rule(&id);
+                let _ = reply.send(self.apply_rule_change(result).await);
+            }
+            SystemCommand::ListPlans(reply) => {
+                let _ = reply.send(self.control.running_plans());
+            }
+            SystemCommand::CancelPlan { execution, reply } => {
+                let result = if self.control.cancel_plan(execution) {
+                    Ok(())
+                } else {
+                    Err(anyhow!("No running plan execution with ID {}", execution))
+                };
+                let _ = reply.send(result);
+            }
+            SystemCommand::GetMode(reply) => {
+                let _ = reply.send(self.control.current_mode().clone());
+            }
+            SystemCommand::SetMode { mode, reply } => self.set_mode(&mode, reply),
+            SystemCommand::ListScenes(reply) => {
+                let _ = reply.send(self.control.scenes().clone());
+            }
+            SystemCommand::ApplyScene { scene, reply } => self.apply_scene(&scene, reply),
+            SystemCommand::Actuate { action, reply } => {
+                info!("Actuation requested through the API: {:?}", action);
+                let actuation = self
+                    .control
+                    .actuations
+                    .begin(String::from("api"), None, *action);
+                self.control.actuations.notify(actuation, reply);
+                self.attempt_actuation(actuation);
+            }
+            SystemCommand::QueryRemoteHistory {
+                peer,
+                request,
+                reply,
+            } => {
+                let id = self.swarm.send_history_request(&peer, request);
+                self.history_queries.insert(id, reply);
+            }
+            SystemCommand::StartPairing(reply) => {
+                let _ = reply.send(self.start_pairing());
+            }
+            SystemCommand::StopPairing(reply) => {
+                let result = match self.pairing.take() {
+                    Some(session) => {
+                        session.task.abort();
+                        info!("Left pairing mode");
+                        Ok(())
+                    }
+                    None => Err(anyhow!("Not in pairing mode")),
+                };
+                let _ = reply.send(result);
+            }
+            SystemCommand::PairingEnded { code, result } => {
+                self.finish_pairing(&code, result).await;
+            }
         }
     }
 
-    async fn handle_sensor_data(&mut self, sender: PeerId, sensor_data: FullSensorData) {
-        match self.storage.insert_sensor_data(sender, sensor_data.clone()) {
-            Ok(Some(_)) => {}
-            Ok(None) => {
-                warn!("Received sensor data for yet-to-register peer, discarding");
+    /// Switches to a mode, answering before carrying out the actions it triggers
+    fn set_mode(&mut self, mode: &str, reply: oneshot::Sender<Result<ModeState>>) {
+        let (changed, actions) = match self.control.set_mode(mode) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                let _ = reply.send(Err(err));
                 return;
             }
+        };
+        if let Some(changed) = changed {
+            self.announce_mode(changed);
+        }
+        let _ = reply.send(Ok(self.control.current_mode().clone()));
+        for action in actions {
+            self.handle_action(format!("mode:{mode}"), None, action);
+        }
+    }
+
+    /// Applies a scene, answering before carrying out its actions
+    fn apply_scene(&mut self, scene: &str, reply: oneshot::Sender<Result<()>>) {
+        let actions = match self.control.apply_scene(scene) {
+            Ok(actions) => actions,
             Err(err) => {
-                error!("Error while saving sensor data to storage: {}", err);
+                let _ = reply.send(Err(err));
                 return;
             }
+        };
+        let _ = reply.send(Ok(()));
+        info!("Applying scene {}", scene);
+        for action in actions {
+            self.handle_action(format!("scene:{scene}"), None, action);
+        }
+    }
+
+    /// Enters pairing mode, replacing the pairing code in use if any
+    ///
+    /// The code is only shown on the console, so that it can't be read by whoever asked for
+    /// pairing mode from afar.
+    fn start_pairing(&mut self) -> Result<PairingTicket> {
+        let config = self.config.pairing.clone().unwrap_or_default();
+        let role = config
+            .role
+            .clone()
+            .unwrap_or_else(|| self.authorization.default_role().to_string());
+        if !self.authorization.has_role(&role) {
+            bail!("Unknown role \"{}\"", role);
+        }
+        if let Some(session) = self.pairing.take() {
+            session.task.abort();
         }
 
+        // Unix-domain sockets are only reachable from this machine
+        let local_peer_id = self.storage.local_peer_id();
+        let addresses = Swarm::listeners(&self.swarm)
+            .chain(Swarm::external_addresses(&self.swarm).map(|record| &record.addr))
+            .filter(|address| !matches!(address.iter().next(), Some(Protocol::Unix(_))))
+            .map(|address| address.clone().with(Protocol::P2p(local_peer_id.into())))
+            .collect();
+        let grant = PairingGrant {
+            psk: self.psk,
+            role: role.clone(),
+            addresses,
+        };
+
+        let ticket = PairingTicket {
+            code: pairing::generate_code(),
+            role,
+            port: config.port,
+            expires_at: now_millis().saturating_add(
+                u64::try_from(config.code_lifetime().as_millis()).unwrap_or(u64::MAX),
+            ),
+        };
+        let code = ticket.code.clone();
+        let commands_tx = self.commands_tx.clone();
+        let task = tokio::spawn(async move {
+            let result = pairing::host(config, code.clone(), grant).await;
+            let _ = commands_tx.send(SystemCommand::PairingEnded { code, result });
+        });
+
+        info!(
+            "Entered pairing mode on port {}, letting a new node in as \"{}\"",
+            ticket.port, ticket.role
+        );
+        println!("Pairing code: {}", ticket.code);
+        self.pairing = Some(PairingSession {
+            ticket: ticket.clone(),
+            task,
+        });
+        Ok(ticket)
+    }
+
+    /// Gives the node let in through pairing its role, and saves it to the config file
+    ///
+    /// The role only holds on this node: the others give the new node their own default role
+    /// until it is listed in their `authorization.peers` too.
+    async fn finish_pairing(&mut self, code: &str, result: Result<PeerId>) {
+        // Pairing may have been restarted with another code meanwhile
+        let Some(session) = self.pairing.take_if(|session| session.ticket.code == code) else {
+            return;
+        };
+
+        let peer = match result {
+            Ok(peer) => peer,
+            Err(err) => {
+                warn!("Left pairing mode without pairing: {:#}", err);
+                return;
+            }
+        };
+        let role = session.ticket.role;
+        info!("Paired with {} as \"{}\"", peer, role);
+
+        self.authorization.assign(peer, role.clone());
+        self.config
+            .authorization
+            .get_or_insert_with(Default::default)
+            .peers
+            .insert(peer.to_base58(), role.clone());
+        if let Err(err) = persist_section(
+            &self.config_path,
+            "authorization",
+            &self.config.authorization,
+        )
+        .await
+        {
+            error!(
+                "Couldn't save role of paired node to the config file: {:#}",
+                err
+            );
+        }
+
+        if let Err(err) = self.webserver_tx.send(WebserverMessage::Paired {
+            node: peer.to_base58(),
+            role,
+        }) {
+            debug!(
+                "Error while sending pairing to web server (most likely OK): {}",
+                err
+            );
+        }
+    }
+
+    /// Persists a successful change to the ruleset and lets web clients know about it
+    async fn apply_rule_change(&mut self, change: Result<RuleChange>) -> Result<RuleChange> {
+        let change = change?;
+        info!("Ruleset changed: {:?}", change);
+        self.update_subscriptions();
+
+        self.config.rules = Some(self.control.rules().to_vec());
+        if let Err(err) = persist_section(&self.config_path, "rules", &self.config.rules).await {
+            error!("Couldn't save ruleset to the config file: {:#}", err);
+        }
+        if let Err(err) = self.storage.backend().save_rules(self.control.rules()) {
+            error!("Couldn't save ruleset to storage: {:#}", err);
+        }
+
+        if let Err(err) = self.webserver_tx.send(WebserverMessage::RuleChanged {
+            data: change.clone(),
+        }) {
+            debug!(
+                "Error while sending rule change to web server (most likely OK): {}",
+                err
+            );
+        }
+
+        Ok(change)
+    }
+
+    fn handle_plan_event(&mut self, event: PlanEvent) {
+        match event {
+            PlanEvent::Actuate {
+                execution,
+                rule,
+                action,
+            } => {
+                debug!("Execution {} requested actuation: {:?}", execution, action);
+                self.handle_action(rule, Some(execution), action);
+                return;
+            }
+            PlanEvent::Finished {
+                execution,
+                ref rule,
+            } => {
+                if !self.control.plans.finished(execution) {
+                    return;
+                }
+                info!("Execution {} of plan for rule {} finished", execution, rule);
+            }
+            PlanEvent::Started { .. } | PlanEvent::Cancelled { .. } => {}
+        }
+
+        if let Err(err) = self
+            .webserver_tx
+            .send(WebserverMessage::PlanStatus { data: event })
+        {
+            debug!(
+                "Error while sending plan status to web server (most likely OK): {}",
+                err
+            );
+        }
+    }
+
+    fn handle_remote_sensor_data(&mut self, peer_id: PeerId, sensor_data: &FullSensorData) {
+        self.handle_sensor_data(peer_id, sensor_data.clone());
+
+        if let Some(fired) = self.control.trigger_remote(peer_id, sensor_data) {
+            self.handle_fired_rules(peer_id, sensor_data, fired);
+        }
+    }
+
+    fn handle_local_sensor_data(&mut self, sensor_data: &FullSensorData) {
+        let local_peer_id = self.storage.local_peer_id();
+        self.handle_sensor_data(local_peer_id, sensor_data.clone());
+
+        if let Some(fired) = self.control.trigger_local(sensor_data) {
+            self.handle_fired_rules(local_peer_id, sensor_data, fired);
+        }
+    }
+
+    fn handle_sensor_data(&self, sender: PeerId, sensor_data: FullSensorData) {
+        if !self.storage.insert_sensor_data(sender, sensor_data.clone()) {
+            warn!("Received sensor data for yet-to-register peer, discarding");
+            return;
+        }
+
+        self.history.record(sender, sensor_data.clone());
+
         if let Err(err) = self.webserver_tx.send(WebserverMessage::SensorData {
             node: sender.to_base58(),
             data: sensor_data,
@@ -293,43 +960,150 @@ impl System {
                 "Error while sending sensor data to web server (most likely OK): {}",
                 err
             );
+        }
+    }
+
+    /// Subscribes to the sensors of other nodes that rules, scripts and web clients need
+    fn update_subscriptions(&mut self) {
+        let (all, extra) = self
+            .config
+            .subscriptions
+            .as_ref()
+            .map_or((false, &[][..]), |subscriptions| {
+                (subscriptions.all, &subscriptions.sensors[..])
+            });
+        // Web clients show every sensor, each holding a receiver while connected
+        let all = all || self.webserver_tx.receiver_count() > 0;
+
+        let watched = if all {
+            None
+        } else {
+            self.control.watched_sensors(extra)
+        };
+        let watched = watched.unwrap_or_else(|| self.storage.remote_sensors());
+        self.swarm.watch_sensors(watched);
+    }
+
+    fn handle_peer_identity(&mut self, sender: PeerId, peer_data: PeerData) {
+        let changes = self.storage.merge_peer_data(sender, peer_data.clone());
+        if changes.is_empty() {
+            return;
+        }
+
+        info!("Peer {} changed: {:?}", sender.to_base58(), changes);
+        self.control.peer_changed(sender, &changes);
+        self.update_subscriptions();
+
+        for change in changes {
+            if let Err(err) = self.webserver_tx.send(WebserverMessage::PeerChanged {
+                node: sender.to_base58(),
+                data: change,
+            }) {
+                debug!(
+                    "Error while sending peer change to web server (most likely OK): {}",
+                    err
+                );
+            }
+        }
+        if let Err(err) = self.webserver_tx.send(WebserverMessage::PeerIdentity {
+            node: sender.to_base58(),
+            data: peer_data,
+        }) {
+            debug!(
+                "Error while sending identity data to web server (most likely OK): {}",
+                err
+            );
+        }
+    }
+
+    /// Applies a change to the liveness of a peer, letting rules, scripts and web clients know if
+    /// it went online or offline
+    fn update_liveness(
+        &mut self,
+        peer: PeerId,
+        update: impl FnOnce(&mut Liveness) -> Option<PeerStatus>,
+    ) {
+        if let Some(status) = self.storage.update_liveness(peer, update) {
+            self.handle_peer_status(peer, status);
+        }
+    }
+
+    fn check_liveness(&mut self, timeout: u64) {
+        let local_peer_id = self.storage.local_peer_id();
+        let now = now_millis();
+        self.storage
+            .update_liveness(local_peer_id, |liveness| liveness.heard(now));
+
+        for peer in self.storage.expire_silent_peers(now, timeout) {
+            self.handle_peer_status(peer, PeerStatus::Offline);
+        }
+    }
+
+    fn handle_peer_status(&mut self, peer: PeerId, status: PeerStatus) {
+        info!("Peer {} is now {:?}", peer.to_base58(), status);
+
+        if let Err(err) = self.webserver_tx.send(WebserverMessage::PeerStatus {
+            node: peer.to_base58(),
+            status,
+        }) {
+            debug!(
+                "Error while sending peer status to web server (most likely OK): {}",
+                err
+            );
+        }
+
+        let sensor_data = status.as_sensor_data();
+        if let Some(fired) = self.control.trigger_remote(peer, &sensor_data) {
+            self.handle_fired_rules(peer, &sensor_data, fired);
+        }
+
+        if status == PeerStatus::Online {
+            self.start_catch_up(peer);
+        }
+    }
+
+    /// Asks a node that came online for the measurements of its sensors this node missed
+    fn start_catch_up(&mut self, peer: PeerId) {
+        if peer == self.storage.local_peer_id() || self.catching_up.contains_key(&peer) {
+            return;
+        }
+        let since = match self.history.catch_up_since(peer) {
+            Ok(since) => since,
+            Err(err) => {
+                error!(
+                    "Couldn't catch up with peer {}: {:#}",
+                    peer.to_base58(),
+                    err
+                );
+                return;
+            }
         };
+
+        debug!("Catching up with peer {} since {}", peer.to_base58(), since);
+        let id = self.swarm.send_catch_up_request(
+            &peer,
+            CatchUpRequest {
+                since,
+                limit: CATCH_UP_BATCH,
+            },
+        );
+        self.catching_up.insert(peer, id);
     }
 
-    async fn handle_swarm_event(&mut self, ev: SwarmOutEvent) {
+    fn handle_swarm_event(&mut self, ev: SwarmOutEvent) {
         match ev {
             SwarmOutEvent::Broadcast(ReceivedBroadcast {
                 sender,
                 broadcast_type,
             }) => {
-                let sender_name = match self.storage.peer_name(sender) {
-                    Ok(possible_name) => {
-                        if let Some(name) = possible_name {
-                            name
-                        } else {
-                            format!("<unregistered peer {}>", sender.to_base58())
-                        }
-                    }
-                    Err(err) => {
-                        error!("Error while fetching swarm event: {}", err);
-                        format!("<failed to get peer name of peer {}>", sender.to_base58())
-                    }
-                };
+                let sender_name = self
+                    .storage
+                    .peer_name(sender)
+                    .unwrap_or_else(|| format!("<unregistered peer {}>", sender.to_base58()));
                 match broadcast_type {
                     DiotdBroadcast::Identity(peer_data) => {
-                        info!(
-                            "Updating peer identity: {} --> {:?}",
-                            sender_name, peer_data
-                        );
-                        if let Err(err) = self.storage.insert_peer_data(sender, peer_data.clone()) {
-                            error!("Error while updating peer identity: {}", err);
-                        }
-                        if let Err(err) = self.webserver_tx.send(WebserverMessage::PeerIdentity {
-                            node: sender.to_base58(),
-                            data: peer_data,
-                        }) {
-                            debug!("Error while sending identity data to web server (most likely OK): {}", err);
-                        };
+                        debug!("Peer identity from {}: {:?}", sender_name, peer_data);
+                        self.handle_peer_identity(sender, peer_data);
                     }
                     DiotdBroadcast::SensorData(sensor_data) => {
                         info!("Sensor data received!");
@@ -338,19 +1112,181 @@ impl System {
                         info!("  Sensor name: {}", sensor_data.sensor_name);
                         info!("  Value: {:?}", sensor_data.value);
 
-                        self.handle_remote_sensor_data(sender, sensor_data).await;
+                        self.handle_remote_sensor_data(sender, &sensor_data);
+                    }
+                    DiotdBroadcast::Mode(mode) => {
+                        if self.control.merge_mode(mode) {
+                            self.notify_mode(self.control.current_mode().clone());
+                        }
                     }
                 }
+
+                self.update_liveness(sender, |liveness| liveness.heard(now_millis()));
+            }
+            SwarmOutEvent::PeerDiscovered(peer) => {
+                self.update_liveness(peer, |liveness| liveness.set_discovered(true));
             }
-            SwarmOutEvent::ActuatorRequest { data, channel } => {
+            SwarmOutEvent::PeerExpired(peer) => {
+                self.update_liveness(peer, |liveness| liveness.set_discovered(false));
+            }
+            SwarmOutEvent::ActuatorRequest {
+                peer,
+                data,
+                channel,
+            } => {
                 info!("Actuator request received: {:?}", data);
-                self.supervisor.actuate_device_remote(data, channel);
+                let local_peer_id = self.storage.local_peer_id();
+                self.audit
+                    .record(AuditEvent::inbound_requested(local_peer_id, peer, &data));
+
+                if let Err(reason) = self.authorization.check(peer, &data) {
+                    warn!("Denied actuation requested by peer {}: {}", peer, reason);
+                    self.audit.record(AuditEvent::inbound_settled(
+                        local_peer_id,
+                        peer,
+                        &data,
+                        Ok(ActuationResult::Forbidden {
+                            reason: reason.clone(),
+                        }),
+                    ));
+                    if channel
+                        .send_response(RemoteActuationResponse::Forbidden { reason })
+                        .is_err()
+                    {
+                        debug!("Peer {} stopped waiting for the actuation", peer);
+                    }
+                    return;
+                }
+
+                let audit = self.audit.clone();
+                let requested = data.clone();
+                let started =
+                    self.supervisor
+                        .actuate_device_remote(data.clone(), channel, move |result| {
+                            audit.record(AuditEvent::inbound_settled(
+                                local_peer_id,
+                                peer,
+                                &requested,
+                                Ok(result.clone()),
+                            ));
+                        });
+                if started.is_none() {
+                    warn!("Peer {} requested actuation of an unknown device", peer);
+                    self.audit.record(AuditEvent::inbound_settled(
+                        local_peer_id,
+                        peer,
+                        &data,
+                        Err(format!("Unknown device \"{}\"", data.device)),
+                    ));
+                }
             }
             SwarmOutEvent::ActuatorResponse { id, response } => {
                 info!(
                     "Actuator response received for request {:?} received: {:?}",
                     id, response
                 );
+                if let Some(signal) = self.control.actuations.remote_outcome(id, Ok(response)) {
+                    self.handle_actuation_signal(signal);
+                }
+            }
+            SwarmOutEvent::ActuatorFailure { id, error } => {
+                if let Some(signal) = self.control.actuations.remote_outcome(id, Err(error)) {
+                    self.handle_actuation_signal(signal);
+                }
+            }
+            SwarmOutEvent::HistoryRequest {
+                peer,
+                request,
+                channel,
+            } => {
+                debug!("Peer {} queried history: {:?}", peer, request);
+                let history = self.history.clone();
+                let local_peer_id = self.storage.local_peer_id();
+                tokio::task::spawn_blocking(move || {
+                    let response = history
+                        .query(
+                            local_peer_id,
+                            &request.device,
+                            &request.sensor_name,
+                            &request.query,
+                        )
+                        .map_err(|err| format!("{err:#}"));
+                    if channel.send_response(response).is_err() {
+                        debug!("Peer {} stopped waiting for history", peer);
+                    }
+                });
+            }
+            SwarmOutEvent::HistoryResponse { id, response } => {
+                if let Some(reply) = self.history_queries.remove(&id) {
+                    let _ = reply.send(response);
+                }
+            }
+            SwarmOutEvent::CatchUpRequest {
+                peer,
+                request,
+                channel,
+            } => {
+                debug!("Peer {} is catching up since {}", peer, request.since);
+                let history = self.history.clone();
+                let local_peer_id = self.storage.local_peer_id();
+                tokio::task::spawn_blocking(move || {
+                    let response = history
+                        .catch_up_page(
+                            local_peer_id,
+                            request.since,
+                            request.limit.clamp(1, CATCH_UP_BATCH),
+                        )
+                        .map_err(|err| format!("{err:#}"));
+                    if channel.send_response(response).is_err() {
+                        debug!("Peer {} stopped waiting to catch up", peer);
+                    }
+                });
+            }
+            SwarmOutEvent::CatchUpResponse { id, peer, response } => {
+                // Answers to requests of an earlier catch-up with the same node are stale
+                if self.catching_up.get(&peer) != Some(&id) {
+                    return;
+                }
+                let CatchUpPage {
+                    samples,
+                    next_since,
+                } = match response {
+                    Ok(page) => page,
+                    Err(err) => {
+                        warn!("Couldn't catch up with peer {}: {}", peer.to_base58(), err);
+                        self.catching_up.remove(&peer);
+                        return;
+                    }
+                };
+
+                let history = self.history.clone();
+                let received = samples.len();
+                tokio::task::spawn_blocking(move || match history.merge_caught_up(peer, samples) {
+                    Ok(merged) if merged > 0 => info!(
+                        "Caught up on {} of {} measurements from peer {}",
+                        merged,
+                        received,
+                        peer.to_base58()
+                    ),
+                    Ok(_) => {}
+                    Err(err) => error!("{:#}", err),
+                });
+
+                match next_since {
+                    Some(since) => {
+                        let id = self.swarm.send_catch_up_request(
+                            &peer,
+                            CatchUpRequest {
+                                since,
+                                limit: CATCH_UP_BATCH,
+                            },
+                        );
+                        self.catching_up.insert(peer, id);
+                    }
+                    None => {
+                        self.catching_up.remove(&peer);
+                    }
+                }
             }
         }
     }
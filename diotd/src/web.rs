@@ -1,13 +1,28 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use serde::{Deserialize, Serialize};
-use tokio::{sync::broadcast::Sender as BroadcastSender, task::JoinHandle};
-use warp::Filter;
+use tokio::{
+    sync::{broadcast::Sender as BroadcastSender, mpsc::UnboundedSender},
+    task::JoinHandle,
+};
+use warp::{Filter, Rejection};
 
-use crate::{hardware::FullSensorData, store::Storage, swarm::PeerData};
+use crate::{
+    audit::{AuditLog, AuditQuery},
+    control::{ActuationEvent, ModeState, PlanEvent, RuleChange},
+    hardware::FullSensorData,
+    history::SensorHistory,
+    liveness::PeerStatus,
+    store::{PeerChange, Storage},
+    swarm::PeerData,
+    system::SystemCommand,
+};
 
 static FRONTEND_SOURCE: &str = include_str!(concat!(env!("OUT_DIR"), "/www-dist/index.html"));
 
+/// Largest history file that may be imported over HTTP, in bytes
+const MAX_IMPORT_SIZE: u64 = 256 * 1024 * 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebserverConfig {
     port: u16,
@@ -26,6 +41,35 @@ pub enum WebserverMessage {
         #[serde(flatten)]
         data: PeerData,
     },
+    PeerChanged {
+        node: String,
+        #[serde(flatten)]
+        data: PeerChange,
+    },
+    PeerStatus {
+        node: String,
+        status: PeerStatus,
+    },
+    PlanStatus {
+        #[serde(flatten)]
+        data: PlanEvent,
+    },
+    RuleChanged {
+        #[serde(flatten)]
+        data: RuleChange,
+    },
+    Actuation {
+        #[serde(flatten)]
+        data: ActuationEvent,
+    },
+    ModeChanged {
+        #[serde(flatten)]
+        data: ModeState,
+    },
+    Paired {
+        node: String,
+        role: String,
+    },
 }
 
 mod ws_events {
@@ -86,37 +130,798 @@ mod ws_events {
     }
 }
 
+mod api {
+    use std::{convert::Infallible, fmt::Write, io, sync::Arc};
+
+    use diot_core::device::{ActuationResult, ActuatorValue, Measurement};
+    use libp2p::PeerId;
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::{
+        mpsc::{self, UnboundedSender},
+        oneshot,
+    };
+    use tokio_stream::wrappers::ReceiverStream;
+    use warp::{
+        http::{
+            header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+            Response, StatusCode,
+        },
+        hyper::{body::Bytes, Body},
+        reply::{json, with_status, Json, WithStatus},
+        Rejection, Reply,
+    };
+
+    use crate::{
+        audit::{AuditLog, AuditQuery},
+        control::{Action, ExecutionId, RetryPolicy, Rule},
+        hardware::FullActuatorData,
+        history::{
+            self, ExportFilter, HistoryPage, HistoryPoints, HistoryQuery, SensorHistory,
+            TransferFormat,
+        },
+        store::Storage,
+        swarm::HistoryRequest,
+        system::SystemCommand,
+    };
+
+    use super::NotLocal;
+
+    #[derive(Serialize)]
+    struct ApiError {
+        error: String,
+    }
+
+    type ApiReply = WithStatus<Json>;
+
+    fn error_reply(status: StatusCode, error: String) -> ApiReply {
+        with_status(json(&ApiError { error }), status)
+    }
+
+    /// Sends a command to the system and waits for its answer
+    async fn request<T>(
+        commands: &UnboundedSender<SystemCommand>,
+        make_command: impl FnOnce(oneshot::Sender<T>) -> SystemCommand,
+    ) -> Result<T, ApiReply> {
+        let (reply, response) = oneshot::channel();
+
+        if commands.send(make_command(reply)).is_err() {
+            return Err(error_reply(
+                StatusCode::SERVICE_UNAVAILABLE,
+                String::from("System is not running"),
+            ));
+        }
+
+        response.await.map_err(|_| {
+            error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                String::from("System dropped the request"),
+            )
+        })
+    }
+
+    /// Answers requests turned away by `local_only` with an error, leaving other rejections be
+    pub async fn reject_not_local(rejection: Rejection) -> Result<ApiReply, Rejection> {
+        if rejection.find::<NotLocal>().is_some() {
+            Ok(error_reply(
+                StatusCode::FORBIDDEN,
+                String::from("Only allowed from the machine the node runs on"),
+            ))
+        } else {
+            Err(rejection)
+        }
+    }
+
+    fn respond<T: Serialize>(
+        result: Result<anyhow::Result<T>, ApiReply>,
+        err_status: StatusCode,
+    ) -> ApiReply {
+        match result {
+            Ok(Ok(value)) => with_status(json(&value), StatusCode::OK),
+            Ok(Err(err)) => error_reply(err_status, format!("{err:#}")),
+            Err(reply) => reply,
+        }
+    }
+
+    pub async fn list_rules(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::ListRules).await;
+        Ok(respond(result.map(Ok), StatusCode::OK))
+    }
+
+    pub async fn list_rule_issues(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::ListRuleIssues).await;
+        Ok(respond(result.map(Ok), StatusCode::OK))
+    }
+
+    pub async fn put_rule(
+        id: String,
+        mut rule: Rule,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        rule.id = id;
+        let result = request(&commands, |reply| SystemCommand::UpsertRule {
+            rule: Box::new(rule),
+            reply,
+        })
+        .await;
+        Ok(respond(result, StatusCode::BAD_REQUEST))
+    }
+
+    pub async fn set_rule_enabled(
+        id: String,
+        enabled: bool,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, |reply| SystemCommand::SetRuleEnabled {
+            id,
+            enabled,
+            reply,
+        })
+        .await;
+        Ok(respond(result, StatusCode::NOT_FOUND))
+    }
+
+    pub async fn delete_rule(
+        id: String,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, |reply| SystemCommand::DeleteRule { id, reply }).await;
+        Ok(respond(result, StatusCode::NOT_FOUND))
+    }
+
+    pub async fn list_plans(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::ListPlans).await;
+        Ok(respond(result.map(Ok), StatusCode::OK))
+    }
+
+    pub async fn cancel_plan(
+        execution: ExecutionId,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, |reply| SystemCommand::CancelPlan {
+            execution,
+            reply,
+        })
+        .await;
+        Ok(respond(result, StatusCode::NOT_FOUND))
+    }
+
+    pub async fn get_mode(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::GetMode).await;
+        Ok(respond(result.map(Ok), StatusCode::OK))
+    }
+
+    pub async fn set_mode(
+        mode: String,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, |reply| SystemCommand::SetMode { mode, reply }).await;
+        Ok(respond(result, StatusCode::BAD_REQUEST))
+    }
+
+    pub async fn list_scenes(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::ListScenes).await;
+        Ok(respond(result.map(Ok), StatusCode::OK))
+    }
+
+    pub async fn apply_scene(
+        scene: String,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, |reply| SystemCommand::ApplyScene {
+            scene,
+            reply,
+        })
+        .await;
+        Ok(respond(result, StatusCode::NOT_FOUND))
+    }
+
+    pub async fn start_pairing(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::StartPairing).await;
+        Ok(respond(result, StatusCode::BAD_REQUEST))
+    }
+
+    pub async fn stop_pairing(
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let result = request(&commands, SystemCommand::StopPairing).await;
+        Ok(respond(result, StatusCode::NOT_FOUND))
+    }
+
+    /// Seconds to wait for an actuation requested through the API, unless it says otherwise
+    const ACTUATION_TIMEOUT: f64 = 10.0;
+
+    #[derive(Debug, Deserialize)]
+    pub struct ActuateBody {
+        data: ActuatorValue,
+        #[serde(default)]
+        retry: RetryPolicy,
+    }
+
+    #[derive(Serialize)]
+    struct ActuateReply {
+        result: ActuationResult,
+    }
+
+    pub async fn actuate(
+        node: String,
+        device: String,
+        actuator_name: String,
+        body: ActuateBody,
+        storage: Arc<Storage>,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<ApiReply, Infallible> {
+        let node = match node.as_str() {
+            "local" => storage.local_peer_id(),
+            node => match resolve_node(&storage, node) {
+                Ok(node) => node,
+                Err(reply) => return Ok(reply),
+            },
+        };
+        let mut retry = body.retry;
+        retry.timeout.get_or_insert(ACTUATION_TIMEOUT);
+        let action = Action {
+            node: Some(node),
+            actuator: FullActuatorData {
+                device,
+                actuator_name,
+                data: body.data,
+            },
+            retry,
+            fallback: None,
+        };
+
+        let result = request(&commands, |reply| SystemCommand::Actuate {
+            action: Box::new(action),
+            reply,
+        })
+        .await;
+        Ok(match result {
+            Ok(Ok(result)) => with_status(json(&ActuateReply { result }), StatusCode::OK),
+            Ok(Err(reason)) => error_reply(StatusCode::BAD_GATEWAY, reason),
+            Err(reply) => reply,
+        })
+    }
+
+    pub async fn query_audit(
+        query: AuditQuery,
+        audit: Arc<AuditLog>,
+        storage: Arc<Storage>,
+    ) -> Result<ApiReply, Infallible> {
+        let peers = query.peer.as_deref().map(|peer| {
+            peer.parse::<PeerId>()
+                .map_or_else(|_| storage.peers_named(peer), |peer_id| vec![peer_id])
+        });
+
+        let result = tokio::task::spawn_blocking(move || audit.query(&query, peers.as_deref()))
+            .await
+            .map_err(|err| {
+                error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Audit log query failed: {err}"),
+                )
+            });
+        Ok(respond(result, StatusCode::INTERNAL_SERVER_ERROR))
+    }
+
+    #[derive(Debug, Clone, Copy, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum HistoryFormat {
+        Json,
+        Csv,
+    }
+
+    /// History query as given in the query string, along with the format to answer in
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct HistoryParams {
+        from: Option<u64>,
+        to: Option<u64>,
+        bucket: Option<f64>,
+        limit: Option<usize>,
+        format: Option<HistoryFormat>,
+    }
+
+    #[derive(Serialize)]
+    struct HistoryReply {
+        node: String,
+        device: String,
+        sensor_name: String,
+        #[serde(flatten)]
+        points: HistoryPoints,
+        next_from: Option<u64>,
+    }
+
+    /// Finds the node a peer ID or display name stands for
+    fn resolve_node(storage: &Storage, node: &str) -> Result<PeerId, ApiReply> {
+        if let Ok(peer_id) = node.parse() {
+            return Ok(peer_id);
+        }
+        match storage.peers_named(node).as_slice() {
+            [peer_id] => Ok(*peer_id),
+            [] => Err(error_reply(
+                StatusCode::NOT_FOUND,
+                format!("Unknown node \"{node}\""),
+            )),
+            _ => Err(error_reply(
+                StatusCode::BAD_REQUEST,
+                format!("More than one node is named \"{node}\"; use its peer ID instead"),
+            )),
+        }
+    }
+
+    fn csv_value(value: &Measurement) -> String {
+        match value {
+            Measurement::Signal => String::from("signal"),
+            Measurement::Integer(value) => value.to_string(),
+            Measurement::Double(value) => value.to_string(),
+            Measurement::String(value) => format!("\"{}\"", value.replace('"', "\"\"")),
+        }
+    }
+
+    fn history_csv(points: &HistoryPoints) -> String {
+        let mut csv = String::new();
+        // Writing to a string can't fail
+        match points {
+            HistoryPoints::Samples(samples) => {
+                csv.push_str("at,value\n");
+                for sample in samples {
+                    let _ = writeln!(csv, "{},{}", sample.at, csv_value(&sample.value));
+                }
+            }
+            HistoryPoints::Buckets(buckets) => {
+                csv.push_str("start,count,min,mean,max\n");
+                for bucket in buckets {
+                    let _ = writeln!(
+                        csv,
+                        "{},{},{},{},{}",
+                        bucket.start, bucket.count, bucket.min, bucket.mean, bucket.max
+                    );
+                }
+            }
+        }
+        csv
+    }
+
+    fn history_reply(
+        node: PeerId,
+        device: String,
+        sensor_name: String,
+        page: HistoryPage,
+        format: HistoryFormat,
+    ) -> Box<dyn Reply> {
+        match format {
+            HistoryFormat::Json => Box::new(with_status(
+                json(&HistoryReply {
+                    node: node.to_base58(),
+                    device,
+                    sensor_name,
+                    points: page.points,
+                    next_from: page.next_from,
+                }),
+                StatusCode::OK,
+            )),
+            HistoryFormat::Csv => {
+                let mut response =
+                    Response::builder().header(CONTENT_TYPE, "text/csv; charset=utf-8");
+                if let Some(next_from) = page.next_from {
+                    response = response.header("x-next-from", next_from);
+                }
+                match response.body(history_csv(&page.points)) {
+                    Ok(response) => Box::new(response),
+                    Err(err) => Box::new(error_reply(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Couldn't build CSV response: {err}"),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Queries the history of a sensor, asking the node it belongs to when this node has none of
+    /// it within the queried range
+    pub async fn query_history(
+        node: String,
+        device: String,
+        sensor_name: String,
+        params: HistoryParams,
+        history: Arc<SensorHistory>,
+        storage: Arc<Storage>,
+        commands: UnboundedSender<SystemCommand>,
+    ) -> Result<Box<dyn Reply>, Infallible> {
+        let node = match resolve_node(&storage, &node) {
+            Ok(node) => node,
+            Err(reply) => return Ok(Box::new(reply)),
+        };
+        let format = params.format.unwrap_or(HistoryFormat::Json);
+        let query = HistoryQuery {
+            from: params.from,
+            to: params.to,
+            bucket: params.bucket,
+            limit: params.limit,
+        };
+
+        let local = {
+            let (device, sensor_name, query) = (device.clone(), sensor_name.clone(), query.clone());
+            tokio::task::spawn_blocking(move || history.query(node, &device, &sensor_name, &query))
+                .await
+        };
+        let page = match local {
+            Ok(Ok(page)) => page,
+            Ok(Err(err)) => {
+                return Ok(Box::new(error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("{err:#}"),
+                )))
+            }
+            Err(err) => {
+                return Ok(Box::new(error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("History query failed: {err}"),
+                )))
+            }
+        };
+
+        if !page.points.is_empty() || node == storage.local_peer_id() {
+            return Ok(history_reply(node, device, sensor_name, page, format));
+        }
+
+        let remote = request(&commands, |reply| SystemCommand::QueryRemoteHistory {
+            peer: node,
+            request: HistoryRequest {
+                device: device.clone(),
+                sensor_name: sensor_name.clone(),
+                query,
+            },
+            reply,
+        })
+        .await;
+        Ok(match remote {
+            Ok(Ok(page)) => history_reply(node, device, sensor_name, page, format),
+            Ok(Err(err)) => Box::new(error_reply(
+                StatusCode::BAD_GATEWAY,
+                format!(
+                    "Couldn't query history of node {}: {}",
+                    node.to_base58(),
+                    err
+                ),
+            )),
+            Err(reply) => Box::new(reply),
+        })
+    }
+
+    /// Sensors, time range and format to export history in; lists are comma-separated
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct ExportParams {
+        nodes: Option<String>,
+        devices: Option<String>,
+        sensors: Option<String>,
+        from: Option<u64>,
+        to: Option<u64>,
+        format: Option<TransferFormat>,
+    }
+
+    fn split_list(list: Option<String>) -> Vec<String> {
+        list.map_or_else(Vec::new, |list| {
+            list.split(',')
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        })
+    }
+
+    /// Sends what is written to it as chunks of a response body
+    struct BodyWriter(mpsc::Sender<io::Result<Vec<u8>>>);
+
+    impl io::Write for BodyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .blocking_send(Ok(buf.to_vec()))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client went away"))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Streams the stored history of the selected sensors as it is read
+    pub async fn export_history(
+        params: ExportParams,
+        storage: Arc<Storage>,
+    ) -> Result<Box<dyn Reply>, Infallible> {
+        let format = params.format.unwrap_or(TransferFormat::Csv);
+        let filter = ExportFilter {
+            nodes: split_list(params.nodes),
+            devices: split_list(params.devices),
+            sensors: split_list(params.sensors),
+            from: params.from,
+            to: params.to,
+        };
+
+        let backend = storage.backend().clone();
+        let (chunks_tx, chunks) = mpsc::channel(16);
+        tokio::task::spawn_blocking(move || {
+            let body = BodyWriter(chunks_tx.clone());
+            if let Err(err) = history::export_history(&*backend, &filter, format, body) {
+                warn!("History export stopped: {:#}", err);
+                // Makes the client see the response as cut short
+                let _ = chunks_tx.blocking_send(Err(io::Error::other(format!("{err:#}"))));
+            }
+        });
+
+        let response = Response::builder()
+            .header(CONTENT_TYPE, format.content_type())
+            .header(
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"history.{}\"", format.extension()),
+            )
+            .body(Body::wrap_stream(ReceiverStream::new(chunks)));
+        Ok(match response {
+            Ok(response) => Box::new(response),
+            Err(err) => Box::new(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Couldn't build export response: {err}"),
+            )),
+        })
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct ImportParams {
+        format: TransferFormat,
+    }
+
+    pub async fn import_history(
+        params: ImportParams,
+        body: Bytes,
+        storage: Arc<Storage>,
+    ) -> Result<ApiReply, Infallible> {
+        let backend = storage.backend().clone();
+        let result = tokio::task::spawn_blocking(move || {
+            history::import_history(&*backend, params.format, &*body)
+        })
+        .await
+        .map_err(|err| {
+            error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("History import failed: {err}"),
+            )
+        });
+        Ok(respond(result, StatusCode::BAD_REQUEST))
+    }
+}
+
+/// Rejection of a request which may only come from this machine
+#[derive(Debug)]
+struct NotLocal;
+
+impl warp::reject::Reject for NotLocal {}
+
+/// Lets through requests from this machine only
+///
+/// The API isn't authenticated, so routes which change anything are kept from the rest of the
+/// network.
+fn local_only() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and_then(|remote: Option<SocketAddr>| async move {
+            match remote {
+                Some(remote) if remote.ip().is_loopback() => Ok(()),
+                _ => Err(warp::reject::custom(NotLocal)),
+            }
+        })
+        .untuple_one()
+}
+
 pub async fn webserver_spawn(
     storage: Arc<Storage>,
+    audit: Arc<AuditLog>,
+    history: Arc<SensorHistory>,
     main_sender: BroadcastSender<WebserverMessage>,
+    commands: UnboundedSender<SystemCommand>,
     config: WebserverConfig,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        webserver(config, storage, main_sender).await;
+        webserver(config, storage, audit, history, main_sender, commands).await;
     })
 }
 
-#[instrument(skip(storage, main_sender))]
+#[instrument(skip(storage, audit, history, main_sender, commands))]
 async fn webserver(
     config: WebserverConfig,
     storage: Arc<Storage>,
+    audit: Arc<AuditLog>,
+    history: Arc<SensorHistory>,
     main_sender: BroadcastSender<WebserverMessage>,
+    commands: UnboundedSender<SystemCommand>,
 ) {
     let channel = warp::any().map(move || main_sender.subscribe());
     let storage = warp::any().map(move || storage.clone());
+    let commands = warp::any().map(move || commands.clone());
+    let audit = warp::any().map(move || audit.clone());
+    let history = warp::any().map(move || history.clone());
 
     let ws = warp::path("updates")
         .and(warp::ws())
         .and(channel)
-        .and(storage)
+        .and(storage.clone())
         .map(|ws: warp::ws::Ws, channel, storage| {
             ws.on_upgrade(move |socket| ws_events::user_connected(socket, channel, storage))
         });
 
+    let rules = {
+        let list = warp::path!("api" / "rules")
+            .and(warp::get())
+            .and(commands.clone())
+            .and_then(api::list_rules);
+        let issues = warp::path!("api" / "rules" / "issues")
+            .and(warp::get())
+            .and(commands.clone())
+            .and_then(api::list_rule_issues);
+        let put = warp::path!("api" / "rules" / String)
+            .and(warp::put())
+            .and(local_only())
+            .and(warp::body::json())
+            .and(commands.clone())
+            .and_then(api::put_rule);
+        let enable = warp::path!("api" / "rules" / String / "enable")
+            .and(warp::post())
+            .map(|id| (id, true))
+            .untuple_one();
+        let disable = warp::path!("api" / "rules" / String / "disable")
+            .and(warp::post())
+            .map(|id| (id, false))
+            .untuple_one();
+        let set_enabled = enable
+            .or(disable)
+            .unify()
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::set_rule_enabled);
+        let delete = warp::path!("api" / "rules" / String)
+            .and(warp::delete())
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::delete_rule);
+
+        list.or(issues).or(put).or(set_enabled).or(delete)
+    };
+
+    let plans = {
+        let list = warp::path!("api" / "plans")
+            .and(warp::get())
+            .and(commands.clone())
+            .and_then(api::list_plans);
+        let cancel = warp::path!("api" / "plans" / u64)
+            .and(warp::delete())
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::cancel_plan);
+
+        list.or(cancel)
+    };
+
+    let modes = {
+        let get = warp::path!("api" / "mode")
+            .and(warp::get())
+            .and(commands.clone())
+            .and_then(api::get_mode);
+        let set = warp::path!("api" / "mode" / String)
+            .and(warp::put())
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::set_mode);
+        let list_scenes = warp::path!("api" / "scenes")
+            .and(warp::get())
+            .and(commands.clone())
+            .and_then(api::list_scenes);
+        let apply_scene = warp::path!("api" / "scenes" / String / "apply")
+            .and(warp::post())
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::apply_scene);
+
+        get.or(set).or(list_scenes).or(apply_scene)
+    };
+
+    let pairing = {
+        let start = warp::path!("api" / "pairing")
+            .and(warp::post())
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::start_pairing);
+        let stop = warp::path!("api" / "pairing")
+            .and(warp::delete())
+            .and(local_only())
+            .and(commands.clone())
+            .and_then(api::stop_pairing);
+
+        start.or(stop)
+    };
+
+    let actuate = warp::path!("api" / "actuators" / String / String / String)
+        .and(warp::post())
+        .and(local_only())
+        .and(warp::body::json())
+        .and(storage.clone())
+        .and(commands.clone())
+        .and_then(api::actuate);
+
+    let audit = warp::path!("api" / "audit")
+        .and(warp::get())
+        .and(warp::query::<AuditQuery>())
+        .and(audit)
+        .and(storage.clone())
+        .and_then(api::query_audit);
+
+    let history = {
+        let query = warp::path!("api" / "history" / String / String / String)
+            .and(warp::get())
+            .and(warp::query::<api::HistoryParams>())
+            .and(history)
+            .and(storage.clone())
+            .and(commands)
+            .and_then(api::query_history);
+        let export = warp::path!("api" / "history" / "export")
+            .and(warp::get())
+            .and(warp::query::<api::ExportParams>())
+            .and(storage.clone())
+            .and_then(api::export_history);
+        let import = warp::path!("api" / "history" / "import")
+            .and(warp::post())
+            .and(local_only())
+            .and(warp::query::<api::ImportParams>())
+            .and(warp::body::content_length_limit(MAX_IMPORT_SIZE))
+            .and(warp::body::bytes())
+            .and(storage)
+            .and_then(api::import_history);
+
+        query.or(export).or(import)
+    };
+
     let frontend = warp::path::end().map(|| warp::reply::html(FRONTEND_SOURCE));
-    let paths = frontend.or(ws);
+    let paths = frontend
+        .or(ws)
+        .or(rules)
+        .or(plans)
+        .or(modes)
+        .or(pairing)
+        .or(actuate)
+        .or(audit)
+        .or(history)
+        .recover(api::reject_not_local);
 
     info!("Webserver listening on wherever");
 
     warp::serve(paths).run(([0, 0, 0, 0], config.port)).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn changes_are_only_allowed_from_this_machine() {
+        let filter = local_only();
+        let allowed = |remote: &str| {
+            warp::test::request()
+                .remote_addr(remote.parse().unwrap())
+                .filter(&filter)
+        };
+
+        assert!(allowed("127.0.0.1:40000").await.is_ok());
+        assert!(allowed("[::1]:40000").await.is_ok());
+        assert!(allowed("192.168.1.20:40000").await.is_err());
+        assert!(warp::test::request().filter(&filter).await.is_err());
+    }
+}